@@ -1,23 +1,175 @@
+use net::file::{schedule_ranges, ChunkError, FileProgress, FileStore};
 use net::*;
 use scc::HashMap;
+use speedy::{Readable, Writable};
+use std::path::Path;
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{watch, Mutex, Notify};
 use tokio::task::AbortHandle;
+use tracing::*;
+
+const SERVER_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// evaluators that take longer than this are considered failed
+const EVALUATION_TIMEOUT: Duration = Duration::from_secs(600);
+const FILE_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+// so that a request fits in a message
+const MAX_REQUESTED_RANGES: usize = 64;
+// chunks are requested from peers in blocks of this many
+const CHUNKS_PER_BLOCK: u32 = 16;
+// chunks served for a single request, the rest is asked again by the downloader
+const MAX_SERVED_CHUNKS: usize = MAX_REQUESTED_RANGES * CHUNKS_PER_BLOCK as usize;
+// bytes served to a single peer per second, what goes past it is not sent at all
+const PEER_UPLOAD_LIMIT: u64 = (MAX_SERVED_CHUNKS * MAX_DATAGRAM_SIZE) as u64 * 1000
+    / FILE_REQUEST_INTERVAL.as_millis() as u64;
+// missing queue messages are asked to the server again at most this often
+const QUEUE_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+// files and keys a worker needs that don't arrive in this time make the evaluation fail
+const WORKER_FETCH_TIMEOUT: Duration = Duration::from_secs(300);
 
 #[derive(Default)]
 struct QueueState {
     next_message_id: u32,
     subs: std::collections::HashMap<SubmissionId, EvaluationInfo>,
     problems: std::collections::HashMap<ProblemId, QProblemDesc>,
+    submissions: std::collections::HashMap<SubmissionId, QSubmission>,
+    announcements: Vec<QAnnouncement>,
+    keys: std::collections::HashMap<EncKeyId, EncKey>,
+    // when missing messages were last requested
+    last_gap_request: Option<Instant>,
+    // submissions this worker was asked to evaluate, and has not started yet
+    assigned: std::collections::VecDeque<SubmissionId>,
+    // evaluations sent, kept and sent again until they show up in the queue
+    evaluations: std::collections::HashMap<SubmissionId, QEvaluation>,
+    // proofs of the evaluations sent, revealed once every evaluator committed,
+    // kept and sent again until they show up in the queue
+    proofs: std::collections::HashMap<SubmissionId, QEvaluationProof>,
+    // the proofs that were revealed
+    revealed: std::collections::HashSet<SubmissionId>,
+}
+
+/// what is saved by Client::save_state, preceded by the versions it was saved with
+/// (see SavedState::VERSION) and its blake3 hash
+#[derive(Readable, Writable)]
+struct SavedState {
+    next_message_id: u32,
+    // the messages with id < next_message_id, in order
+    messages: Vec<QueueMessage>,
+    // evaluations and proofs not yet seen in the queue
+    evaluations: Vec<QEvaluation>,
+    proofs: Vec<QEvaluationProof>,
+}
+impl SavedState {
+    // bump it on incompatible changes to SavedState,
+    // changes to the queue messages in it bump the PROTOCOL_VERSION saved along with it
+    const VERSION: u8 = 3;
+}
+
+/// token bucket limiting the bytes per second spent serving files,
+/// tokio's mutex is fair, so concurrent requests take turns chunk by chunk
+struct UploadLimit {
+    bytes_per_sec: u64, // 0 means unlimited
+    tokens: f64,
+    last: Instant,
+}
+impl UploadLimit {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            tokens: bytes_per_sec as f64,
+            last: Instant::now(),
+        }
+    }
+    /// how long to wait before n bytes can be sent, takes them if it's now
+    fn take(&mut self, n: usize) -> Option<Duration> {
+        if self.bytes_per_sec == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let rate = self.bytes_per_sec as f64;
+        // allows bursts of up to a second worth of bytes
+        self.tokens = (self.tokens + (now - self.last).as_secs_f64() * rate).min(rate);
+        self.last = now;
+        let n = n as f64;
+        if self.tokens >= n.min(rate) {
+            self.tokens -= n;
+            None
+        } else {
+            Some(Duration::from_secs_f64((n.min(rate) - self.tokens) / rate))
+        }
+    }
+}
+
+/// the limits a worker evaluates a submission with
+fn preset_limits(preset: LimitsPreset) -> evaluator::Limits {
+    match preset {
+        LimitsPreset::Native => evaluator::Limits::NATIVE,
+        LimitsPreset::Interpreted => evaluator::Limits::INTERPRETED,
+    }
+}
+
+/// what is published about a test, None if the evaluator of the problem failed on it
+fn test_verdict(ev: evaluator::TestEval) -> Option<TestVerdict> {
+    match ev {
+        evaluator::TestEval::Score(_) => Some(TestVerdict::Scored),
+        evaluator::TestEval::TLE => Some(TestVerdict::TLE),
+        evaluator::TestEval::MLE => Some(TestVerdict::MLE),
+        evaluator::TestEval::RTE => Some(TestVerdict::RTE),
+        evaluator::TestEval::MFO => Some(TestVerdict::MFO),
+        evaluator::TestEval::EvaluatorError => None,
+    }
+}
+
+/// the fetched files a problem's tests come from
+enum TestData {
+    Generator(Vec<u8>),
+    Inputs(Vec<Vec<u8>>),
+}
+/// the fetched files a problem's tests are scored with
+enum ScorerData {
+    Eval(Vec<u8>),
+    Diff(DiffMode, Vec<Vec<u8>>),
+}
+/// the evaluator's counterpart of a diff mode in the queue
+fn diff_mode(mode: DiffMode) -> evaluator::DiffMode {
+    match mode {
+        DiffMode::Exact => evaluator::DiffMode::Exact,
+        DiffMode::TrailingWhitespace => evaluator::DiffMode::TrailingWhitespace,
+        DiffMode::Tokens => evaluator::DiffMode::Tokens,
+    }
+}
+
+/// how many incomplete files, and how many bytes of them, can be received from a peer at once
+#[derive(Clone, Copy, Debug)]
+pub struct ReceiveLimits {
+    pub files: usize,
+    pub bytes: usize,
+}
+impl Default for ReceiveLimits {
+    fn default() -> Self {
+        Self {
+            files: 16,
+            bytes: 1 << 28,
+        }
+    }
 }
 
 pub struct Client {
     net: Arc<Net>,
     server_psk: PubSigKey,
-    receiving_files: HashMap<(FileHash, PubSigKey), (SystemTime, AbortHandle)>,
+    entity: Entity,
+    // time of the last chunk, the download task, size of the file
+    receiving_files: HashMap<(FileHash, PubSigKey), (SystemTime, AbortHandle, usize)>,
+    receive_limits: Mutex<ReceiveLimits>,
     queue_buffer: HashMap<QueueMessageId, QueueMessage>,
     queue: Mutex<QueueState>,
+    files: Arc<FileStore>,
+    upload_limit: Mutex<UploadLimit>,
+    // what each peer was served recently, so that one peer can't take all of upload_limit
+    peer_upload_limits: HashMap<PubSigKey, UploadLimit>,
+    // wakes up the worker loop when a submission is assigned
+    assigned_notify: Notify,
 }
 
 impl Client {
@@ -26,69 +178,814 @@ impl Client {
         server_addr: PeerAddr,
         contest_id: ContestId,
         entity: Entity,
-        ssk: SecSigKey,
-    ) -> Self {
-        let net = Arc::new(Net::new(ssk, entity, contest_id, Filter {}).await);
+        signer: impl Signer + 'static,
+    ) -> anyhow::Result<Self> {
+        let net = Net::builder(signer, entity, contest_id, Filter::reject_all())
+            .server_psk(server_psk)
+            .build()
+            .await?;
+        let net = Arc::new(net);
         // connect to the server
         net.update_peer_addr(server_psk, server_addr).await;
         net.inc_keepalive(server_psk).await;
-        Self {
+        anyhow::ensure!(
+            net.wait_connection_timeout(server_psk, SERVER_CONNECT_TIMEOUT)
+                .await,
+            "could not connect to the server at {:?}",
+            server_addr
+        );
+        net.start_reaper(CONNECTION_IDLE_TIMEOUT);
+        net.start_rekeyer(RekeyPolicy::default());
+        let files = Arc::new(FileStore::new().with_rng(net.rng().clone()));
+        Ok(Self {
             net,
             server_psk,
+            entity,
             receiving_files: HashMap::new(),
+            receive_limits: Mutex::new(ReceiveLimits::default()),
             queue_buffer: HashMap::new(),
             queue: Mutex::new(QueueState::default()),
-        }
+            files,
+            upload_limit: Mutex::new(UploadLimit::new(0)),
+            peer_upload_limits: HashMap::new(),
+            assigned_notify: Notify::new(),
+        })
     }
-    pub async fn recv(&self, buf: &mut [u8]) -> (RecvMessage, PubSigKey) {
-        self.net.recv(self.server_psk, buf).await
+    pub async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<(RecvMessage, PubSigKey)> {
+        self.net.recv(buf).await
     }
     pub async fn handle_queue_message(&self, m: QueueMessage, psk: PubSigKey) {
         if self.queue_buffer.insert_async(m.id, m).await.is_ok() {
             let mut qs = self.queue.lock().await;
-            while let Some(m) = self.queue_buffer.get_async(&qs.next_message_id).await {
-                qs.next_message_id += 1;
-                let m = m.get().clone();
-                match m.message {
-                    QueueMessageInner::Submission(im) => {
-                        todo!();
-                    }
-                    QueueMessageInner::EvaluationRequest(im) => {
-                        qs.subs
-                            .insert(im.submission_id, EvaluationInfo::new(im.evaluators));
-                        todo!(); // if you have to evaluate, evaluate
-                    }
-                    QueueMessageInner::Evaluation(im) => {
-                        todo!();
-                    }
-                    QueueMessageInner::EvaluationProof(im) => {
-                        todo!();
-                    }
-                    QueueMessageInner::ProblemDesc(im) => {
-                        todo!();
+            self.process_buffered_queue(&mut qs).await;
+            self.request_queue_gaps(&mut qs).await;
+        }
+    }
+    /// processes the buffered messages that follow the last processed one, in order
+    async fn process_buffered_queue(&self, qs: &mut QueueState) {
+        while let Some(m) = self.queue_buffer.get_async(&qs.next_message_id).await {
+            qs.next_message_id += 1;
+            let m = m.get().clone();
+            // time is taken from the queue, so that every client agrees on who timed out
+            for ei in qs.subs.values_mut() {
+                ei.tick(m.timestamp, EVALUATION_TIMEOUT);
+            }
+            self.process_queue_message(qs, m.timestamp, m.message).await;
+            self.reveal_proofs(qs).await;
+        }
+    }
+    /// sends the proofs of this worker's evaluations once all the evaluators committed,
+    /// revealing them earlier would let the others copy them
+    async fn reveal_proofs(&self, qs: &mut QueueState) {
+        let ready: Vec<SubmissionId> = qs
+            .proofs
+            .keys()
+            .filter(|id| !qs.revealed.contains(*id))
+            .filter(|id| qs.subs.get(id).is_some_and(|ei| ei.all_committed()))
+            .cloned()
+            .collect();
+        for id in ready {
+            self.send_proof(qs.proofs[&id].clone()).await;
+            qs.revealed.insert(id);
+        }
+    }
+    async fn send_proof(&self, proof: QEvaluationProof) {
+        let m = SendMessage::Evaluation(EvaluationMessage::Proof(proof));
+        if let Err(e) = self.net.send(m, self.server_psk).await {
+            warn!("could not send evaluation proof: {}", e);
+        }
+    }
+    async fn send_evaluation(&self, evaluation: QEvaluation) {
+        let m = SendMessage::Evaluation(EvaluationMessage::Evaluation(evaluation));
+        if let Err(e) = self.net.send(m, self.server_psk).await {
+            warn!("could not send evaluation: {}", e);
+        }
+    }
+    /// sends again the evaluations and revealed proofs that did not show up in the queue yet
+    async fn resend_unqueued(&self, qs: &QueueState) {
+        for evaluation in qs.evaluations.values() {
+            self.send_evaluation(evaluation.clone()).await;
+        }
+        for id in qs.revealed.iter() {
+            if let Some(proof) = qs.proofs.get(id) {
+                self.send_proof(proof.clone()).await;
+            }
+        }
+    }
+    /// asks the server for the messages missing before the last one received,
+    /// and sends again this worker's evaluations and proofs the queue is missing,
+    /// should also be called periodically, in case the requests or the answers get lost
+    pub async fn request_missing_queue(&self) {
+        let mut qs = self.queue.lock().await;
+        self.request_queue_gaps(&mut qs).await;
+        self.resend_unqueued(&qs).await;
+    }
+    async fn request_queue_gaps(&self, qs: &mut QueueState) {
+        if qs
+            .last_gap_request
+            .is_some_and(|t| t.elapsed() < QUEUE_REQUEST_INTERVAL)
+        {
+            return;
+        }
+        let next = qs.next_message_id;
+        let mut ahead = vec![];
+        self.queue_buffer
+            .scan_async(|id, _m| {
+                if *id > next {
+                    ahead.push(*id);
+                }
+            })
+            .await;
+        if ahead.is_empty() {
+            return;
+        }
+        ahead.sort_unstable();
+        // ranges [l,r] between next and the last buffered message
+        let mut ranges = vec![];
+        let mut l = next;
+        for id in ahead {
+            if id > l {
+                ranges.push((l, id - 1));
+            }
+            l = id + 1;
+        }
+        ranges.truncate(MAX_REQUESTED_RANGES);
+        debug!("requesting missing queue messages {:?}", ranges);
+        qs.last_gap_request = Some(Instant::now());
+        let m = SendMessage::Request(RequestMessage::Queue(ranges));
+        if let Err(e) = self.net.send(m, self.server_psk).await {
+            debug!("could not request queue messages: {}", e);
+        }
+    }
+    async fn process_queue_message(
+        &self,
+        qs: &mut QueueState,
+        timestamp: Timestamp,
+        m: QueueMessageInner,
+    ) {
+        match m {
+            QueueMessageInner::Submission(im) => {
+                qs.submissions.insert(im.submission_id(), im);
+            }
+            QueueMessageInner::EvaluationRequest(im) => {
+                if self.entity == Entity::Worker && im.evaluators.contains(&self.net.psk()) {
+                    qs.assigned.push_back(im.submission_id.clone());
+                    self.assigned_notify.notify_one();
+                }
+                qs.subs.insert(
+                    im.submission_id,
+                    EvaluationInfo::new(im.evaluators, timestamp),
+                );
+            }
+            QueueMessageInner::Evaluation(im) => {
+                if im.evaluation_id.evaluator == self.net.psk() {
+                    // already evaluated, eg before a restart
+                    qs.assigned
+                        .retain(|id| *id != im.evaluation_id.submission_id);
+                    qs.evaluations.remove(&im.evaluation_id.submission_id);
+                }
+                if let Some(ei) = qs.subs.get_mut(&im.evaluation_id.submission_id) {
+                    let evaluator = im.evaluation_id.evaluator;
+                    if !ei.add_evaluation(im) {
+                        warn!(
+                            "{} committed an evaluation after a proof was revealed",
+                            evaluator
+                        );
                     }
-                    QueueMessageInner::Announcement(im) => {
-                        todo!();
+                }
+            }
+            QueueMessageInner::EvaluationProof(im) => {
+                if im.evaluation_id.evaluator == self.net.psk() {
+                    qs.proofs.remove(&im.evaluation_id.submission_id);
+                    qs.revealed.remove(&im.evaluation_id.submission_id);
+                }
+                if let Some(ei) = qs.subs.get_mut(&im.evaluation_id.submission_id) {
+                    let evaluator = im.evaluation_id.evaluator;
+                    if !ei.add_evaluation_proof(im) {
+                        warn!(
+                            "{} revealed a proof before every evaluator committed",
+                            evaluator
+                        );
                     }
-                    QueueMessageInner::PublicKey(im) => {
-                        todo!();
+                    let disputed = ei.disputed_tests();
+                    if !disputed.is_empty() {
+                        warn!("the evaluators disagree on tests {:?}", disputed);
                     }
-                    QueueMessageInner::PeerInfo(im) => {
-                        self.net.update_peer_addr(im.psk, im.addr.inner()).await;
+                }
+            }
+            QueueMessageInner::ProblemDesc(im) => {
+                qs.problems.insert(im.id, im);
+            }
+            QueueMessageInner::Announcement(im) => {
+                info!("announcement: {}", im.text);
+                qs.announcements.push(im);
+            }
+            QueueMessageInner::PublicKey(im) => {
+                qs.keys.insert(im.id, im.key);
+            }
+            QueueMessageInner::PeerInfo(im) => {
+                // an address learned from an established connection is more recent
+                if im.psk == self.net.psk()
+                    || im.psk == self.server_psk
+                    || self.net.is_connected(im.psk).await
+                {
+                    return;
+                }
+                match im.addr.inner(&self.net.obfuscation_key()) {
+                    Some(addr) if addr.is_plausible() => {
+                        debug!("learned address {:?} of {} {:?}", addr, im.psk, im.entity);
+                        self.net.update_peer_addr(im.psk, addr).await;
                     }
+                    addr => debug!("ignoring address {:?} of {}", addr, im.psk),
                 }
             }
         }
     }
+    /// score of a submission as known from the queue so far
+    pub async fn submission_score(&self, id: SubmissionId) -> EvaluationResultScore {
+        match self.queue.lock().await.subs.get(&id) {
+            Some(ei) => ei.score(),
+            None => EvaluationResultScore::None,
+        }
+    }
     pub async fn handle_file_message(&self, m: FileMessage, psk: PubSigKey) {
-        todo!()
+        // chunks of files that were never requested are unknown to the file store and ignored
+        match self
+            .files
+            .add_enc_chunk(m.hash, m.piece as usize, m.data)
+            .await
+        {
+            Ok(true) => {
+                debug!("received file {:?}", m.hash);
+                self.receiving_files
+                    .retain_async(|(hash, _psk), (_start, ah, _size)| {
+                        if *hash == m.hash {
+                            ah.abort();
+                            false
+                        } else {
+                            true
+                        }
+                    })
+                    .await;
+            }
+            Ok(false) => {
+                if let Some(mut entry) = self.receiving_files.get_async(&(m.hash, psk)).await {
+                    entry.get_mut().0 = SystemTime::now();
+                }
+            }
+            Err(ChunkError::BadChunk) => {
+                warn!("bad chunk {} of {:?} from {:?}", m.piece, m.hash, psk)
+            }
+            Err(e) => debug!(
+                "dropping chunk {} of {:?} from {:?}: {:?}",
+                m.piece, m.hash, psk, e
+            ),
+        }
+    }
+    pub async fn handle_chunk_hashes_message(&self, m: ChunkHashesMessage, psk: PubSigKey) {
+        let (hash, first, hashes) = m;
+        if !self.files.add_chunk_hashes(hash, first, hashes).await {
+            debug!(
+                "dropping chunk hashes of {:?} from {} from {:?}",
+                hash, first, psk
+            );
+        }
+    }
+    /// starts receiving a file from psk, chunks are requested until the file is complete,
+    /// returns false if psk is already sending too much
+    pub async fn request_file(
+        &self,
+        hash: FileHash,
+        size: usize,
+        enc_key: EncKey,
+        psk: PubSigKey,
+    ) -> bool {
+        self.download_file(hash, size, enc_key, None, vec![psk])
+            .await
+    }
+    /// follows a file being received, eg for a progress bar,
+    /// a new value comes with every chunk until the file is complete
+    pub async fn file_progress(&self, hash: FileHash) -> Option<watch::Receiver<FileProgress>> {
+        self.files.progress(hash).await
+    }
+    /// starts receiving a file from peers that have it,
+    /// the missing chunks are split among them until the file is complete,
+    /// if chunks_root is given, the chunk hashes are asked too, to check each chunk on arrival,
+    /// peers that are already sending too much are skipped,
+    /// returns false if no peer was left
+    pub async fn download_file(
+        &self,
+        hash: FileHash,
+        size: usize,
+        enc_key: EncKey,
+        chunks_root: Option<Mac>,
+        peers: Vec<PubSigKey>,
+    ) -> bool {
+        let mut downloading = false;
+        // incomplete files and their total size for each peer
+        let mut in_flight = std::collections::HashMap::<PubSigKey, (usize, usize)>::new();
+        self.receiving_files
+            .scan_async(|(h, psk), (_last, _ah, size)| {
+                downloading |= *h == hash;
+                let (files, bytes) = in_flight.entry(*psk).or_default();
+                *files += 1;
+                *bytes += size;
+            })
+            .await;
+        if downloading {
+            return true;
+        }
+        let limits = *self.receive_limits.lock().await;
+        let peers: Vec<PubSigKey> = peers
+            .into_iter()
+            .filter(|psk| {
+                let (files, bytes) = in_flight.get(psk).copied().unwrap_or_default();
+                files < limits.files && bytes.saturating_add(size) <= limits.bytes
+            })
+            .collect();
+        if peers.is_empty() {
+            debug!("not receiving {:?} of size {}, over the limits", hash, size);
+            return false;
+        }
+        let added = match chunks_root {
+            Some(root) => {
+                self.files
+                    .add_new_with_root(hash, size, enc_key, root)
+                    .await
+            }
+            None => self.files.add_new(hash, size, enc_key).await,
+        };
+        if !added {
+            debug!("not receiving {:?} of size {}, too big", hash, size);
+            return false;
+        }
+        let ah = tokio::task::spawn(download_file_loop(
+            self.net.clone(),
+            self.files.clone(),
+            hash,
+            peers.clone(),
+        ))
+        .abort_handle();
+        let now = SystemTime::now();
+        for psk in peers {
+            let _ = self
+                .receiving_files
+                .insert_async((hash, psk), (now, ah.clone(), size))
+                .await;
+        }
+        true
+    }
+    /// limits what can be received at once from each peer, applies to new downloads
+    pub async fn set_receive_limits(&self, limits: ReceiveLimits) {
+        *self.receive_limits.lock().await = limits;
+    }
+    /// aborts the downloads that did not receive a chunk from any peer in idle_timeout,
+    /// and forgets what they received so far
+    pub async fn sweep_file_receives(&self, idle_timeout: Duration) {
+        let now = SystemTime::now();
+        // a download is alive as long as any of its peers is sending
+        let mut last_chunk = std::collections::HashMap::<FileHash, SystemTime>::new();
+        self.receiving_files
+            .scan_async(|(hash, _psk), (last, _ah, _size)| {
+                let t = last_chunk.entry(*hash).or_insert(*last);
+                *t = (*t).max(*last);
+            })
+            .await;
+        let stale: Vec<FileHash> = last_chunk
+            .into_iter()
+            .filter(|(_hash, last)| now.duration_since(*last).unwrap_or_default() > idle_timeout)
+            .map(|(hash, _last)| hash)
+            .collect();
+        for hash in stale {
+            debug!("giving up on receiving file {:?}", hash);
+            self.receiving_files
+                .retain_async(|(h, _psk), (_last, ah, _size)| {
+                    if *h == hash {
+                        ah.abort();
+                        false
+                    } else {
+                        true
+                    }
+                })
+                .await;
+            self.files.remove_incomplete(hash).await;
+        }
+    }
+    /// limits the bandwidth used to serve files, 0 means unlimited
+    pub async fn set_upload_limit(&self, bytes_per_sec: u64) {
+        *self.upload_limit.lock().await = UploadLimit::new(bytes_per_sec);
+    }
+    async fn throttle_upload(&self, n: usize) {
+        let mut limit = self.upload_limit.lock().await;
+        // the lock is held while waiting, so that whoever is next can't overtake
+        while let Some(wait) = limit.take(n) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+    /// takes n bytes from what psk can be served now, returns false if there is not enough
+    async fn take_peer_upload(&self, psk: PubSigKey, n: usize) -> bool {
+        self.peer_upload_limits
+            .entry_async(psk)
+            .await
+            .or_insert_with(|| UploadLimit::new(PEER_UPLOAD_LIMIT))
+            .get_mut()
+            .take(n)
+            .is_none()
     }
     pub async fn handle_request_message(&self, m: RequestMessage, psk: PubSigKey) {
-        todo!()
+        match m {
+            RequestMessage::File(hash, ranges) => {
+                let Some(nchunks) = self.files.nchunks(hash).await else {
+                    return;
+                };
+                // a small request can ask for a whole file, only part of it is sent at a time
+                let pieces = ranges
+                    .into_iter()
+                    .flat_map(|(l, r)| l..r.saturating_add(1).min(nchunks as u32))
+                    .take(MAX_SERVED_CHUNKS);
+                for piece in pieces {
+                    if !self.take_peer_upload(psk, MAX_DATAGRAM_SIZE).await {
+                        debug!("{:?} is asking for too many chunks", psk);
+                        return;
+                    }
+                    self.throttle_upload(MAX_DATAGRAM_SIZE).await;
+                    let Some(data) = self.files.get_enc_chunk(hash, piece as usize).await else {
+                        return;
+                    };
+                    let m = FileMessage { hash, piece, data };
+                    if let Err(e) = self.net.send(SendMessage::File(m), psk).await {
+                        debug!("could not send file chunk to {:?}: {}", psk, e);
+                        return;
+                    }
+                }
+            }
+            RequestMessage::ChunkHashes(hash, first) => {
+                let Some(hashes) = self
+                    .files
+                    .chunk_hashes_from(hash, first as usize, MAX_CHUNK_HASHES)
+                    .await
+                else {
+                    return;
+                };
+                if hashes.is_empty() || !self.take_peer_upload(psk, MAX_DATAGRAM_SIZE).await {
+                    return;
+                }
+                self.throttle_upload(MAX_DATAGRAM_SIZE).await;
+                let m = SendMessage::ChunkHashes((hash, first, hashes));
+                if let Err(e) = self.net.send(m, psk).await {
+                    debug!("could not send chunk hashes to {:?}: {}", psk, e);
+                }
+            }
+            // only the server answers these
+            RequestMessage::Queue(_)
+            | RequestMessage::EncKey(_)
+            | RequestMessage::Rendezvous(_) => {}
+        }
     }
     pub async fn handle_enckey_message(&self, m: EncKeyInfo, psk: PubSigKey) {
-        todo!()
+        // only the server hands out keys, and those published in the queue are final
+        if psk != self.server_psk {
+            debug!("ignoring key {:?} from {:?}", m.id, psk);
+            return;
+        }
+        self.queue.lock().await.keys.entry(m.id).or_insert(m.key);
+    }
+    /// saves the queue messages processed so far, and the evaluations and proofs not queued yet
+    pub async fn save_state(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let qs = self.queue.lock().await;
+        let mut messages = Vec::with_capacity(qs.next_message_id as usize);
+        for id in 0..qs.next_message_id {
+            if let Some(m) = self.queue_buffer.get_async(&id).await {
+                messages.push(m.get().clone());
+            }
+        }
+        let state = SavedState {
+            next_message_id: qs.next_message_id,
+            messages,
+            evaluations: qs.evaluations.values().cloned().collect(),
+            proofs: qs.proofs.values().cloned().collect(),
+        };
+        drop(qs);
+        let data = state.write_to_vec()?;
+        let mut file = vec![PROTOCOL_VERSION, SavedState::VERSION];
+        file.extend_from_slice(blake3::hash(&data).as_bytes());
+        file.extend_from_slice(&data);
+        // write to a temporary file first so that a crash can't leave a truncated state
+        let tmp = path.as_ref().with_extension("tmp");
+        tokio::fs::write(&tmp, file).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+    /// restores the state saved by save_state, replaying the saved queue messages,
+    /// the current state is left untouched if the file is corrupt
+    pub async fn load_state(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let file = tokio::fs::read(path).await?;
+        anyhow::ensure!(file.len() >= 34, "state file is truncated");
+        let (versions, file) = file.split_at(2);
+        anyhow::ensure!(
+            versions == [PROTOCOL_VERSION, SavedState::VERSION],
+            "state file was saved by an incompatible version"
+        );
+        let (hash, data) = file.split_at(32);
+        anyhow::ensure!(
+            blake3::hash(data).as_bytes() == hash,
+            "state file is corrupt"
+        );
+        let state = SavedState::read_from_buffer(data)?;
+        anyhow::ensure!(
+            state.messages.len() == state.next_message_id as usize
+                && state
+                    .messages
+                    .iter()
+                    .enumerate()
+                    .all(|(i, m)| m.id as usize == i),
+            "state file has missing queue messages"
+        );
+        let mut qs = self.queue.lock().await;
+        // restored before the replay, which drops the queued ones
+        // and reveals the proofs once every evaluator committed
+        for evaluation in state.evaluations {
+            qs.evaluations
+                .entry(evaluation.evaluation_id.submission_id.clone())
+                .or_insert(evaluation);
+        }
+        for proof in state.proofs {
+            qs.proofs
+                .entry(proof.evaluation_id.submission_id.clone())
+                .or_insert(proof);
+        }
+        // messages that were already received are skipped by the buffer
+        for m in state.messages {
+            let _ = self.queue_buffer.insert_async(m.id, m).await;
+        }
+        self.process_buffered_queue(&mut qs).await;
+        Ok(())
+    }
+    /// asks the server for the queue messages that were not processed yet
+    pub async fn request_queue(&self) -> anyhow::Result<()> {
+        let next_message_id = self.queue.lock().await.next_message_id;
+        let m = RequestMessage::Queue(vec![(next_message_id, u32::MAX)]);
+        self.net
+            .send(SendMessage::Request(m), self.server_psk)
+            .await?;
+        Ok(())
+    }
+    /// stores the source so that it can be served to whoever evaluates it,
+    /// and sends the submission to the server
+    pub async fn submit(
+        &self,
+        problem_id: ProblemId,
+        source: Vec<u8>,
+        limits_preset: LimitsPreset,
+    ) -> anyhow::Result<FileHash> {
+        anyhow::ensure!(self.entity != Entity::Spectator, "spectators can't submit");
+        if let Some(problem) = self.queue.lock().await.problems.get(&problem_id) {
+            anyhow::ensure!(
+                problem.allows(limits_preset),
+                "problem {} does not allow {:?}",
+                problem_id,
+                limits_preset
+            );
+        }
+        let file_size = u32::try_from(source.len())?;
+        let enc_key = EncKey::random(self.net.rng());
+        let file_id = self.files.add_done_with_key(source, enc_key).await;
+        // the same source may have been stored before with another key
+        let enc_key = self.files.enc_key(file_id).await.unwrap_or(enc_key);
+        let m = SubmissionMessage::new(problem_id, file_id, file_size, limits_preset, enc_key);
+        self.net
+            .send(SendMessage::Submission(m), self.server_psk)
+            .await?;
+        Ok(file_id)
+    }
+    /// evaluates the submissions assigned to this worker, one at a time, forever
+    pub async fn worker_loop(self: Arc<Self>) {
+        loop {
+            let next = self.queue.lock().await.assigned.pop_front();
+            let Some(id) = next else {
+                self.assigned_notify.notified().await;
+                continue;
+            };
+            if let Err(e) = self.evaluate(id.clone()).await {
+                warn!("could not evaluate {:?}: {}", id, e);
+            }
+        }
+    }
+    /// fetches the files of the submission and its problem, evaluates it,
+    /// and sends the evaluation to the server, keeping the proof for later,
+    /// both are kept until they show up in the queue
+    async fn evaluate(&self, id: SubmissionId) -> anyhow::Result<()> {
+        let (sub, problem) = {
+            let qs = self.queue.lock().await;
+            let sub = qs
+                .submissions
+                .get(&id)
+                .cloned()
+                .ok_or(anyhow::anyhow!("unknown submission"))?;
+            let problem = qs
+                .problems
+                .get(&id.problem_id)
+                .cloned()
+                .ok_or(anyhow::anyhow!("unknown problem {}", id.problem_id))?;
+            (sub, problem)
+        };
+        // the submitter picks the preset, only the problem decides which ones it gets
+        anyhow::ensure!(
+            problem.allows(sub.limits_preset),
+            "problem {} does not allow {:?}",
+            id.problem_id,
+            sub.limits_preset
+        );
+        let source = self.fetch_file(&sub.file_desc).await?;
+        let tests = match &problem.tests {
+            QTests::Generated(desc) => TestData::Generator(self.fetch_file(desc).await?),
+            QTests::Static(desc) => {
+                let data = self.fetch_file(desc).await?;
+                TestData::Inputs(Vec::<Vec<u8>>::read_from_buffer(&data)?)
+            }
+        };
+        let scorer = match &problem.scorer {
+            QScorer::Eval(desc) => ScorerData::Eval(self.fetch_file(desc).await?),
+            QScorer::Diff(mode, desc) => {
+                let data = self.fetch_file(desc).await?;
+                ScorerData::Diff(*mode, Vec::<Vec<u8>>::read_from_buffer(&data)?)
+            }
+        };
+        let limits = preset_limits(sub.limits_preset);
+        // if this task is aborted, the evaluation stops at the next test instead of running to the end
+        let cancel = evaluator::CancelToken::new();
+        let _cancel_guard = cancel.cancel_on_drop();
+        let evaluation = tokio::task::spawn_blocking(move || {
+            let tests = match &tests {
+                TestData::Generator(gen) => evaluator::Tests::Generated(gen),
+                TestData::Inputs(inputs) => evaluator::Tests::Static(inputs),
+            };
+            let eval;
+            let scoring = match &scorer {
+                ScorerData::Eval(scorer) => {
+                    eval = [scorer.as_slice()];
+                    evaluator::Scoring::Eval(&eval)
+                }
+                ScorerData::Diff(mode, expected) => {
+                    evaluator::Scoring::Diff(diff_mode(*mode), expected)
+                }
+            };
+            evaluator::evaluate_submission(
+                tests,
+                scoring,
+                &source,
+                limits,
+                problem.n_testcases,
+                // problems don't describe extra test args (yet), tests only get their test_id
+                &[],
+                &cancel,
+            )
+        })
+        .await??;
+        // the scorer of the problem is broken, scoring the submission 0 would blame it
+        let mut verdicts = evaluation
+            .verdicts
+            .iter()
+            .map(|&v| test_verdict(v))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(anyhow::anyhow!(
+                "the evaluator of problem {} failed",
+                id.problem_id
+            ))?;
+        verdicts.truncate(MAX_VERDICTS);
+        let score = evaluation.score;
+        let score = SubScore::try_from(score.into_inner())
+            .map_err(|_| anyhow::anyhow!("invalid score {}", score))?;
+        let proof = QEvaluationProof {
+            evaluation_id: EvaluationId {
+                submission_id: id.clone(),
+                evaluator: self.net.psk(),
+            },
+            detailhs: Mac(evaluation.hash),
+            test_hashes: evaluation
+                .test_hashes
+                .iter()
+                .take(MAX_TEST_HASHES)
+                .map(|h| h.as_bytes()[..8].try_into().unwrap())
+                .collect(),
+        };
+        let evaluation = QEvaluation::new(proof.clone(), score, verdicts);
+        let mut qs = self.queue.lock().await;
+        qs.evaluations.insert(id.clone(), evaluation.clone());
+        qs.proofs.insert(id, proof);
+        drop(qs);
+        // if it gets lost, request_missing_queue sends it again
+        self.send_evaluation(evaluation).await;
+        Ok(())
+    }
+    /// downloads a file described in the queue from the server and decrypts it
+    async fn fetch_file(&self, desc: &QFileDesc) -> anyhow::Result<Vec<u8>> {
+        let key_encrypting_key = self.wait_enc_key(&desc.key_encrypting_key).await?;
+        let enc_key = desc
+            .enc_encrypting_key
+            .inner(&key_encrypting_key)
+            .ok_or(anyhow::anyhow!(
+                "could not decrypt the key of {:?}",
+                desc.hash
+            ))?;
+        anyhow::ensure!(
+            self.download_file(
+                desc.hash,
+                desc.size as usize,
+                enc_key,
+                Some(desc.chunks_root),
+                vec![self.server_psk]
+            )
+            .await,
+            "could not download {:?}",
+            desc.hash
+        );
+        let ff = self.files.get_file(desc.hash).await;
+        let ff = tokio::time::timeout(WORKER_FETCH_TIMEOUT, ff.wait()).await?;
+        Ok(ff.get_all().to_vec())
+    }
+    /// the enc key with the given id, asking the server for it if it's not known yet
+    async fn wait_enc_key(&self, id: &EncKeyId) -> anyhow::Result<EncKey> {
+        let start = Instant::now();
+        loop {
+            if let Some(key) = self.queue.lock().await.keys.get(id) {
+                return Ok(*key);
+            }
+            anyhow::ensure!(
+                start.elapsed() < WORKER_FETCH_TIMEOUT,
+                "did not get the key {:?}",
+                id
+            );
+            let m = SendMessage::Request(RequestMessage::EncKey(id.clone()));
+            if let Err(e) = self.net.send(m, self.server_psk).await {
+                debug!("could not request key {:?}: {}", id, e);
+            }
+            tokio::time::sleep(QUEUE_REQUEST_INTERVAL).await;
+        }
+    }
+    /// asks the server to help connecting to psk, for when both are behind a nat
+    pub async fn rendezvous(&self, psk: PubSigKey) -> anyhow::Result<()> {
+        self.net
+            .send(
+                SendMessage::Request(RequestMessage::Rendezvous(psk)),
+                self.server_psk,
+            )
+            .await?;
+        Ok(())
+    }
+    pub async fn ask_question(
+        &self,
+        text: String,
+        context: Option<ProblemId>,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.entity != Entity::Spectator,
+            "spectators can't ask questions"
+        );
+        let m = QuestionMessage::new(text, context)?;
+        self.net
+            .send(SendMessage::Question(m), self.server_psk)
+            .await?;
+        Ok(())
+    }
+}
+
+async fn download_file_loop(
+    net: Arc<Net>,
+    files: Arc<FileStore>,
+    hash: FileHash,
+    peers: Vec<PubSigKey>,
+) {
+    // each round the blocks are assigned to different peers,
+    // so that what a peer did not send is asked to another one
+    for round in 0.. {
+        let Some(missing) = files.missing_chunks(hash).await else {
+            return;
+        };
+        if missing.is_empty() {
+            return;
+        }
+        // asked along with the chunks, the chunks received before the hashes are checked later
+        let mut firsts = files.missing_chunk_hashes(hash).await;
+        firsts.truncate(MAX_REQUESTED_RANGES);
+        for (i, first) in firsts.into_iter().enumerate() {
+            let psk = peers[(round + i) % peers.len()];
+            let m = SendMessage::Request(RequestMessage::ChunkHashes(hash, first));
+            if let Err(e) = net.send(m, psk).await {
+                debug!("could not request chunk hashes from {:?}: {}", psk, e);
+            }
+        }
+        let schedule = schedule_ranges(&missing, peers.len(), CHUNKS_PER_BLOCK, round);
+        for (psk, mut ranges) in peers.iter().zip(schedule) {
+            if ranges.is_empty() {
+                continue;
+            }
+            ranges.truncate(MAX_REQUESTED_RANGES);
+            let m = SendMessage::Request(RequestMessage::File(hash, ranges));
+            if let Err(e) = net.send(m, *psk).await {
+                debug!("could not request file from {:?}: {}", psk, e);
+            }
+        }
+        tokio::time::sleep(FILE_REQUEST_INTERVAL).await;
     }
-    //TODO: submit
-    //TODO: question
 }
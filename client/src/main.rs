@@ -4,10 +4,15 @@ use argh::FromArgs;
 use client::*;
 use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey};
 use net::*;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 use tokio::task;
 use tracing::*;
 
+const MAX_CONCURRENT_HANDLERS: usize = 64;
+
 #[derive(FromArgs)]
 #[argh(description = "decipi")]
 struct Args {
@@ -19,11 +24,46 @@ struct Args {
     )]
     entity: Entity,
     #[argh(option, description = "id of the contest to connect to")]
-    contest_id: ContestId,
+    contest_id: Option<ContestId>,
     #[argh(option, description = "server address for the contest to connect to")]
-    server_addr: PeerAddr,
+    server_addr: Option<PeerAddr>,
     #[argh(option, description = "public key for the contest to connect to")]
-    server_psk: PubSigKey,
+    server_psk: Option<PubSigKey>,
+    #[argh(
+        option,
+        description = "file where the contest state is saved and reloaded from"
+    )]
+    state: Option<PathBuf>,
+    #[argh(
+        option,
+        default = "0",
+        description = "bytes per second that can be used to serve files, 0 means unlimited"
+    )]
+    upload_limit: u64,
+    #[argh(
+        option,
+        default = "60",
+        description = "seconds without receiving a chunk after which a download is abandoned"
+    )]
+    file_receive_timeout: u64,
+    #[argh(
+        switch,
+        description = "print the signing key as a PKCS#8 PEM to stdout and exit"
+    )]
+    export_key: bool,
+    #[argh(
+        option,
+        description = "replace the signing key with the PKCS#8 PEM in this file and exit"
+    )]
+    import_key: Option<PathBuf>,
+}
+
+fn import_key(entry: &keyring::Entry, path: &PathBuf) -> anyhow::Result<PubSigKey> {
+    let pkcs8 = std::fs::read_to_string(path)?;
+    let key = ed25519_dalek::SigningKey::from_pkcs8_pem(&pkcs8)
+        .map_err(|e| anyhow::anyhow!("invalid key: {}", e))?;
+    entry.set_password(&pkcs8)?;
+    Ok(PubSigKey::from(&key))
 }
 
 #[tokio::main]
@@ -40,6 +80,32 @@ async fn main() {
 
     // get signing keypair
     let entry = keyring::Entry::new("decipi", &whoami::username()).unwrap();
+    if args.export_key {
+        match entry.get_password() {
+            Ok(pkcs8) => print!("{}", pkcs8),
+            Err(e) => {
+                error!("could not read the key: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(path) = &args.import_key {
+        match import_key(&entry, path) {
+            Ok(psk) => info!("imported key, public key: {}", psk),
+            Err(e) => {
+                error!("could not import the key from {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let (Some(contest_id), Some(server_addr), Some(server_psk)) =
+        (args.contest_id, args.server_addr, args.server_psk)
+    else {
+        error!("--contest-id, --server-addr and --server-psk are required");
+        return;
+    };
     let ssk = match entry.get_password() {
         Err(_) => {
             info!("generating new ed25519 keypair");
@@ -55,42 +121,82 @@ async fn main() {
         Ok(pkcs8) => ed25519_dalek::SigningKey::from_pkcs8_pem(&pkcs8).unwrap(),
     };
 
-    let client = Arc::new(
-        Client::new(
-            args.server_psk,
-            args.server_addr,
-            args.contest_id,
-            args.entity,
-            ssk,
-        )
-        .await,
-    );
+    info!("public key: {}", PubSigKey::from(&ssk));
 
-    loop {
-        let mut buf = [0u8; MAX_MESSAGE_SIZE];
-        let (m, psk) = client.recv(&mut buf).await;
-        let c = client.clone();
-        match m {
-            RecvMessage::Queue(m) => {
-                task::spawn(async move {
-                    c.handle_queue_message(m, psk).await;
-                });
+    let client = match Client::new(server_psk, server_addr, contest_id, args.entity, ssk).await {
+        Ok(client) => Arc::new(client),
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    client.set_upload_limit(args.upload_limit).await;
+
+    let c = client.clone();
+    task::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            c.request_missing_queue().await;
+        }
+    });
+
+    if args.entity == Entity::Worker {
+        task::spawn(client.clone().worker_loop());
+    }
+
+    let c = client.clone();
+    let file_receive_timeout = Duration::from_secs(args.file_receive_timeout);
+    task::spawn(async move {
+        loop {
+            tokio::time::sleep(file_receive_timeout / 4).await;
+            c.sweep_file_receives(file_receive_timeout).await;
+        }
+    });
+
+    if let Some(path) = args.state {
+        if path.exists() {
+            if let Err(e) = client.load_state(&path).await {
+                warn!("could not load state from {:?}: {}", path, e);
             }
-            RecvMessage::File(m) => {
-                task::spawn(async move {
-                    c.handle_file_message(m, psk).await;
-                });
+        }
+        let c = client.clone();
+        task::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                if let Err(e) = c.save_state(&path).await {
+                    warn!("could not save state to {:?}: {}", path, e);
+                }
             }
-            RecvMessage::Request(m) => {
-                task::spawn(async move {
-                    c.handle_request_message(m, psk).await;
-                });
+        });
+    }
+
+    // at most this many messages are handled at once,
+    // the others wait in the socket buffer
+    let handlers = Arc::new(Semaphore::new(MAX_CONCURRENT_HANDLERS));
+    // received messages own their data, so the buffer is reused for the next one
+    let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let permit = handlers.clone().acquire_owned().await.unwrap();
+        let (m, psk) = match client.recv(&mut buf).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("could not receive: {}", e);
+                return;
             }
-            RecvMessage::EncKey(m) => {
-                task::spawn(async move {
-                    c.handle_enckey_message(m, psk).await;
-                });
+        };
+        let c = client.clone();
+        task::spawn(async move {
+            match m {
+                RecvMessage::Queue(m) => c.handle_queue_message(m, psk).await,
+                RecvMessage::File(m) => c.handle_file_message(m, psk).await,
+                RecvMessage::ChunkHashes(m) => c.handle_chunk_hashes_message(m, psk).await,
+                RecvMessage::Request(m) => c.handle_request_message(m, psk).await,
+                RecvMessage::EncKey(m) => c.handle_enckey_message(m, psk).await,
+                // only delivered to the server
+                _ => {}
             }
-        }
+            drop(permit);
+        });
     }
 }
@@ -1,15 +1,158 @@
 use blake3::Hasher;
-use num_traits::identities::Zero;
+use num_traits::identities::{One, Zero};
 use ordered_float::NotNan;
+use std::borrow::Cow;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{field, instrument, Span};
 use wasi_common::pipe::*;
 use wasi_common::WasiCtx;
+use wasmtime::component::Component;
 use wasmtime::*;
+pub use wasmtime::{Engine, Module};
+use wasmtime_wasi::preview2;
+use wasmtime_wasi::preview2::pipe::{MemoryInputPipe, MemoryOutputPipe};
 
+/// the sandbox of a submission, every resource is limited on its own
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Limits {
     memory: u32,
     cpu: u64,
+    table_elements: u32,
+    instances: u32,
+    memories: u32,
+    tables: u32,
+}
+impl Limits {
+    /// memory in bytes, cpu in fuel, the rest is the defaults:
+    /// a single instance with a single memory and a single table of DEFAULT_TABLE_ELEMENTS,
+    /// which is what a program compiled to wasm needs
+    pub const fn new(memory: u32, cpu: u64) -> Self {
+        Self {
+            memory,
+            cpu,
+            table_elements: Self::DEFAULT_TABLE_ELEMENTS,
+            instances: 1,
+            memories: 1,
+            tables: 1,
+        }
+    }
+    /// elements of a table are function references, a few bytes each in the host,
+    /// a program needs one for each function it calls indirectly
+    pub const DEFAULT_TABLE_ELEMENTS: u32 = 1 << 20;
+    /// for submissions compiled straight to wasm (c, c++, rust, ...),
+    /// as core modules or as components (see for_component)
+    pub const NATIVE: Self = Self::new(256 << 20, 10_000_000_000);
+    /// for interpreters compiled to wasm running the submission (python, ...),
+    /// which need a lot more of both
+    pub const INTERPRETED: Self = Self::new(1 << 30, 200_000_000_000);
+    /// elements of each table
+    pub const fn with_table_elements(mut self, table_elements: u32) -> Self {
+        self.table_elements = table_elements;
+        self
+    }
+    // a component built for wasm32-wasip2 is the program, the wasi adapter and the shims
+    // linking them, each its own core instance with its own memories and tables
+    pub const COMPONENT_INSTANCES: u32 = 16;
+    pub const COMPONENT_MEMORIES: u32 = 4;
+    pub const COMPONENT_TABLES: u32 = 4;
+    /// the limits a component runs with: at least the counts it needs to be instantiated,
+    /// every preset is raised to these when the submission is a component
+    pub const fn for_component(self) -> Self {
+        const fn at_least(n: u32, min: u32) -> u32 {
+            if n < min {
+                min
+            } else {
+                n
+            }
+        }
+        self.with_instances(at_least(self.instances, Self::COMPONENT_INSTANCES))
+            .with_memories(at_least(self.memories, Self::COMPONENT_MEMORIES))
+            .with_tables(at_least(self.tables, Self::COMPONENT_TABLES))
+    }
+    /// core instances, for a component the ones it is made of (see for_component)
+    pub const fn with_instances(mut self, instances: u32) -> Self {
+        self.instances = instances;
+        self
+    }
+    pub const fn with_memories(mut self, memories: u32) -> Self {
+        self.memories = memories;
+        self
+    }
+    pub const fn with_tables(mut self, tables: u32) -> Self {
+        self.tables = tables;
+        self
+    }
+    pub fn memory(&self) -> u32 {
+        self.memory
+    }
+    pub fn cpu(&self) -> u64 {
+        self.cpu
+    }
+    pub fn table_elements(&self) -> u32 {
+        self.table_elements
+    }
+    pub fn instances(&self) -> u32 {
+        self.instances
+    }
+    pub fn memories(&self) -> u32 {
+        self.memories
+    }
+    pub fn tables(&self) -> u32 {
+        self.tables
+    }
+}
+
+/// submissions run with fuel, contest modules (generator, evaluator) without
+#[derive(Clone)]
+pub struct Engines {
+    pub contest: Engine,
+    pub submission: Engine,
+}
+impl Engines {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            contest: get_contest_engine()?,
+            submission: get_submission_engine()?,
+        })
+    }
+    pub fn contest_module(&self, wasm: &[u8]) -> anyhow::Result<Module> {
+        Module::from_binary(&self.contest, wasm)
+    }
+    pub fn submission_module(&self, wasm: &[u8]) -> anyhow::Result<Module> {
+        Module::from_binary(&self.submission, wasm)
+    }
+    /// compiles a submission as a component or as a core module, depending on what it is
+    pub fn submission(&self, wasm: &[u8]) -> anyhow::Result<Submission> {
+        if is_component(wasm) {
+            Ok(Submission::Component(Component::from_binary(
+                &self.submission,
+                wasm,
+            )?))
+        } else {
+            Ok(Submission::Module(self.submission_module(wasm)?))
+        }
+    }
+}
+
+/// a core module exporting _start, or a component exporting wasi:cli/run,
+/// wasmtime 14 links the release candidate of wasi 0.2 it ships, not wasi 0.2.0,
+/// so components must be adapted from wasm32-wasi programs (see testwasm/README.md)
+#[derive(Clone)]
+pub enum Submission {
+    Module(Module),
+    Component(Component),
+}
+impl From<Module> for Submission {
+    fn from(module: Module) -> Self {
+        Self::Module(module)
+    }
+}
+/// components have the same magic as core modules, but layer 1 in the version field
+pub fn is_component(wasm: &[u8]) -> bool {
+    wasm.len() >= 8 && wasm[..4] == *b"\0asm" && wasm[6..8] == [1, 0]
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -18,205 +161,701 @@ pub enum TestEval {
     TLE,
     MLE,
     RTE,
+    // the output of the submission could not be collected (eg it was too large)
+    MFO,
+    // the evaluator output is not a valid verdict
+    EvaluatorError,
+}
+impl TestEval {
+    /// the points of the test, the ones that were not scored get none
+    pub fn score(&self) -> NotNan<f64> {
+        match self {
+            TestEval::Score(s) => *s,
+            TestEval::TLE
+            | TestEval::MLE
+            | TestEval::RTE
+            | TestEval::MFO
+            | TestEval::EvaluatorError => NotNan::zero(),
+        }
+    }
+}
+/// what the evaluator prints to stdout:
+/// the first whitespace separated token is the score in 0..=1, the rest is an optional message
+#[derive(Clone, Debug, PartialEq)]
+pub struct Verdict {
+    pub score: NotNan<f64>,
+    pub message: String,
+}
+impl FromStr for Verdict {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_start();
+        let (score, message) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        let score = NotNan::<f64>::from_str(score)?;
+        // same invariant as SubScore
+        if !(0.0..=1.0).contains(&score.into_inner()) {
+            anyhow::bail!("score {} not in 0..=1", score);
+        }
+        Ok(Self {
+            score,
+            message: message.trim().to_string(),
+        })
+    }
 }
 #[derive(Clone, Debug, PartialEq)]
 pub enum SubRes {
-    OK(String),
+    OK(Vec<u8>),
     TLE,
     MLE,
     RTE,
     MFO,
 }
 
+/// the args of the generator and of the eval modules are the test_id, then the extra args
+/// of the test (eg a subtask, a difficulty or a variant, as configured for the problem),
+/// so a module that only reads the test_id works the same whatever the extra args are
+fn push_test_args(ctx: &mut WasiCtx, test_id: u32, args: &[String]) -> anyhow::Result<()> {
+    ctx.push_arg(&test_id.to_string())?;
+    for arg in args {
+        ctx.push_arg(arg)?;
+    }
+    Ok(())
+}
+
+/// where the input of each test comes from
+#[derive(Clone)]
+pub enum TestInputs {
+    /// a deterministic generator, whose output for a test is its input
+    Generator(Module),
+    /// the inputs of the tests, by test_id
+    Static(Vec<Vec<u8>>),
+}
+impl From<Module> for TestInputs {
+    fn from(module: Module) -> Self {
+        Self::Generator(module)
+    }
+}
+/// like TestInputs, as given to evaluate_submission
+#[derive(Clone, Copy, Debug)]
+pub enum Tests<'a> {
+    /// the wasm of the generator
+    Generated(&'a [u8]),
+    /// the inputs of the tests, by test_id
+    Static(&'a [Vec<u8>]),
+}
+
+/// the input of the test, a static input is hashed as is, since there is no generator run to hash
+fn test_input<'a>(
+    inputs: &'a TestInputs,
+    engine: Engine,
+    test_id: u32,
+    args: &[String],
+    hasher: &mut Hasher,
+) -> anyhow::Result<Cow<'a, [u8]>> {
+    match inputs {
+        TestInputs::Generator(module) => {
+            run_gen(module.clone(), engine, test_id, args, hasher).map(Cow::Owned)
+        }
+        TestInputs::Static(inputs) => {
+            let input = inputs
+                .get(test_id as usize)
+                .ok_or_else(|| anyhow::anyhow!("no input for test {}", test_id))?;
+            hasher.update(input);
+            Ok(Cow::Borrowed(input))
+        }
+    }
+}
+
+#[instrument(level = "debug", skip_all, fields(test_id = test_id, duration = field::Empty))]
 fn run_gen(
     module: Module,
     engine: Engine,
     test_id: u32,
+    args: &[String],
     hasher: &mut Hasher,
-) -> anyhow::Result<String> {
+) -> anyhow::Result<Vec<u8>> {
     let stdout = WritePipe::new_in_memory();
     let mut ctx = deterministic_wasi_ctx::build_wasi_ctx();
     ctx.set_stdout(Box::new(stdout.clone()));
-    ctx.push_arg(&test_id.to_string())?;
+    push_test_args(&mut ctx, test_id, args)?;
     run_wasi(&module, &engine, ctx, None, StoreLimits::default(), hasher)??;
-    let contents: Vec<u8> = stdout
+    Ok(stdout
         .try_into_inner()
         .map_err(|e| anyhow::anyhow!("error getting contents of stdout pipe: {:?}", e))?
-        .into_inner();
-    Ok(String::from_utf8(contents)?)
+        .into_inner())
 }
 
+#[instrument(
+    level = "debug",
+    skip_all,
+    fields(test_id = test_id, fuel = field::Empty, duration = field::Empty)
+)]
 fn run_sub(
-    module: Module,
+    sub: &Submission,
     engine: Engine,
-    input: String,
+    test_id: u32,
+    input: &[u8],
     limits: Limits,
     hasher: &mut Hasher,
 ) -> anyhow::Result<SubRes> {
-    let stdin = ReadPipe::from(input.as_bytes());
+    let module = match sub {
+        Submission::Module(module) => module,
+        Submission::Component(component) => {
+            return run_sub_component(component, &engine, input, limits, hasher)
+        }
+    };
+    let stdin = ReadPipe::from(input);
     let stdout = WritePipe::new_in_memory();
     let ctx = deterministic_wasi_ctx::build_wasi_ctx();
     ctx.set_stdin(Box::new(stdin.clone()));
     ctx.set_stdout(Box::new(stdout.clone()));
-    let store_limits = StoreLimitsBuilder::new()
-        .trap_on_grow_failure(true)
-        .instances(1)
-        .memories(1)
-        .memory_size(limits.memory as usize)
-        .tables(1)
-        .table_elements(limits.memory >> 4)
-        .build();
     let result = run_wasi(
-        &module,
+        module,
         &engine,
         ctx,
         Some(limits.cpu),
-        store_limits,
+        sub_store_limits(limits),
         hasher,
     )?;
     match result {
         Ok(()) => {
             if let Ok(inner) = stdout.try_into_inner() {
-                let contents: Vec<u8> = inner.into_inner();
-                Ok(SubRes::OK(String::from_utf8(contents).unwrap()))
+                Ok(SubRes::OK(inner.into_inner()))
             } else {
-                Ok(SubRes::MFO) //TODO
+                Ok(SubRes::MFO)
             }
         }
-        Err(e) => {
-            if let Some(&t) = e.root_cause().downcast_ref::<Trap>() {
-                match t {
-                    Trap::OutOfFuel => Ok(SubRes::TLE),
-                    Trap::MemoryOutOfBounds => Ok(SubRes::MLE),
-                    Trap::TableOutOfBounds => Ok(SubRes::MLE),
-                    _ => Ok(SubRes::RTE),
-                }
-            } else {
-                // TODO: better solution
-                let t = e.root_cause().to_string();
-                if t.contains("forcing trap when growing memory") {
-                    Ok(SubRes::MLE)
-                } else {
-                    Ok(SubRes::RTE)
-                }
-            }
+        Err(e) => Ok(trap_result(&e)),
+    }
+}
+
+fn sub_store_limits(limits: Limits) -> StoreLimits {
+    StoreLimitsBuilder::new()
+        .trap_on_grow_failure(true)
+        .instances(limits.instances as usize)
+        .memories(limits.memories as usize)
+        .memory_size(limits.memory as usize)
+        .tables(limits.tables as usize)
+        .table_elements(limits.table_elements)
+        .build()
+}
+
+/// what a submission that trapped gets
+fn trap_result(e: &anyhow::Error) -> SubRes {
+    if let Some(&t) = e.root_cause().downcast_ref::<Trap>() {
+        match t {
+            Trap::OutOfFuel => SubRes::TLE,
+            Trap::MemoryOutOfBounds => SubRes::MLE,
+            Trap::TableOutOfBounds => SubRes::MLE,
+            _ => SubRes::RTE,
         }
+    } else {
+        // TODO: better solution
+        let t = e.root_cause().to_string();
+        if t.contains("forcing trap when growing memory") {
+            SubRes::MLE
+        } else {
+            SubRes::RTE
+        }
+    }
+}
+
+// stdout of a component is kept in a buffer of this size, filling it is MFO
+const MAX_COMPONENT_OUTPUT: usize = 1 << 26;
+
+/// same as run_sub, for components:
+/// their memories are not exported, so the output is hashed instead, along with the fuel
+fn run_sub_component(
+    component: &Component,
+    engine: &Engine,
+    input: &[u8],
+    limits: Limits,
+    hasher: &mut Hasher,
+) -> anyhow::Result<SubRes> {
+    struct State {
+        limits: StoreLimits,
+        table: preview2::Table,
+        wasi: preview2::WasiCtx,
+    }
+    impl preview2::WasiView for State {
+        fn table(&self) -> &preview2::Table {
+            &self.table
+        }
+        fn table_mut(&mut self) -> &mut preview2::Table {
+            &mut self.table
+        }
+        fn ctx(&self) -> &preview2::WasiCtx {
+            &self.wasi
+        }
+        fn ctx_mut(&mut self) -> &mut preview2::WasiCtx {
+            &mut self.wasi
+        }
+    }
+    let stdout = MemoryOutputPipe::new(MAX_COMPONENT_OUTPUT);
+    // the same as deterministic_wasi_ctx does for core modules
+    let wasi = preview2::WasiCtxBuilder::new()
+        .stdin(MemoryInputPipe::new(input.to_vec().into()))
+        .stdout(stdout.clone())
+        .secure_random(CounterRng(0))
+        .insecure_random(CounterRng(0))
+        .insecure_random_seed(0)
+        .wall_clock(FrozenClock)
+        .monotonic_clock(FrozenClock)
+        .build();
+    let mut linker = component::Linker::new(engine);
+    preview2::command::sync::add_to_linker(&mut linker)?;
+    let mut store = Store::new(
+        engine,
+        State {
+            limits: sub_store_limits(limits.for_component()),
+            table: preview2::Table::new(),
+            wasi,
+        },
+    );
+    store.limiter(|state| &mut state.limits);
+    store.add_fuel(limits.cpu)?;
+    let timer = PhaseTimer::start();
+    let (command, _instance) =
+        preview2::command::sync::Command::instantiate(&mut store, component, &linker)?;
+    let result = command.wasi_cli_run().call_run(&mut store);
+    let fuel_used = store.fuel_consumed().unwrap_or_default();
+    timer.record(Some(fuel_used));
+    hasher.update(&fuel_used.to_be_bytes());
+    let output = stdout.contents().to_vec();
+    hasher.update(&output);
+    // the write that overflows fails, so the guest usually panics or exits with an error after it
+    if output.len() >= MAX_COMPONENT_OUTPUT {
+        return Ok(SubRes::MFO);
     }
+    Ok(match result {
+        Ok(Ok(())) => SubRes::OK(output),
+        // exited with an error
+        Ok(Err(())) => SubRes::RTE,
+        Err(e) => trap_result(&e),
+    })
 }
 
+/// deterministic stand in for the random sources of a component (splitmix64)
+struct CounterRng(u64);
+impl rand_core::RngCore for CounterRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+/// clock that is always at 0
+struct FrozenClock;
+impl preview2::HostWallClock for FrozenClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+    fn now(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+impl preview2::HostMonotonicClock for FrozenClock {
+    fn resolution(&self) -> u64 {
+        1
+    }
+    fn now(&self) -> u64 {
+        0
+    }
+}
+
+/// returns None if the eval module did not exit successfully
+#[instrument(level = "debug", skip_all, fields(test_id = test_id, duration = field::Empty))]
 fn run_eval(
     module: Module,
     engine: Engine,
     test_id: u32,
-    input: String,
+    args: &[String],
+    input: &[u8],
     hasher: &mut Hasher,
-) -> anyhow::Result<String> {
-    let stdin = ReadPipe::from(input.as_bytes());
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let stdin = ReadPipe::from(input);
     let stdout = WritePipe::new_in_memory();
     let mut ctx = deterministic_wasi_ctx::build_wasi_ctx();
     ctx.set_stdin(Box::new(stdin.clone()));
     ctx.set_stdout(Box::new(stdout.clone()));
-    ctx.push_arg(&test_id.to_string())?;
-    run_wasi(&module, &engine, ctx, None, StoreLimits::default(), hasher)??;
-    let contents: Vec<u8> = stdout
-        .try_into_inner()
-        .map_err(|e| anyhow::anyhow!("error getting contents of stdout pipe: {:?}", e))?
-        .into_inner();
-    Ok(String::from_utf8(contents)?)
+    push_test_args(&mut ctx, test_id, args)?;
+    if run_wasi(&module, &engine, ctx, None, StoreLimits::default(), hasher)?.is_err() {
+        return Ok(None);
+    }
+    Ok(Some(
+        stdout
+            .try_into_inner()
+            .map_err(|e| anyhow::anyhow!("error getting contents of stdout pipe: {:?}", e))?
+            .into_inner(),
+    ))
+}
+
+// a phase that is not the last one can print this as its first token to stop the pipeline,
+// the test gets a score of 0
+pub const REJECT: &str = "REJECT";
+
+/// runs the eval phases in order, each one gets the stdout of the previous one,
+/// the verdict is the output of the last phase, a score of 0 if a phase rejected,
+/// or EvaluatorError if a phase failed or the last one printed something that is not a verdict
+fn run_eval_phases(
+    phases: &[Module],
+    engine: Engine,
+    test_id: u32,
+    args: &[String],
+    input: &[u8],
+    hasher: &mut Hasher,
+) -> anyhow::Result<TestEval> {
+    let Some((last, phases)) = phases.split_last() else {
+        anyhow::bail!("no eval phases");
+    };
+    let mut output = input.to_vec();
+    for module in phases {
+        let Some(out) = run_eval(
+            module.clone(),
+            engine.clone(),
+            test_id,
+            args,
+            &output,
+            hasher,
+        )?
+        else {
+            return Ok(TestEval::EvaluatorError);
+        };
+        output = out;
+        let mut tokens = output
+            .split(u8::is_ascii_whitespace)
+            .filter(|x| !x.is_empty());
+        if tokens.next() == Some(REJECT.as_bytes()) {
+            return Ok(TestEval::Score(NotNan::zero()));
+        }
+    }
+    let Some(output) = run_eval(last.clone(), engine, test_id, args, &output, hasher)? else {
+        return Ok(TestEval::EvaluatorError);
+    };
+    // nothing printed, eg the evaluator crashed before writing the score
+    if output.iter().all(u8::is_ascii_whitespace) {
+        return Ok(TestEval::EvaluatorError);
+    }
+    Ok(match std::str::from_utf8(&output).map(Verdict::from_str) {
+        Ok(Ok(verdict)) => TestEval::Score(verdict.score),
+        _ => TestEval::EvaluatorError,
+    })
+}
+
+/// how the built-in diff scorer compares the output of a submission with the expected one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffMode {
+    /// byte by byte
+    Exact,
+    /// ignoring whitespace at the end of lines, and empty lines at the end
+    TrailingWhitespace,
+    /// as sequences of whitespace separated tokens
+    Tokens,
+}
+impl DiffMode {
+    pub fn matches(self, output: &[u8], expected: &[u8]) -> bool {
+        match self {
+            Self::Exact => output == expected,
+            Self::TrailingWhitespace => {
+                let lines = |x: &[u8]| {
+                    x.trim_ascii_end()
+                        .split(|c| *c == b'\n')
+                        .map(<[u8]>::trim_ascii_end)
+                        .collect::<Vec<_>>()
+                };
+                lines(output) == lines(expected)
+            }
+            Self::Tokens => {
+                let tokens = |x: &[u8]| {
+                    x.split(u8::is_ascii_whitespace)
+                        .filter(|x| !x.is_empty())
+                        .collect::<Vec<_>>()
+                };
+                tokens(output) == tokens(expected)
+            }
+        }
+    }
+}
+
+/// how the output of a submission is scored
+#[derive(Clone)]
+pub enum Scorer {
+    /// eval phases, in the order they are run (see run_eval_phases)
+    Eval(Vec<Module>),
+    /// a score of 1 if the output matches the expected output of the test, by test_id, 0 otherwise
+    Diff(DiffMode, Vec<Vec<u8>>),
+}
+impl From<Vec<Module>> for Scorer {
+    fn from(phases: Vec<Module>) -> Self {
+        Self::Eval(phases)
+    }
+}
+/// like Scorer, as given to evaluate_submission
+#[derive(Clone, Copy, Debug)]
+pub enum Scoring<'a> {
+    /// the wasm of the eval phases, in the order they are run
+    Eval(&'a [&'a [u8]]),
+    /// the expected outputs of the tests, by test_id
+    Diff(DiffMode, &'a [Vec<u8>]),
 }
 
+/// the expected output is hashed as is, like a static input
+fn run_scorer(
+    scorer: &Scorer,
+    engine: Engine,
+    test_id: u32,
+    args: &[String],
+    output: &[u8],
+    hasher: &mut Hasher,
+) -> anyhow::Result<TestEval> {
+    match scorer {
+        Scorer::Eval(phases) => run_eval_phases(phases, engine, test_id, args, output, hasher),
+        Scorer::Diff(mode, expected) => {
+            let expected = expected
+                .get(test_id as usize)
+                .ok_or_else(|| anyhow::anyhow!("no expected output for test {}", test_id))?;
+            hasher.update(expected);
+            let score = if mode.matches(output, expected) {
+                NotNan::one()
+            } else {
+                NotNan::zero()
+            };
+            Ok(TestEval::Score(score))
+        }
+    }
+}
+
+/// evaluates a submission on a single test, args are its extra args (see push_test_args),
+/// with a fresh hasher it gives the hash of the test in evaluate_submission
 #[allow(clippy::too_many_arguments)]
-fn evaluate_on_test(
-    gen_wasm: Module,
-    sub_wasm: Module,
-    eval_wasm: Module,
-    contest_engine: Engine,
-    submission_engine: Engine,
+pub fn evaluate_one(
+    inputs: &TestInputs,
+    scorer: &Scorer,
+    sub_wasm: &Submission,
+    engines: &Engines,
     limits: Limits,
     test_id: u32,
+    args: &[String],
     hasher: &mut Hasher,
 ) -> anyhow::Result<TestEval> {
-    let tc = run_gen(gen_wasm, contest_engine.clone(), test_id, hasher)?;
-    let sub_res = run_sub(sub_wasm, submission_engine, tc, limits, hasher)?;
+    let contest_engine = engines.contest.clone();
+    let tc = test_input(inputs, contest_engine.clone(), test_id, args, hasher)?;
+    let sub_res = run_sub(
+        sub_wasm,
+        engines.submission.clone(),
+        test_id,
+        &tc,
+        limits,
+        hasher,
+    )?;
     Ok(match sub_res {
-        SubRes::OK(out) => {
-            let score = NotNan::<f64>::from_str(
-                run_eval(eval_wasm, contest_engine, test_id, out, hasher)?.trim(),
-            )?;
-            TestEval::Score(score)
-        }
+        SubRes::OK(out) => run_scorer(scorer, contest_engine, test_id, args, &out, hasher)?,
         SubRes::TLE => TestEval::TLE,
         SubRes::MLE => TestEval::MLE,
         SubRes::RTE => TestEval::RTE,
-        SubRes::MFO => TestEval::Score(NotNan::zero()),
+        SubRes::MFO => TestEval::MFO,
     })
 }
 
+/// stops an evaluation, checked between tests, so the test being run is finished first
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+    /// cancels the token when the guard is dropped,
+    /// eg to stop an evaluation run on another thread when the task waiting for it is aborted
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+}
+pub struct CancelOnDrop(CancelToken);
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+/// the error of an evaluation that was cancelled,
+/// completed are the tests that were evaluated before it stopped
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cancelled {
+    pub completed: Vec<(TestEval, blake3::Hash)>,
+}
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "evaluation cancelled after {} tests",
+            self.completed.len()
+        )
+    }
+}
+impl std::error::Error for Cancelled {}
+
 #[allow(clippy::too_many_arguments)]
 fn evaluate_on_testset(
-    gen_wasm: Module,
-    sub_wasm: Module,
-    eval_wasm: Module,
-    contest_engine: Engine,
-    submission_engine: Engine,
+    inputs: TestInputs,
+    sub_wasm: Submission,
+    scorer: Scorer,
+    engines: &Engines,
     limits: Limits,
     testset_length: u32,
-    hasher: &mut Hasher,
-) -> anyhow::Result<Vec<TestEval>> {
-    (0..testset_length)
-        .map(|x| {
-            evaluate_on_test(
-                gen_wasm.clone(),
-                sub_wasm.clone(),
-                eval_wasm.clone(),
-                contest_engine.clone(),
-                submission_engine.clone(),
-                limits,
-                x,
-                hasher,
-            )
-        })
-        .collect()
+    test_args: &[Vec<String>],
+    cancel: &CancelToken,
+) -> anyhow::Result<Vec<(TestEval, blake3::Hash)>> {
+    let mut completed = Vec::with_capacity(testset_length as usize);
+    for x in 0..testset_length {
+        if cancel.is_cancelled() {
+            return Err(Cancelled { completed }.into());
+        }
+        let mut hasher = Hasher::new();
+        let ev = evaluate_one(
+            &inputs,
+            &scorer,
+            &sub_wasm,
+            engines,
+            limits,
+            x,
+            test_args.get(x as usize).map_or(&[][..], Vec::as_slice),
+            &mut hasher,
+        )?;
+        completed.push((ev, hasher.finalize()));
+    }
+    Ok(completed)
+}
+
+/// the hash of a whole evaluation, from the hashes of its tests along with their test_id,
+/// they are combined sorted by test_id, so tests can be run in any order (or on different threads)
+pub fn aggregate_hash(test_hashes: impl IntoIterator<Item = (u32, blake3::Hash)>) -> blake3::Hash {
+    let mut test_hashes: Vec<_> = test_hashes.into_iter().collect();
+    test_hashes.sort_unstable_by_key(|&(test_id, _)| test_id);
+    let mut hasher = Hasher::new();
+    for (test_id, hash) in test_hashes {
+        hasher.update(&test_id.to_le_bytes());
+        hasher.update(hash.as_bytes());
+    }
+    hasher.finalize()
 }
 
+/// the result of evaluate_submission
+#[derive(Clone, Debug, PartialEq)]
+pub struct Evaluation {
+    pub score: NotNan<f64>,
+    /// aggregate_hash of test_hashes, with their test_id
+    pub hash: blake3::Hash,
+    /// the hash of the execution of each test, indexed by test_id,
+    /// two evaluators that disagree can compare them to find out which tests they disagree on
+    pub test_hashes: Vec<blake3::Hash>,
+    /// the verdict of each test, indexed by test_id,
+    /// an EvaluatorError in it means the problem is broken, not the submission
+    pub verdicts: Vec<TestEval>,
+}
+
+/// static tests must have an input, and diff scoring an expected output,
+/// for each of the testset_length tests,
+/// test_args the extra args of each test by test_id, tests past its end have none,
+/// if cancel is cancelled the evaluation stops before the next test, with a Cancelled error
 pub fn evaluate_submission(
-    gen: &[u8],
-    eval: &[u8],
+    tests: Tests<'_>,
+    scoring: Scoring<'_>,
     sub: &[u8],
-    max_memory: u32,
-    max_cpu: u64,
+    limits: Limits,
     testset_length: u32,
-) -> anyhow::Result<(NotNan<f64>, blake3::Hash)> {
-    let submission_engine = get_submission_engine()?;
-    let contest_engine = get_contest_engine()?;
-    let gen_module = Module::from_binary(&contest_engine, gen)?;
-    let eval_module = Module::from_binary(&contest_engine, eval)?;
-    let sub_module = Module::from_binary(&submission_engine, sub)?;
-    let limits = Limits {
-        memory: max_memory,
-        cpu: max_cpu,
+    test_args: &[Vec<String>],
+    cancel: &CancelToken,
+) -> anyhow::Result<Evaluation> {
+    let engines = engines()?;
+    let inputs = match tests {
+        Tests::Generated(gen) => TestInputs::Generator(engines.contest_module(gen)?),
+        Tests::Static(inputs) => {
+            anyhow::ensure!(
+                inputs.len() >= testset_length as usize,
+                "{} static inputs for {} tests",
+                inputs.len(),
+                testset_length
+            );
+            TestInputs::Static(inputs.to_vec())
+        }
     };
-    let mut hasher = Hasher::new();
-    let ev = evaluate_on_testset(
-        gen_module,
+    let scorer = match scoring {
+        Scoring::Eval(eval) => Scorer::Eval(
+            eval.iter()
+                .map(|eval| engines.contest_module(eval))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        ),
+        Scoring::Diff(mode, expected) => {
+            anyhow::ensure!(
+                expected.len() >= testset_length as usize,
+                "{} expected outputs for {} tests",
+                expected.len(),
+                testset_length
+            );
+            Scorer::Diff(mode, expected.to_vec())
+        }
+    };
+    let sub_module = engines.submission(sub)?;
+    let (verdicts, test_hashes): (Vec<_>, Vec<_>) = evaluate_on_testset(
+        inputs,
         sub_module,
-        eval_module,
-        contest_engine,
-        submission_engine,
+        scorer,
+        &engines,
         limits,
         testset_length,
-        &mut hasher,
-    )?;
-    Ok((
-        ev.into_iter()
-            .map(|x| match x {
-                TestEval::Score(s) => s,
-                _ => NotNan::zero(),
-            })
-            .max()
-            .ok_or(anyhow::anyhow!("max err"))?,
-        hasher.finalize(),
-    ))
+        test_args,
+        cancel,
+    )?
+    .into_iter()
+    .unzip();
+    let score = verdicts
+        .iter()
+        .map(TestEval::score)
+        .max()
+        .ok_or(anyhow::anyhow!("max err"))?;
+    Ok(Evaluation {
+        score,
+        hash: aggregate_hash((0..).zip(test_hashes.iter().copied())),
+        test_hashes,
+        verdicts,
+    })
+}
+
+/// records how long a phase ran, and the fuel it used if it is metered,
+/// on the span of the phase, nothing is measured if the span is disabled
+struct PhaseTimer(Option<Instant>);
+impl PhaseTimer {
+    fn start() -> Self {
+        Self((!Span::current().is_disabled()).then(Instant::now))
+    }
+    fn record(self, fuel_used: Option<u64>) {
+        let Some(start) = self.0 else {
+            return;
+        };
+        let span = Span::current();
+        span.record("duration", field::debug(start.elapsed()));
+        if let Some(fuel_used) = fuel_used {
+            span.record("fuel", fuel_used);
+        }
+    }
 }
 
 fn run_wasi(
@@ -241,6 +880,7 @@ fn run_wasi(
     }
 
     // make an instance and run the wasi program
+    let timer = PhaseTimer::start();
     let instance = linker.instantiate(&mut store, module)?; //TODO: check the start function here consumes fuel/is not exploitable
     let result = instance
         .get_typed_func::<(), ()>(&mut store, "_start")?
@@ -249,6 +889,7 @@ fn run_wasi(
     // get the execution data
     let mut _memory_used = 0;
     let fuel_used = store.fuel_consumed().unwrap_or_default();
+    timer.record(fuel.map(|_| fuel_used));
     //TODO: is the memory always called memory?
     if let Some(memory) = instance.get_memory(&mut store, "memory") {
         hasher.update(memory.data(&store));
@@ -261,61 +902,235 @@ fn run_wasi(
     Ok(result)
 }
 
-fn get_submission_engine() -> anyhow::Result<Engine> {
+pub fn get_submission_engine() -> anyhow::Result<Engine> {
+    new_engine(true)
+}
+pub fn get_contest_engine() -> anyhow::Result<Engine> {
+    new_engine(false)
+}
+/// the engines are built and checked once, later calls get the same ones
+pub fn engines() -> anyhow::Result<Engines> {
+    static ENGINES: OnceLock<Result<Engines, String>> = OnceLock::new();
+    ENGINES
+        .get_or_init(|| Engines::new().map_err(|e| e.to_string()))
+        .clone()
+        .map_err(anyhow::Error::msg)
+}
+fn new_engine(fuel: bool) -> anyhow::Result<Engine> {
     let mut config = Config::new();
     config.cranelift_opt_level(OptLevel::Speed);
     unsafe {
         config.cranelift_flag_enable("enable_nan_canonicalization");
     }
-    config.consume_fuel(true);
-    Engine::new(&config)
+    config.consume_fuel(fuel);
+    config.wasm_component_model(true);
+    let engine = Engine::new(&config)
+        .map_err(|e| anyhow::anyhow!("could not configure the wasm engine: {}", e))?;
+    check_engine(&engine, fuel)?;
+    Ok(engine)
 }
-fn get_contest_engine() -> anyhow::Result<Engine> {
-    let mut config = Config::new();
-    config.cranelift_opt_level(OptLevel::Speed);
-    unsafe {
-        config.cranelift_flag_enable("enable_nan_canonicalization");
+
+// divides its arguments and returns the bits of the result
+const NAN_PROBE: &str = r#"(module
+  (func (export "div") (param f32 f32) (result i32)
+    local.get 0
+    local.get 1
+    f32.div
+    i32.reinterpret_f32))"#;
+const CANONICAL_NAN: u32 = 0x7fc00000;
+
+/// checks that the engine behaves as configured,
+/// otherwise evaluations would silently give different hashes on different machines
+fn check_engine(engine: &Engine, fuel: bool) -> anyhow::Result<()> {
+    let module = Module::new(engine, NAN_PROBE)?;
+    let mut store = Store::new(engine, ());
+    if fuel {
+        store
+            .add_fuel(1000)
+            .map_err(|e| anyhow::anyhow!("the engine does not consume fuel: {}", e))?;
+    }
+    let instance = Instance::new(&mut store, &module, &[])?;
+    let bits = instance
+        .get_typed_func::<(f32, f32), i32>(&mut store, "div")?
+        .call(&mut store, (0.0, 0.0))? as u32;
+    anyhow::ensure!(
+        bits == CANONICAL_NAN,
+        "the engine does not canonicalize nans, 0/0 gave {:#x}",
+        bits
+    );
+    if fuel {
+        anyhow::ensure!(
+            store.fuel_consumed().is_some_and(|f| f > 0),
+            "the engine does not consume fuel"
+        );
     }
-    Engine::new(&config)
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use num_traits::identities::One;
 
-    fn eval_sub(sub_file: &str) -> (anyhow::Result<Vec<TestEval>>, blake3::Hash) {
-        let submission_engine = get_submission_engine().unwrap();
-        let contest_engine = get_contest_engine().unwrap();
-        let gen_module = Module::from_file(
-            &contest_engine,
+    const LIMITS: Limits = Limits::new(2000000, 10000000);
+    fn modules(engines: &Engines, sub_file: &str) -> (TestInputs, Module, Submission) {
+        modules_with_gen(
+            engines,
             "./testwasm/target/wasm32-wasi/debug/gen.wasm",
+            sub_file,
         )
-        .unwrap();
+    }
+    fn modules_with_gen(
+        engines: &Engines,
+        gen_file: &str,
+        sub_file: &str,
+    ) -> (TestInputs, Module, Submission) {
+        let gen_module = Module::from_file(&engines.contest, gen_file).unwrap();
         let eval_module = Module::from_file(
-            &contest_engine,
+            &engines.contest,
             "./testwasm/target/wasm32-wasi/debug/eval.wasm",
         )
         .unwrap();
-        let sub_module = Module::from_file(&submission_engine, sub_file).unwrap();
-        let limits = Limits {
-            memory: 2000000,
-            cpu: 10000000,
-        };
-        let mut hasher = Hasher::new();
-        let ev = evaluate_on_testset(
+        let sub = engines
+            .submission(&std::fs::read(sub_file).unwrap())
+            .unwrap();
+        (gen_module.into(), eval_module, sub)
+    }
+    fn eval_sub(sub_file: &str) -> (anyhow::Result<Vec<TestEval>>, Vec<blake3::Hash>) {
+        let engines = Engines::new().unwrap();
+        let (gen_module, eval_module, sub_module) = modules(&engines, sub_file);
+        match evaluate_on_testset(
             gen_module,
             sub_module,
-            eval_module,
-            contest_engine,
-            submission_engine,
-            limits,
+            vec![eval_module].into(),
+            &engines,
+            LIMITS,
             16,
-            &mut hasher,
-        );
-        (ev, hasher.finalize())
+            &[],
+            &CancelToken::new(),
+        ) {
+            Ok(ev) => {
+                let (ev, hashes) = ev.into_iter().unzip();
+                (Ok(ev), hashes)
+            }
+            Err(e) => (Err(e), vec![]),
+        }
     }
 
+    #[test]
+    fn engines_checked() {
+        assert!(engines().is_ok());
+        assert!(check_engine(&engines().unwrap().submission, true).is_ok());
+        // fuel is not enabled on the contest engine
+        assert!(check_engine(&engines().unwrap().contest, true).is_err());
+        // x86 gives a negative nan for 0/0 unless it is canonicalized
+        #[cfg(target_arch = "x86_64")]
+        assert!(check_engine(&Engine::default(), false).is_err());
+    }
+    #[test]
+    fn component_detection() {
+        let module = std::fs::read("./testwasm/target/wasm32-wasi/debug/sub_ac.wasm").unwrap();
+        assert!(!is_component(&module));
+        assert!(matches!(
+            engines().unwrap().submission(&module).unwrap(),
+            Submission::Module(_)
+        ));
+        assert!(is_component(b"\0asm\x0d\x00\x01\x00"));
+        assert!(!is_component(b"\0asm"));
+    }
+    #[test]
+    fn component_sub() {
+        // sub_ac adapted to a component, see testwasm/README.md
+        let sub_file = "./testwasm/target/wasm32-wasip2/debug/sub_ac.wasm";
+        let engines = Engines::new().unwrap();
+        let (gen_module, eval_module, sub) = modules(&engines, sub_file);
+        assert!(matches!(sub, Submission::Component(_)));
+        let scorer = Scorer::from(vec![eval_module]);
+        let ev = eval_sub(sub_file).0.unwrap();
+        assert_eq!(ev, vec![TestEval::Score(NotNan::one()); 16]);
+        // the presets the client uses run components too
+        for limits in [Limits::NATIVE, Limits::INTERPRETED] {
+            let mut hasher = Hasher::new();
+            let ev = evaluate_one(
+                &gen_module,
+                &scorer,
+                &sub,
+                &engines,
+                limits,
+                0,
+                &[],
+                &mut hasher,
+            )
+            .unwrap();
+            assert_eq!(ev, TestEval::Score(NotNan::one()));
+        }
+    }
+    #[test]
+    fn component_output_overflow() {
+        // sub_mfo adapted to a component, see testwasm/README.md, prints more than the buffer keeps
+        let engines = Engines::new().unwrap();
+        let sub = engines
+            .submission(
+                &std::fs::read("./testwasm/target/wasm32-wasip2/debug/sub_mfo.wasm").unwrap(),
+            )
+            .unwrap();
+        let res = run_sub(
+            &sub,
+            engines.submission.clone(),
+            0,
+            b"",
+            Limits::NATIVE,
+            &mut Hasher::new(),
+        )
+        .unwrap();
+        assert_eq!(res, SubRes::MFO);
+    }
+    #[test]
+    fn limits() {
+        let limits = Limits::new(1 << 20, 1000);
+        assert_eq!(limits.table_elements(), Limits::DEFAULT_TABLE_ELEMENTS);
+        assert_eq!(limits.instances(), 1);
+        // the table is not limited by the memory, or the other way around
+        let limits = limits.with_table_elements(1 << 24).with_tables(2);
+        assert_eq!(limits.memory(), 1 << 20);
+        assert_eq!(limits.table_elements(), 1 << 24);
+        assert_eq!(limits.tables(), 2);
+        assert_eq!(limits.memories(), 1);
+        assert_eq!(
+            Limits::NATIVE.table_elements(),
+            Limits::DEFAULT_TABLE_ELEMENTS
+        );
+        for preset in [Limits::NATIVE, Limits::INTERPRETED] {
+            let component = preset.for_component();
+            assert_eq!(component.memory(), preset.memory());
+            assert_eq!(component.cpu(), preset.cpu());
+            assert_eq!(component.instances(), Limits::COMPONENT_INSTANCES);
+            assert_eq!(component.memories(), Limits::COMPONENT_MEMORIES);
+            assert_eq!(component.tables(), Limits::COMPONENT_TABLES);
+        }
+        // larger counts are kept
+        let limits = Limits::NATIVE.with_instances(100).for_component();
+        assert_eq!(limits.instances(), 100);
+    }
+    #[test]
+    fn test_eval_score() {
+        let half = NotNan::new(0.5).unwrap();
+        assert_eq!(TestEval::Score(half).score(), half);
+        assert_eq!(TestEval::MFO.score(), NotNan::zero());
+        // zero points, but not the same as a wrong answer
+        assert_ne!(TestEval::MFO, TestEval::Score(NotNan::zero()));
+    }
+    #[test]
+    fn verdict_parsing() {
+        let v = Verdict::from_str("0.5 wrong answer on line 3\n").unwrap();
+        assert_eq!(v.score, NotNan::new(0.5).unwrap());
+        assert_eq!(v.message, "wrong answer on line 3");
+        assert_eq!(Verdict::from_str(" 1\n").unwrap().message, "");
+        assert!(Verdict::from_str("").is_err());
+        assert!(Verdict::from_str("ok 1").is_err());
+        assert!(Verdict::from_str("5.0").is_err());
+        assert!(Verdict::from_str("-1").is_err());
+        assert!(Verdict::from_str("inf").is_err());
+    }
     #[test]
     fn ac_sub() {
         let (ans, _hash) = eval_sub("./testwasm/target/wasm32-wasi/debug/sub_ac.wasm");
@@ -351,4 +1166,317 @@ mod tests {
         assert_eq!(ans1, ans2.unwrap());
         assert_eq!(hash1, hash2);
     }
+    #[test]
+    fn evaluate_one_same_hash() {
+        let sub_file = "./testwasm/target/wasm32-wasi/debug/sub_ac.wasm";
+        let engines = Engines::new().unwrap();
+        let (gen_module, eval_module, sub_module) = modules(&engines, sub_file);
+        let scorer = Scorer::from(vec![eval_module]);
+        let hashes = eval_sub(sub_file).1;
+        for test_id in 0..16 {
+            let mut hasher = Hasher::new();
+            let ev = evaluate_one(
+                &gen_module,
+                &scorer,
+                &sub_module,
+                &engines,
+                LIMITS,
+                test_id,
+                &[],
+                &mut hasher,
+            )
+            .unwrap();
+            assert_eq!(ev, TestEval::Score(NotNan::one()));
+            assert_eq!(hasher.finalize(), hashes[test_id as usize]);
+        }
+    }
+    #[test]
+    fn execution_order_independent() {
+        let sub_file = "./testwasm/target/wasm32-wasi/debug/sub_ac.wasm";
+        let engines = Engines::new().unwrap();
+        let (gen_module, eval_module, sub_module) = modules(&engines, sub_file);
+        let scorer = Scorer::from(vec![eval_module]);
+        let run = |test_ids: &[u32]| {
+            aggregate_hash(test_ids.iter().map(|&test_id| {
+                let mut hasher = Hasher::new();
+                evaluate_one(
+                    &gen_module,
+                    &scorer,
+                    &sub_module,
+                    &engines,
+                    LIMITS,
+                    test_id,
+                    &[],
+                    &mut hasher,
+                )
+                .unwrap();
+                (test_id, hasher.finalize())
+            }))
+        };
+        let in_order = run(&[0, 1, 2, 3]);
+        assert_eq!(in_order, run(&[3, 1, 0, 2]));
+        assert_eq!(
+            in_order,
+            aggregate_hash((0..).zip(eval_sub(sub_file).1.into_iter().take(4)))
+        );
+    }
+    #[test]
+    fn aggregate_hash_sorted() {
+        let hashes: Vec<_> = (0..8u32).map(|i| blake3::hash(&i.to_le_bytes())).collect();
+        let in_order = aggregate_hash((0..).zip(hashes.clone()));
+        let reversed = aggregate_hash((0..8).rev().map(|i| (i, hashes[i as usize])));
+        assert_eq!(in_order, reversed);
+        // the same hashes for other tests are a different evaluation
+        assert_ne!(in_order, aggregate_hash((1..).zip(hashes.clone())));
+        assert_ne!(in_order, aggregate_hash((0..8).rev().zip(hashes)));
+    }
+    #[test]
+    fn test_hashes() {
+        let read = |name: &str| {
+            std::fs::read(format!("./testwasm/target/wasm32-wasi/debug/{}.wasm", name)).unwrap()
+        };
+        let (gen, eval) = (read("gen"), read("eval"));
+        let evaluate = |sub: &str| {
+            evaluate_submission(
+                Tests::Generated(&gen),
+                Scoring::Eval(&[&eval]),
+                &read(sub),
+                LIMITS,
+                4,
+                &[],
+                &CancelToken::new(),
+            )
+            .unwrap()
+        };
+        let ac = evaluate("sub_ac");
+        assert_eq!(ac.test_hashes.len(), 4);
+        assert_eq!(ac.verdicts, vec![TestEval::Score(NotNan::one()); 4]);
+        assert_eq!(ac.hash, aggregate_hash((0..).zip(ac.test_hashes.clone())));
+        assert_eq!(ac, evaluate("sub_ac"));
+        // the same tests, run differently
+        let wa = evaluate("sub_wa");
+        assert_ne!(ac.hash, wa.hash);
+    }
+    #[test]
+    fn broken_evaluator() {
+        let engines = Engines::new().unwrap();
+        let (gen_module, _, sub_module) =
+            modules(&engines, "./testwasm/target/wasm32-wasi/debug/sub_ac.wasm");
+        for eval_file in ["eval_silent", "eval_crash"] {
+            let eval_module = Module::from_file(
+                &engines.contest,
+                format!("./testwasm/target/wasm32-wasi/debug/{}.wasm", eval_file),
+            )
+            .unwrap();
+            let ev = evaluate_on_testset(
+                gen_module.clone(),
+                sub_module.clone(),
+                vec![eval_module].into(),
+                &engines,
+                LIMITS,
+                4,
+                &[],
+                &CancelToken::new(),
+            );
+            // the evaluation does not fail, and the submission is not blamed for it
+            let ev: Vec<_> = ev.unwrap().into_iter().map(|(ev, _)| ev).collect();
+            assert_eq!(vec![TestEval::EvaluatorError; 4], ev);
+        }
+        // and the error reaches whoever evaluated the submission
+        let read = |name: &str| {
+            std::fs::read(format!("./testwasm/target/wasm32-wasi/debug/{}.wasm", name)).unwrap()
+        };
+        let ev = evaluate_submission(
+            Tests::Generated(&read("gen")),
+            Scoring::Eval(&[&read("eval_crash")]),
+            &read("sub_ac"),
+            LIMITS,
+            4,
+            &[],
+            &CancelToken::new(),
+        )
+        .unwrap();
+        assert_eq!(ev.verdicts, vec![TestEval::EvaluatorError; 4]);
+    }
+    #[test]
+    fn extra_args() {
+        let engines = Engines::new().unwrap();
+        let gen_module = Module::from_file(
+            &engines.contest,
+            "./testwasm/target/wasm32-wasi/debug/gen_args.wasm",
+        )
+        .unwrap();
+        let gen = |test_id, args: &[String]| {
+            let mut hasher = Hasher::new();
+            run_gen(
+                gen_module.clone(),
+                engines.contest.clone(),
+                test_id,
+                args,
+                &mut hasher,
+            )
+            .unwrap()
+        };
+        assert_eq!(gen(3, &[]), b"3\n");
+        let args = ["subtask".to_string(), "2".to_string()];
+        assert_eq!(gen(3, &args), b"3 subtask 2\n");
+    }
+    #[test]
+    fn cancelled() {
+        let engines = Engines::new().unwrap();
+        let (gen_module, eval_module, sub_module) =
+            modules(&engines, "./testwasm/target/wasm32-wasi/debug/sub_ac.wasm");
+        let cancel = CancelToken::new();
+        drop(cancel.cancel_on_drop());
+        assert!(cancel.is_cancelled());
+        let err = evaluate_on_testset(
+            gen_module,
+            sub_module,
+            vec![eval_module].into(),
+            &engines,
+            LIMITS,
+            4,
+            &[],
+            &cancel,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Cancelled>(),
+            Some(&Cancelled { completed: vec![] })
+        );
+    }
+    #[test]
+    fn static_inputs() {
+        let engines = Engines::new().unwrap();
+        let (gen_module, eval_module, sub_module) =
+            modules(&engines, "./testwasm/target/wasm32-wasi/debug/sub_ac.wasm");
+        let TestInputs::Generator(gen_module) = gen_module else {
+            unreachable!()
+        };
+        // the same inputs as the generator gives
+        let inputs: Vec<_> = (0..4)
+            .map(|test_id| {
+                run_gen(
+                    gen_module.clone(),
+                    engines.contest.clone(),
+                    test_id,
+                    &[],
+                    &mut Hasher::new(),
+                )
+                .unwrap()
+            })
+            .collect();
+        let ev = evaluate_on_testset(
+            TestInputs::Static(inputs.clone()),
+            sub_module,
+            vec![eval_module].into(),
+            &engines,
+            LIMITS,
+            4,
+            &[],
+            &CancelToken::new(),
+        );
+        let ev: Vec<_> = ev.unwrap().into_iter().map(|(ev, _)| ev).collect();
+        assert_eq!(vec![TestEval::Score(NotNan::one()); 4], ev);
+        let read = |name: &str| {
+            std::fs::read(format!("./testwasm/target/wasm32-wasi/debug/{}.wasm", name)).unwrap()
+        };
+        let (eval, sub) = (read("eval"), read("sub_ac"));
+        let evaluate = |testset_length| {
+            evaluate_submission(
+                Tests::Static(&inputs),
+                Scoring::Eval(&[&eval]),
+                &sub,
+                LIMITS,
+                testset_length,
+                &[],
+                &CancelToken::new(),
+            )
+        };
+        assert_eq!(evaluate(4).unwrap().score, NotNan::one());
+        assert!(evaluate(5).is_err());
+    }
+    #[test]
+    fn diff_modes() {
+        let expected = b"1 2\n3\n";
+        let cases: [(&[u8], [bool; 3]); 5] = [
+            (b"1 2\n3\n", [true, true, true]),
+            (b"1 2  \n3\n\n", [false, true, true]),
+            (b"1 2\r\n3", [false, true, true]),
+            (b"1\n2 3\n", [false, false, true]),
+            (b"1 2\n4\n", [false, false, false]),
+        ];
+        for (output, matches) in cases {
+            let modes = [
+                DiffMode::Exact,
+                DiffMode::TrailingWhitespace,
+                DiffMode::Tokens,
+            ];
+            assert_eq!(modes.map(|mode| mode.matches(output, expected)), matches);
+        }
+    }
+    #[test]
+    fn diff_scorer() {
+        let engines = Engines::new().unwrap();
+        let (gen_module, _, _) =
+            modules(&engines, "./testwasm/target/wasm32-wasi/debug/sub_ac.wasm");
+        let TestInputs::Generator(gen_module) = gen_module else {
+            unreachable!()
+        };
+        // what sub_ac prints
+        let expected: Vec<_> = (0..4)
+            .map(|test_id| {
+                let input = run_gen(
+                    gen_module.clone(),
+                    engines.contest.clone(),
+                    test_id,
+                    &[],
+                    &mut Hasher::new(),
+                )
+                .unwrap();
+                let n: u64 = std::str::from_utf8(&input).unwrap().trim().parse().unwrap();
+                format!("{}\n", n ^ 42).into_bytes()
+            })
+            .collect();
+        for (sub_file, score) in [("sub_ac", NotNan::one()), ("sub_wa", NotNan::zero())] {
+            let (gen_module, _, sub_module) = modules(
+                &engines,
+                &format!("./testwasm/target/wasm32-wasi/debug/{}.wasm", sub_file),
+            );
+            let ev = evaluate_on_testset(
+                gen_module,
+                sub_module,
+                Scorer::Diff(DiffMode::Exact, expected.clone()),
+                &engines,
+                LIMITS,
+                4,
+                &[],
+                &CancelToken::new(),
+            );
+            let ev: Vec<_> = ev.unwrap().into_iter().map(|(ev, _)| ev).collect();
+            assert_eq!(vec![TestEval::Score(score); 4], ev);
+        }
+    }
+    #[test]
+    fn binary_gen() {
+        let engines = Engines::new().unwrap();
+        let (gen_module, eval_module, sub_module) = modules_with_gen(
+            &engines,
+            "./testwasm/target/wasm32-wasi/debug/gen_bin.wasm",
+            "./testwasm/target/wasm32-wasi/debug/sub_ac.wasm",
+        );
+        let ev = evaluate_on_testset(
+            gen_module,
+            sub_module,
+            vec![eval_module].into(),
+            &engines,
+            LIMITS,
+            4,
+            &[],
+            &CancelToken::new(),
+        );
+        // sub_ac can't parse the input, but the evaluation itself does not fail
+        let ev: Vec<_> = ev.unwrap().into_iter().map(|(ev, _)| ev).collect();
+        assert_eq!(vec![TestEval::RTE; 4], ev);
+    }
 }
@@ -23,7 +23,8 @@ impl Scanner {
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    assert!(args.len() == 1);
+    // the test_id, then the extra args of the test, if any
+    assert!(!args.is_empty());
     let test_id = args[0].parse::<u64>().unwrap();
     let mut seed = [42u8; 32];
     for i in seed.iter_mut().zip(test_id.to_be_bytes().iter().cycle()) {
@@ -0,0 +1,7 @@
+use std::io::Read;
+
+// reads the output of the submission, but never prints a score
+fn main() {
+    let mut input = String::new();
+    let _ = std::io::stdin().read_to_string(&mut input);
+}
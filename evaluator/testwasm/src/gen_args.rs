@@ -0,0 +1,7 @@
+use std::env;
+
+// the test depends on all of the args, eg the test_id and the subtask
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    println!("{}", args.join(" "));
+}
@@ -0,0 +1,8 @@
+use std::io::Write;
+
+// writes bytes that are not valid utf-8
+fn main() {
+    std::io::stdout()
+        .write_all(&[0xff, 0xfe, 0x00, 0x80])
+        .unwrap();
+}
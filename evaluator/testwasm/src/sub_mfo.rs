@@ -0,0 +1,10 @@
+use std::io::Write;
+
+fn main() {
+    // one chunk more than the evaluator keeps of the output of a component
+    let chunk = [b'0'; 1 << 16];
+    let mut stdout = std::io::stdout().lock();
+    for _ in 0..(1 << 10) + 1 {
+        stdout.write_all(&chunk).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
 use crate::message::*;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
 
 enum EvaluationState {
     None,
@@ -9,24 +11,43 @@ enum EvaluationState {
 
 struct SingleEvaluationInfo {
     evaluator: PubSigKey,
+    weight: u32,
+    assigned: SystemTime,
     state: EvaluationState,
+    verdicts: Vec<TestVerdict>,
+    test_hashes: Vec<TestHash>,
 }
 impl SingleEvaluationInfo {
-    fn new(psk: PubSigKey) -> Self {
+    fn new(psk: PubSigKey, weight: u32, assigned: SystemTime) -> Self {
         Self {
             evaluator: psk,
+            weight,
+            assigned,
             state: EvaluationState::None,
+            verdicts: vec![],
+            test_hashes: vec![],
+        }
+    }
+    fn tick(&mut self, now: SystemTime, timeout: Duration) {
+        if matches!(
+            self.state,
+            EvaluationState::None | EvaluationState::Provisional(_, _)
+        ) && now.duration_since(self.assigned).unwrap_or_default() > timeout
+        {
+            self.state = EvaluationState::Failed;
         }
     }
     fn add_evaluation(&mut self, e: QEvaluation) {
         if matches!(self.state, EvaluationState::None) {
             self.state = EvaluationState::Provisional(e.score, e.detailhs_hash);
+            self.verdicts = e.verdicts;
         }
     }
     fn add_evaluation_proof(&mut self, ep: QEvaluationProof) {
         if let EvaluationState::Provisional(score, hh) = self.state {
             if ep.hash() == hh {
                 self.state = EvaluationState::Final(score, ep.detailhs);
+                self.test_hashes = ep.test_hashes;
             } else {
                 self.state = EvaluationState::Failed;
             }
@@ -34,6 +55,7 @@ impl SingleEvaluationInfo {
     }
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum EvaluationResultScore {
     None,
     Provisional(SubScore),
@@ -41,18 +63,44 @@ pub enum EvaluationResultScore {
     Failed,
 }
 
-pub struct EvaluationInfo(Vec<SingleEvaluationInfo>);
+pub struct EvaluationInfo {
+    evaluators: Vec<SingleEvaluationInfo>,
+    // once a proof is revealed, anyone could copy it, so later evaluations are flagged
+    revealed: bool,
+}
 impl EvaluationInfo {
-    pub fn new(evaluators: Vec<PubSigKey>) -> Self {
-        Self(
-            evaluators
+    /// assigned is the timestamp of the queue message that assigned the evaluators
+    pub fn new(evaluators: Vec<PubSigKey>, assigned: SystemTime) -> Self {
+        Self::with_weights(evaluators, &HashMap::new(), assigned)
+    }
+    /// evaluators not in weights have weight 1
+    pub fn with_weights(
+        evaluators: Vec<PubSigKey>,
+        weights: &HashMap<PubSigKey, u32>,
+        assigned: SystemTime,
+    ) -> Self {
+        Self {
+            evaluators: evaluators
                 .into_iter()
-                .map(SingleEvaluationInfo::new)
+                .map(|psk| {
+                    SingleEvaluationInfo::new(psk, *weights.get(&psk).unwrap_or(&1), assigned)
+                })
                 .collect(),
-        )
+            revealed: false,
+        }
+    }
+    /// marks as failed the evaluators that did not produce a proof within timeout of being assigned.
+    /// now has to be the timestamp of a queue message, before adding it,
+    /// so that everyone following the queue reaches the same states, even when replaying it.
+    /// a failed evaluator still counts towards the total weight,
+    /// so the submission can only get a final score if the ones left make a majority of all of them
+    pub fn tick(&mut self, now: SystemTime, timeout: Duration) {
+        for x in self.evaluators.iter_mut() {
+            x.tick(now, timeout);
+        }
     }
     fn provisional_score(&self) -> Option<SubScore> {
-        self.0
+        self.evaluators
             .iter()
             .filter_map(|x| match x.state {
                 EvaluationState::Provisional(s, _h) => Some(s),
@@ -61,34 +109,72 @@ impl EvaluationInfo {
             })
             .next()
     }
+    /// the final verdict whose evaluators weigh more than half of the total
+    fn majority(&self) -> Option<(SubScore, DetailHash)> {
+        let total: u64 = self.evaluators.iter().map(|x| x.weight as u64).sum();
+        let mut verdicts: Vec<((SubScore, DetailHash), u64)> = vec![];
+        for x in self.evaluators.iter() {
+            if let EvaluationState::Final(s, h) = x.state {
+                match verdicts.iter_mut().find(|(v, _w)| *v == (s, h)) {
+                    Some((_v, w)) => *w += x.weight as u64,
+                    None => verdicts.push(((s, h), x.weight as u64)),
+                }
+            }
+        }
+        verdicts
+            .into_iter()
+            .find(|(_v, w)| w * 2 > total)
+            .map(|(v, _w)| v)
+    }
     fn final_score(&self) -> Option<SubScore> {
-        let mut maj = None;
-        let mut cnt = 0;
-        let v: Vec<Option<(SubScore, DetailHash)>> = self
-            .0
+        self.majority().map(|(s, _h)| s)
+    }
+    fn majority_evaluator(&self) -> Option<&SingleEvaluationInfo> {
+        let majority = self.majority()?;
+        self.evaluators
             .iter()
-            .map(|x| match x.state {
-                EvaluationState::Final(s, h) => Some((s, h)),
-                _ => None,
+            .find(|x| matches!(x.state, EvaluationState::Final(s, h) if (s, h) == majority))
+    }
+    /// the verdicts of the tests, as given by an evaluator in the majority
+    pub fn verdicts(&self) -> Option<&[TestVerdict]> {
+        self.majority_evaluator().map(|x| x.verdicts.as_slice())
+    }
+    /// the tests, by test_id, on which an evaluator that revealed its proof
+    /// ran differently from the majority, only the first MAX_TEST_HASHES can be told apart
+    pub fn disputed_tests(&self) -> Vec<u32> {
+        let Some(majority) = self.majority_evaluator() else {
+            return vec![];
+        };
+        let mut disputed: Vec<u32> = self
+            .evaluators
+            .iter()
+            .filter(|x| matches!(x.state, EvaluationState::Final(_, _)))
+            .flat_map(|x| {
+                let n = x.test_hashes.len().max(majority.test_hashes.len());
+                (0..n as u32).filter(|&i| {
+                    x.test_hashes.get(i as usize) != majority.test_hashes.get(i as usize)
+                })
             })
             .collect();
-        for s in v.iter() {
-            if *s == maj {
-                cnt += 1;
-            } else if cnt == 0 {
-                maj = *s;
-            } else {
-                cnt -= 1;
-            }
-        }
-        if v.iter().filter(|x| **x == maj).count() * 2 > self.0.len() {
-            maj.map(|x| x.0)
-        } else {
-            None
-        }
+        disputed.sort_unstable();
+        disputed.dedup();
+        disputed
+    }
+    /// evaluators that failed or whose final verdict differs from the majority one
+    pub fn dissenting_evaluators(&self) -> Vec<PubSigKey> {
+        let majority = self.majority();
+        self.evaluators
+            .iter()
+            .filter(|x| match x.state {
+                EvaluationState::Failed => true,
+                EvaluationState::Final(s, h) => majority.is_some_and(|m| m != (s, h)),
+                _ => false,
+            })
+            .map(|x| x.evaluator)
+            .collect()
     }
     pub fn is_done(&self) -> bool {
-        self.0.iter().all(|x| {
+        self.evaluators.iter().all(|x| {
             matches!(
                 x.state,
                 EvaluationState::Final(_, _) | EvaluationState::Failed
@@ -110,22 +196,168 @@ impl EvaluationInfo {
             }
         }
     }
-    pub fn add_evaluation(&mut self, e: QEvaluation) {
+    /// every evaluator committed an evaluation or failed, so the proofs can be revealed
+    pub fn all_committed(&self) -> bool {
+        self.evaluators
+            .iter()
+            .all(|x| !matches!(x.state, EvaluationState::None))
+    }
+    /// evaluations must be added in queue order,
+    /// one committed after a proof was revealed may be a copy,
+    /// returns false in that case, so that it can be flagged
+    pub fn add_evaluation(&mut self, e: QEvaluation) -> bool {
+        let revealed = self.revealed;
         if let Some(x) = self
-            .0
+            .evaluators
             .iter_mut()
             .find(|x| x.evaluator == e.evaluation_id.evaluator)
         {
+            let copyable = revealed && matches!(x.state, EvaluationState::None);
             x.add_evaluation(e);
+            return !copyable;
         }
+        true
     }
-    pub fn add_evaluation_proof(&mut self, ep: QEvaluationProof) {
+    /// proofs must only be revealed once all the evaluators committed,
+    /// the evaluator of a proof revealed earlier fails, the others are not affected,
+    /// returns false in that case
+    pub fn add_evaluation_proof(&mut self, ep: QEvaluationProof) -> bool {
+        let all_committed = self.all_committed();
         if let Some(x) = self
-            .0
+            .evaluators
             .iter_mut()
             .find(|x| x.evaluator == ep.evaluation_id.evaluator)
         {
+            // even a wrong proof reveals something
+            if matches!(x.state, EvaluationState::Provisional(_, _)) {
+                self.revealed = true;
+                if !all_committed {
+                    x.state = EvaluationState::Failed;
+                    return false;
+                }
+            }
             x.add_evaluation_proof(ep);
         }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn evaluators() -> Vec<PubSigKey> {
+        (1..=3)
+            .map(|i| (&SecSigKey::from_bytes(&[i; 32])).into())
+            .collect()
+    }
+    fn score() -> SubScore {
+        SubScore::try_from(0.5).unwrap()
+    }
+    /// the proof of an evaluator of the same submission, evaluators that ran it the same way
+    /// have the same details, with the same first test and a second one that depends on them
+    fn proof(evaluator: PubSigKey, details: &[u8]) -> QEvaluationProof {
+        QEvaluationProof {
+            evaluation_id: EvaluationId {
+                submission_id: SubmissionId {
+                    submitter: PubSigKey::dummy(),
+                    problem_id: 0,
+                    file_id: Mac(blake3::hash(b"source")),
+                },
+                evaluator,
+            },
+            detailhs: Mac(blake3::hash(details)),
+            test_hashes: vec![
+                [0; 8],
+                blake3::hash(details).as_bytes()[..8].try_into().unwrap(),
+            ],
+        }
+    }
+    #[test]
+    fn provisional_before_proofs() {
+        let evaluators = evaluators();
+        let score = score();
+        let mut ei = EvaluationInfo::new(evaluators.clone(), SystemTime::UNIX_EPOCH);
+        assert_eq!(ei.score(), EvaluationResultScore::None);
+        let proofs: Vec<QEvaluationProof> = evaluators
+            .iter()
+            .map(|&evaluator| proof(evaluator, b"details"))
+            .collect();
+        let verdicts = vec![TestVerdict::Scored, TestVerdict::MFO];
+        ei.add_evaluation(QEvaluation::new(proofs[0].clone(), score, verdicts.clone()));
+        assert_eq!(ei.score(), EvaluationResultScore::Provisional(score));
+        for evp in proofs.iter() {
+            ei.add_evaluation(QEvaluation::new(evp.clone(), score, verdicts.clone()));
+        }
+        ei.add_evaluation_proof(proofs[0].clone());
+        assert_eq!(ei.score(), EvaluationResultScore::Provisional(score));
+        assert_eq!(ei.verdicts(), None);
+        ei.add_evaluation_proof(proofs[1].clone());
+        assert_eq!(ei.score(), EvaluationResultScore::Final(score));
+        assert_eq!(ei.verdicts(), Some(&verdicts[..]));
+    }
+    #[test]
+    fn commit_after_reveal() {
+        let evaluators = evaluators();
+        let score = score();
+        let mut ei = EvaluationInfo::new(evaluators.clone(), SystemTime::UNIX_EPOCH);
+        let proofs: Vec<QEvaluationProof> = evaluators
+            .iter()
+            .map(|&evaluator| proof(evaluator, b"details"))
+            .collect();
+        assert!(ei.add_evaluation(QEvaluation::new(proofs[0].clone(), score, vec![])));
+        assert!(ei.add_evaluation(QEvaluation::new(proofs[1].clone(), score, vec![])));
+        // a proof revealed before being committed reveals nothing
+        assert!(ei.add_evaluation_proof(proofs[2].clone()));
+        assert!(ei.dissenting_evaluators().is_empty());
+        // the third evaluator did not commit yet, only the one revealing early fails
+        assert!(!ei.add_evaluation_proof(proofs[0].clone()));
+        assert_eq!(ei.dissenting_evaluators(), vec![evaluators[0]]);
+        // the details are public now, the third evaluator could have copied them
+        assert!(!ei.add_evaluation(QEvaluation::new(proofs[2].clone(), score, vec![])));
+        assert!(ei.all_committed());
+        assert!(ei.add_evaluation_proof(proofs[2].clone()));
+        assert!(ei.add_evaluation_proof(proofs[1].clone()));
+        assert!(ei.is_done());
+        assert_eq!(ei.score(), EvaluationResultScore::Final(score));
+        assert_eq!(ei.dissenting_evaluators(), vec![evaluators[0]]);
+    }
+    #[test]
+    fn weighted_majority() {
+        let evaluators = evaluators();
+        let score = score();
+        let weights = HashMap::from([(evaluators[0], 3)]);
+        let mut ei =
+            EvaluationInfo::with_weights(evaluators.clone(), &weights, SystemTime::UNIX_EPOCH);
+        let proofs = [
+            proof(evaluators[0], b"details"),
+            proof(evaluators[1], b"other details"),
+            proof(evaluators[2], b"details"),
+        ];
+        for evp in proofs.iter() {
+            ei.add_evaluation(QEvaluation::new(evp.clone(), score, vec![]));
+        }
+        ei.add_evaluation_proof(proofs[0].clone());
+        // 3 out of 5
+        assert_eq!(ei.score(), EvaluationResultScore::Final(score));
+        assert!(ei.disputed_tests().is_empty());
+        ei.add_evaluation_proof(proofs[1].clone());
+        assert_eq!(ei.dissenting_evaluators(), vec![evaluators[1]]);
+        assert_eq!(ei.disputed_tests(), vec![1]);
+        assert!(!ei.is_done());
+        ei.tick(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(30),
+            Duration::from_secs(30),
+        );
+        assert!(!ei.is_done());
+        ei.tick(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(60),
+            Duration::from_secs(30),
+        );
+        assert!(ei.is_done());
+        assert_eq!(ei.score(), EvaluationResultScore::Final(score));
+        assert_eq!(
+            ei.dissenting_evaluators(),
+            vec![evaluators[1], evaluators[2]]
+        );
     }
 }
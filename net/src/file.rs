@@ -1,45 +1,288 @@
 use crate::message::*;
+use crate::socket::RngSource;
 
 use async_lock::OnceCell;
 use bitvec::bitvec;
 use bitvec::prelude::BitVec;
 use scc::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
+use tracing::*;
+
+// leaves and inner nodes of the merkle tree are hashed with different prefixes,
+// so that an inner node can't be passed off as a chunk
+const MERKLE_LEAF: u8 = 0;
+const MERKLE_NODE: u8 = 1;
+
+pub fn chunk_hash(chunk: &[u8]) -> Mac {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[MERKLE_LEAF]);
+    hasher.update(chunk);
+    Mac(hasher.finalize())
+}
+/// root of the merkle tree over the chunk hashes of a file,
+/// an odd node at the end of a level is promoted as is
+pub fn merkle_root(chunk_hashes: &[Mac]) -> Mac {
+    if chunk_hashes.is_empty() {
+        return Mac(blake3::hash(&[]));
+    }
+    let mut level = chunk_hashes.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|x| match x {
+                [l, r] => {
+                    let mut hasher = blake3::Hasher::new();
+                    hasher.update(&[MERKLE_NODE]);
+                    hasher.update(l.0.as_bytes());
+                    hasher.update(r.0.as_bytes());
+                    Mac(hasher.finalize())
+                }
+                _ => x[0],
+            })
+            .collect();
+    }
+    level[0]
+}
+pub fn chunk_hashes(data: &[u8]) -> Vec<Mac> {
+    data.chunks(FILE_CHUNK_SIZE).map(chunk_hash).collect()
+}
+
+/// splits the chunk ranges [l,r] among npeers, in blocks of block_size chunks.
+/// the assignment is shifted by round, so that a block is asked to a different peer every round
+pub fn schedule_ranges(
+    ranges: &[(u32, u32)],
+    npeers: usize,
+    block_size: u32,
+    round: usize,
+) -> Vec<Vec<(u32, u32)>> {
+    let mut schedule: Vec<Vec<(u32, u32)>> = vec![vec![]; npeers];
+    if npeers == 0 {
+        return schedule;
+    }
+    let mut block = round;
+    for &(l, r) in ranges {
+        let mut l = l;
+        while l <= r {
+            let br = l.saturating_add(block_size.max(1) - 1).min(r);
+            let peer_ranges = &mut schedule[block % npeers];
+            match peer_ranges.last_mut() {
+                Some((_, pr)) if *pr + 1 == l => *pr = br,
+                _ => peer_ranges.push((l, br)),
+            }
+            block += 1;
+            if br == u32::MAX {
+                break;
+            }
+            l = br + 1;
+        }
+    }
+    schedule
+}
+
+/// how much of a file being received is there, see FileStore::progress
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileProgress {
+    pub bytes_received: usize,
+    pub size: usize,
+    pub chunks_missing: usize,
+    pub done: bool,
+    /// the complete file did not match its hash and was dropped, no more values follow
+    pub failed: bool,
+}
+impl FileProgress {
+    fn done(size: usize) -> Self {
+        Self {
+            bytes_received: size,
+            size,
+            chunks_missing: 0,
+            done: true,
+            failed: false,
+        }
+    }
+    /// in 0..=100, an empty file is complete
+    pub fn percent(&self) -> f64 {
+        if self.size == 0 {
+            100.0
+        } else {
+            self.bytes_received as f64 * 100.0 / self.size as f64
+        }
+    }
+}
 
 struct FileParts {
     enc_key: EncKey,
+    // what the chunks are actually encrypted with, see file_chunk_key
+    chunk_key: EncKey,
     present: BitVec,
     data: Vec<u8>,
+    // if known, every chunk is checked on arrival
+    chunk_hashes: Option<Vec<Mac>>,
+    // if set, the chunk hashes are asked to peers and checked against it
+    chunks_root: Option<Mac>,
+    // the chunk hashes received so far, until all of them are
+    received_hashes: Vec<Option<Mac>>,
+    hashes_missing: usize,
+    // updated whenever chunks are added or dropped
+    progress: watch::Sender<FileProgress>,
 }
 impl FileParts {
-    fn new(size: usize, enc_key: EncKey) -> Self {
+    fn new(hash: FileHash, size: usize, enc_key: EncKey) -> Self {
+        let nchunks = size.div_ceil(FILE_CHUNK_SIZE);
         Self {
             enc_key,
-            present: bitvec![0; (size+FILE_CHUNK_SIZE-1)/FILE_CHUNK_SIZE],
+            chunk_key: file_chunk_key(hash, &enc_key),
+            present: bitvec![0; nchunks],
             data: vec![0u8; size],
+            chunk_hashes: None,
+            chunks_root: None,
+            received_hashes: vec![],
+            hashes_missing: 0,
+            progress: watch::Sender::new(FileProgress {
+                bytes_received: 0,
+                size,
+                chunks_missing: nchunks,
+                done: false,
+                failed: false,
+            }),
         }
     }
+    fn chunk_range(&self, chunki: usize) -> std::ops::Range<usize> {
+        chunki * FILE_CHUNK_SIZE..((chunki + 1) * FILE_CHUNK_SIZE).min(self.data.len())
+    }
+    fn set_chunk_hashes(&mut self, chunks_root: Mac, chunk_hashes: Vec<Mac>) -> bool {
+        if chunk_hashes.len() != self.nchunks() || merkle_root(&chunk_hashes) != chunks_root {
+            return false;
+        }
+        // drop chunks received before that turn out to be bad
+        for (chunki, h) in chunk_hashes.iter().enumerate() {
+            if self.present[chunki] && chunk_hash(&self.data[self.chunk_range(chunki)]) != *h {
+                self.present.set(chunki, false);
+            }
+        }
+        self.chunk_hashes = Some(chunk_hashes);
+        let (bytes_received, chunks_missing) =
+            (self.count_bytes_received(), self.present.count_zeros());
+        self.progress.send_modify(|p| {
+            p.bytes_received = bytes_received;
+            p.chunks_missing = chunks_missing;
+        });
+        true
+    }
+    /// the chunk hashes will be received from peers and checked against chunks_root
+    fn expect_chunk_hashes(&mut self, chunks_root: Mac) {
+        self.chunks_root = Some(chunks_root);
+        self.received_hashes = vec![None; self.nchunks()];
+        self.hashes_missing = self.nchunks();
+    }
+    /// adds the chunk hashes from first, in any order,
+    /// once all of them are received they are checked against the root and used,
+    /// returns false if they were not expected or do not match the root
+    fn add_chunk_hashes(&mut self, first: usize, hashes: Vec<Mac>) -> bool {
+        let Some(chunks_root) = self.chunks_root else {
+            return false;
+        };
+        if self.chunk_hashes.is_some() || first.saturating_add(hashes.len()) > self.nchunks() {
+            return false;
+        }
+        for (slot, h) in self.received_hashes[first..].iter_mut().zip(hashes) {
+            if slot.replace(h).is_none() {
+                self.hashes_missing -= 1;
+            }
+        }
+        if self.hashes_missing == 0 {
+            let hashes = self.received_hashes.iter().flatten().copied().collect();
+            if !self.set_chunk_hashes(chunks_root, hashes) {
+                // they are asked again, maybe to other peers
+                self.received_hashes.fill(None);
+                self.hashes_missing = self.nchunks();
+                return false;
+            }
+            self.received_hashes = vec![];
+        }
+        true
+    }
+    /// the first index of each block of MAX_CHUNK_HASHES chunk hashes still to be received,
+    /// empty if they are not needed
+    fn missing_chunk_hashes(&self) -> Vec<u32> {
+        self.received_hashes
+            .chunks(MAX_CHUNK_HASHES)
+            .enumerate()
+            .filter(|(_, block)| block.iter().any(Option::is_none))
+            .map(|(i, _)| (i * MAX_CHUNK_HASHES) as u32)
+            .collect()
+    }
     fn nchunks(&self) -> usize {
-        (self.data.len() + FILE_CHUNK_SIZE - 1) / FILE_CHUNK_SIZE
+        self.data.len().div_ceil(FILE_CHUNK_SIZE)
     }
     fn is_full(&self) -> bool {
         self.nchunks() == self.present.count_ones()
     }
-    fn add_chunk(&mut self, chunki: usize, data: &[u8]) {
+    fn add_chunk(&mut self, chunki: usize, data: &[u8]) -> bool {
+        if let Some(chunk_hashes) = &self.chunk_hashes {
+            if chunk_hash(data) != chunk_hashes[chunki] {
+                return false;
+            }
+        }
         if !self.present[chunki] {
             self.present.set(chunki, true);
-            let sl = chunki * FILE_CHUNK_SIZE;
-            let sr = ((chunki + 1) * FILE_CHUNK_SIZE).min(self.data.len());
-            self.data[sl..sr].copy_from_slice(data);
+            let range = self.chunk_range(chunki);
+            let len = range.len();
+            self.data[range].copy_from_slice(data);
+            self.progress.send_modify(|p| {
+                p.bytes_received += len;
+                p.chunks_missing -= 1;
+            });
         }
+        true
     }
-    fn add_enc_chunk(&mut self, chunki: usize, chunk: Encrypted<FileChunk>) {
+    fn add_enc_chunk(
+        &mut self,
+        chunki: usize,
+        chunk: SizedEncrypted<FileChunk, FILE_CHUNK_SIZE>,
+    ) -> bool {
+        // every chunk is encrypted with its index as nonce
+        if chunki >= self.nchunks() || chunk.nonce() != EncNonce::from_counter(chunki as u64) {
+            return false;
+        }
         if !self.present[chunki] {
-            if let Some(FileChunk(data)) = chunk.inner(&self.enc_key) {
-                let sr = FILE_CHUNK_SIZE.min(self.data.len() - chunki * FILE_CHUNK_SIZE);
-                self.add_chunk(chunki, &data[..sr]);
+            if let Some(FileChunk(data)) = chunk.inner(&self.chunk_key) {
+                let sr = self.chunk_range(chunki).len();
+                return self.add_chunk(chunki, &data[..sr]);
+            }
+            return false;
+        }
+        true
+    }
+    fn missing_chunks(&self) -> Vec<(u32, u32)> {
+        let mut ranges: Vec<(u32, u32)> = vec![];
+        for chunki in self.present.iter_zeros() {
+            let chunki = chunki as u32;
+            match ranges.last_mut() {
+                Some((_, r)) if *r + 1 == chunki => *r = chunki,
+                _ => ranges.push((chunki, chunki)),
             }
         }
+        ranges
+    }
+    fn bytes_received(&self) -> usize {
+        self.progress.borrow().bytes_received
+    }
+    fn count_bytes_received(&self) -> usize {
+        self.present
+            .iter_ones()
+            .map(|chunki| self.chunk_range(chunki).len())
+            .sum()
+    }
+    /// the bytes before the first missing chunk
+    fn prefix(&self) -> &[u8] {
+        let end = match self.present.first_zero() {
+            Some(chunki) => chunki * FILE_CHUNK_SIZE,
+            None => self.data.len(),
+        };
+        &self.data[..end]
     }
     fn get_all(&self) -> Option<Vec<u8>> {
         if self.is_full() {
@@ -52,92 +295,515 @@ impl FileParts {
 pub struct FullFile {
     data: Vec<u8>,
     enc_key: EncKey,
+    chunk_key: EncKey,
 }
 enum FilePartsError {
     NotFull,
     WrongHash,
 }
+/// why a received chunk was not added
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkError {
+    /// the file is not being received
+    UnknownFile,
+    /// the chunk does not belong to the file, or does not match its hash
+    BadChunk,
+    /// the complete file does not match its hash, so all of it is dropped
+    WrongHash,
+}
 impl FullFile {
-    fn new(data: Vec<u8>, enc_key: EncKey) -> Self {
-        Self { data, enc_key }
+    fn new(hash: FileHash, data: Vec<u8>, enc_key: EncKey) -> Self {
+        Self {
+            data,
+            enc_key,
+            chunk_key: file_chunk_key(hash, &enc_key),
+        }
     }
     pub fn get_chunk(&self, chunki: usize) -> &[u8] {
         &self.data[chunki * FILE_CHUNK_SIZE..((chunki + 1) * FILE_CHUNK_SIZE).min(self.data.len())]
     }
-    pub fn get_enc_chunk(&self, chunki: usize) -> Encrypted<FileChunk> {
-        let chunk = self.get_chunk(chunki);
-        let mut data = [0u8; FILE_CHUNK_SIZE];
-        data[..chunk.len()].copy_from_slice(chunk);
-        Encrypted::new(FileChunk(data), &self.enc_key)
+    pub fn nchunks(&self) -> usize {
+        self.data.len().div_ceil(FILE_CHUNK_SIZE)
+    }
+    pub fn get_enc_chunk(&self, chunki: usize) -> SizedEncrypted<FileChunk, FILE_CHUNK_SIZE> {
+        enc_chunk(self.get_chunk(chunki), chunki, &self.chunk_key)
     }
     pub fn get_all(&self) -> &[u8] {
         &self.data
     }
+    pub fn chunk_hashes(&self) -> Vec<Mac> {
+        chunk_hashes(&self.data)
+    }
+    /// the hashes of at most n chunks from first
+    pub fn chunk_hashes_from(&self, first: usize, n: usize) -> Vec<Mac> {
+        (first..self.nchunks().min(first.saturating_add(n)))
+            .map(|chunki| chunk_hash(self.get_chunk(chunki)))
+            .collect()
+    }
+    pub fn chunks_root(&self) -> Mac {
+        merkle_root(&self.chunk_hashes())
+    }
+}
+/// the nonce of a chunk is its index, so the same enc_key given for several files
+/// would reuse the keystream, each file's chunks are encrypted with a key of its own instead
+fn file_chunk_key(hash: FileHash, enc_key: &EncKey) -> EncKey {
+    enc_key.derive(hash.0.as_bytes())
+}
+fn enc_chunk(
+    chunk: &[u8],
+    chunki: usize,
+    enc_key: &EncKey,
+) -> SizedEncrypted<FileChunk, FILE_CHUNK_SIZE> {
+    let mut data = [0u8; FILE_CHUNK_SIZE];
+    data[..chunk.len()].copy_from_slice(chunk);
+    let nonce = EncNonce::from_counter(chunki as u64);
+    SizedEncrypted::with_nonce(FileChunk(data), enc_key, nonce)
 }
 
-#[derive(Default)]
 pub struct FileStore {
     file_parts: HashMap<FileHash, FileParts>,
     full_files: HashMap<FileHash, Arc<OnceCell<FullFile>>>,
+    // if set, full files are written here and evicted from memory
+    dir: Option<PathBuf>,
+    on_disk: HashMap<FileHash, EncKey>,
+    // how many times each file was added and not released
+    refs: HashMap<FileHash, u32>,
+    // files received from peers can't be bigger than this
+    max_file_size: usize,
+    // for the keys of the files added without one
+    rng: RngSource,
+}
+impl Default for FileStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl FileStore {
+    pub const DEFAULT_MAX_FILE_SIZE: usize = 1 << 30;
     pub fn new() -> Self {
         Self {
             file_parts: HashMap::new(),
             full_files: HashMap::new(),
+            dir: None,
+            on_disk: HashMap::new(),
+            refs: HashMap::new(),
+            max_file_size: Self::DEFAULT_MAX_FILE_SIZE,
+            rng: RngSource::default(),
         }
     }
-    pub async fn add_done(&self, data: Vec<u8>) -> FileHash {
-        let hash = Mac(blake3::hash(&data));
-        let ff = FullFile::new(data, EncKey::random());
-        let _ = self
+    /// full files will be stored in dir, named by their hash
+    pub fn with_dir(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: Some(dir.into()),
+            ..Self::new()
+        }
+    }
+    /// files bigger than this will not be received
+    pub fn with_max_file_size(mut self, max_file_size: usize) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+    /// where the keys of add_done and add_reader come from, eg the rng of the Net
+    pub fn with_rng(mut self, rng: RngSource) -> Self {
+        self.rng = rng;
+        self
+    }
+    fn file_path(&self, hash: FileHash) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(hex::encode(hash.0.as_bytes())))
+    }
+    async fn set_full(&self, hash: FileHash, ff: FullFile) {
+        let cell = self
             .full_files
             .entry_async(hash)
             .await
             .or_insert(Arc::new(OnceCell::new()))
             .get()
-            .set(ff)
-            .await;
+            .clone();
+        if let Some(path) = self.file_path(hash) {
+            match tokio::fs::write(&path, &ff.data).await {
+                Ok(()) => {
+                    let _ = self.on_disk.insert_async(hash, ff.enc_key).await;
+                    // whoever is already waiting for the file still gets it,
+                    // later calls to get_file will reload it from disk
+                    let _ = cell.set(ff).await;
+                    self.full_files.remove_async(&hash).await;
+                    return;
+                }
+                Err(e) => warn!("could not write file to {:?}: {}", path, e),
+            }
+        }
+        let _ = cell.set(ff).await;
+    }
+    async fn load(&self, hash: FileHash, enc_key: EncKey) -> Option<FullFile> {
+        let path = self.file_path(hash)?;
+        let data = tokio::fs::read(&path).await.ok()?;
+        if hash == Mac(blake3::hash(&data)) {
+            Some(FullFile::new(hash, data, enc_key))
+        } else {
+            warn!("file {:?} does not match its hash", path);
+            None
+        }
+    }
+    pub async fn add_done(&self, data: Vec<u8>) -> FileHash {
+        self.add_done_with_key(data, EncKey::random(&self.rng))
+            .await
+    }
+    /// if the file is already stored only its reference count is increased,
+    /// and it keeps its previous key (see enc_key)
+    pub async fn add_done_with_key(&self, data: Vec<u8>, enc_key: EncKey) -> FileHash {
+        let hash = Mac(blake3::hash(&data));
+        if self.add_ref(hash).await > 1 && self.is_full(hash).await {
+            return hash;
+        }
+        self.file_parts.remove_async(&hash).await;
+        let ff = FullFile::new(hash, data, enc_key);
+        self.set_full(hash, ff).await;
         hash
     }
-    pub async fn add_new(&self, hash: FileHash, size: usize, enc_key: EncKey) {
-        let _ = self
-            .file_parts
-            .insert_async(hash, FileParts::new(size, enc_key))
-            .await;
+    /// like add_done, but reads the file incrementally,
+    /// if the store has a dir the file is never fully kept in memory
+    pub async fn add_reader(&self, reader: impl AsyncRead + Unpin) -> std::io::Result<FileHash> {
+        self.add_reader_with_key(reader, EncKey::random(&self.rng))
+            .await
+    }
+    pub async fn add_reader_with_key(
+        &self,
+        reader: impl AsyncRead + Unpin,
+        enc_key: EncKey,
+    ) -> std::io::Result<FileHash> {
+        let tmp = self
+            .dir
+            .as_ref()
+            .map(|dir| dir.join(format!("tmp-{}", hex::encode(rand::random::<[u8; 8]>()))));
+        let (hash, data) = match Self::ingest(reader, tmp.as_deref()).await {
+            Ok(x) => x,
+            Err(e) => {
+                if let Some(tmp) = &tmp {
+                    let _ = tokio::fs::remove_file(tmp).await;
+                }
+                return Err(e);
+            }
+        };
+        if self.add_ref(hash).await > 1 && self.is_full(hash).await {
+            if let Some(tmp) = &tmp {
+                let _ = tokio::fs::remove_file(tmp).await;
+            }
+            return Ok(hash);
+        }
+        self.file_parts.remove_async(&hash).await;
+        let (Some(tmp), Some(path)) = (tmp, self.file_path(hash)) else {
+            self.set_full(hash, FullFile::new(hash, data, enc_key))
+                .await;
+            return Ok(hash);
+        };
+        tokio::fs::rename(&tmp, &path).await?;
+        let _ = self.on_disk.insert_async(hash, enc_key).await;
+        // someone may be waiting for this file already
+        if let Some(cell) = self
+            .full_files
+            .get_async(&hash)
+            .await
+            .map(|x| x.get().clone())
+        {
+            if let Some(ff) = self.load(hash, enc_key).await {
+                let _ = cell.set(ff).await;
+            }
+            self.full_files.remove_async(&hash).await;
+        }
+        Ok(hash)
+    }
+    // hashes the whole reader, writing it to tmp if given or returning it otherwise
+    async fn ingest(
+        mut reader: impl AsyncRead + Unpin,
+        tmp: Option<&Path>,
+    ) -> std::io::Result<(FileHash, Vec<u8>)> {
+        let mut out = match tmp {
+            Some(tmp) => Some(tokio::fs::File::create(tmp).await?),
+            None => None,
+        };
+        let mut hasher = blake3::Hasher::new();
+        let mut data = vec![];
+        let mut buf = vec![0u8; FILE_CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            match &mut out {
+                Some(out) => out.write_all(&buf[..n]).await?,
+                None => data.extend_from_slice(&buf[..n]),
+            }
+        }
+        if let Some(mut out) = out {
+            out.flush().await?;
+        }
+        Ok((Mac(hasher.finalize()), data))
+    }
+    /// prepares to receive a file, returns false if it is bigger than the maximum file size
+    pub async fn add_new(&self, hash: FileHash, size: usize, enc_key: EncKey) -> bool {
+        self.add_new_inner(hash, size, enc_key, None).await
+    }
+    /// like add_new, the chunk hashes are then checked against chunks_root
+    /// and used to check every chunk on arrival, see add_chunk_hashes
+    pub async fn add_new_with_root(
+        &self,
+        hash: FileHash,
+        size: usize,
+        enc_key: EncKey,
+        chunks_root: Mac,
+    ) -> bool {
+        self.add_new_inner(hash, size, enc_key, Some(chunks_root))
+            .await
+    }
+    async fn add_new_inner(
+        &self,
+        hash: FileHash,
+        size: usize,
+        enc_key: EncKey,
+        chunks_root: Option<Mac>,
+    ) -> bool {
+        // size comes from the network, so it's checked before allocating anything
+        if size > self.max_file_size {
+            return false;
+        }
+        self.add_ref(hash).await;
+        if self.is_full(hash).await {
+            return true;
+        }
+        let mut fp = FileParts::new(hash, size, enc_key);
+        if let Some(chunks_root) = chunks_root {
+            fp.expect_chunk_hashes(chunks_root);
+        }
+        let _ = self.file_parts.insert_async(hash, fp).await;
+        true
+    }
+    async fn add_ref(&self, hash: FileHash) -> u32 {
+        let mut refs = self.refs.entry_async(hash).await.or_insert(0);
+        *refs.get_mut() += 1;
+        *refs.get()
+    }
+    async fn is_full(&self, hash: FileHash) -> bool {
+        self.on_disk.contains_async(&hash).await
+            || self
+                .full_files
+                .get_async(&hash)
+                .await
+                .is_some_and(|ff| ff.get().is_initialized())
+    }
+    /// drops a reference to a file added before,
+    /// the file is removed (also from disk) once nobody references it
+    pub async fn release(&self, hash: FileHash) {
+        let Some(mut refs) = self.refs.get_async(&hash).await else {
+            return;
+        };
+        *refs.get_mut() -= 1;
+        if *refs.get() > 0 {
+            return;
+        }
+        let _ = refs.remove();
+        self.file_parts.remove_async(&hash).await;
+        self.full_files.remove_async(&hash).await;
+        if self.on_disk.remove_async(&hash).await.is_some() {
+            if let Some(path) = self.file_path(hash) {
+                if let Err(e) = tokio::fs::remove_file(&path).await {
+                    warn!("could not remove {:?}: {}", path, e);
+                }
+            }
+        }
+    }
+    /// forgets the chunks received so far of a file that is not complete,
+    /// and drops the reference taken by add_new, returns false if there was nothing to remove
+    pub async fn remove_incomplete(&self, hash: FileHash) -> bool {
+        if self.file_parts.remove_async(&hash).await.is_none() {
+            return false;
+        }
+        self.release(hash).await;
+        true
+    }
+    /// key the file is encrypted with when served
+    pub async fn enc_key(&self, hash: FileHash) -> Option<EncKey> {
+        if let Some(enc_key) = self.on_disk.get_async(&hash).await {
+            return Some(*enc_key.get());
+        }
+        if let Some(fp) = self.file_parts.get_async(&hash).await {
+            return Some(fp.get().enc_key);
+        }
+        self.full_files
+            .get_async(&hash)
+            .await
+            .and_then(|ff| ff.get().get().map(|ff| ff.enc_key))
     }
+    /// adds chunk hashes of a file added with add_new_with_root, from first,
+    /// once all of them are received each chunk is checked on arrival,
+    /// returns false if they were not expected or do not match the root
+    pub async fn add_chunk_hashes(&self, hash: FileHash, first: u32, hashes: Vec<Mac>) -> bool {
+        if let Some(mut fp) = self.file_parts.get_async(&hash).await {
+            fp.get_mut().add_chunk_hashes(first as usize, hashes)
+        } else {
+            false
+        }
+    }
+    /// where the blocks of chunk hashes of a file still to be asked for start,
+    /// empty if the file is unknown or its chunk hashes are not needed
+    pub async fn missing_chunk_hashes(&self, hash: FileHash) -> Vec<u32> {
+        match self.file_parts.get_async(&hash).await {
+            Some(fp) => fp.get().missing_chunk_hashes(),
+            None => vec![],
+        }
+    }
+    /// Ok(true) if the file is now complete,
+    /// Ok(false) if more chunks are needed
     pub async fn add_enc_chunk(
         &self,
         hash: FileHash,
         chunki: usize,
-        piece: Encrypted<FileChunk>,
-    ) -> Option<bool> {
+        piece: SizedEncrypted<FileChunk, FILE_CHUNK_SIZE>,
+    ) -> Result<bool, ChunkError> {
         if let Some(mut fp) = self.file_parts.get_async(&hash).await {
-            fp.get_mut().add_enc_chunk(chunki, piece);
+            if !fp.get_mut().add_enc_chunk(chunki, piece) {
+                return Err(ChunkError::BadChunk);
+            }
             if fp.get().is_full() {
                 let value = fp.remove();
                 if hash == Mac(blake3::hash(&value.data)) {
-                    let ff = FullFile::new(value.data, value.enc_key);
-                    let _ = self
-                        .full_files
-                        .entry_async(hash)
-                        .await
-                        .or_insert(Arc::new(OnceCell::new()))
-                        .get()
-                        .set(ff)
-                        .await;
-                    Some(true)
+                    let progress = value.progress;
+                    let ff = FullFile::new(hash, value.data, value.enc_key);
+                    let size = ff.data.len();
+                    self.set_full(hash, ff).await;
+                    progress.send_replace(FileProgress::done(size));
+                    Ok(true)
                 } else {
-                    None
+                    value.progress.send_modify(|p| p.failed = true);
+                    Err(ChunkError::WrongHash)
                 }
             } else {
-                Some(false)
+                Ok(false)
             }
+        } else {
+            Err(ChunkError::UnknownFile)
+        }
+    }
+    /// ranges [l,r] of the chunks that still have to be received,
+    /// None if the file is unknown
+    pub async fn missing_chunks(&self, hash: FileHash) -> Option<Vec<(u32, u32)>> {
+        if let Some(fp) = self.file_parts.get_async(&hash).await {
+            Some(fp.get().missing_chunks())
+        } else if self.is_full(hash).await {
+            Some(vec![])
         } else {
             None
         }
     }
+    /// how many bytes of the file are there, eg to show download progress,
+    /// None if the file is unknown
+    pub async fn bytes_received(&self, hash: FileHash) -> Option<usize> {
+        if let Some(fp) = self.file_parts.get_async(&hash).await {
+            return Some(fp.get().bytes_received());
+        }
+        self.full_size(hash).await
+    }
+    /// size of a full file, without reading it from disk
+    async fn full_size(&self, hash: FileHash) -> Option<usize> {
+        if self.on_disk.contains_async(&hash).await {
+            let metadata = tokio::fs::metadata(self.file_path(hash)?).await.ok()?;
+            return Some(metadata.len() as usize);
+        }
+        self.full_files
+            .get_async(&hash)
+            .await?
+            .get()
+            .get()
+            .map(|ff| ff.data.len())
+    }
+    /// up to len bytes from start of a full file, and its key,
+    /// only those are read from disk, None if the file is not full
+    async fn read_full(
+        &self,
+        hash: FileHash,
+        start: usize,
+        len: usize,
+    ) -> Option<(Vec<u8>, EncKey)> {
+        if let Some(enc_key) = self.on_disk.get_async(&hash).await.map(|x| *x.get()) {
+            let mut file = tokio::fs::File::open(self.file_path(hash)?).await.ok()?;
+            let size = file.metadata().await.ok()?.len() as usize;
+            let start = start.min(size);
+            let mut data = vec![0u8; len.min(size - start)];
+            file.seek(std::io::SeekFrom::Start(start as u64))
+                .await
+                .ok()?;
+            file.read_exact(&mut data).await.ok()?;
+            return Some((data, enc_key));
+        }
+        let cell = self.full_files.get_async(&hash).await?.get().clone();
+        let ff = cell.get()?;
+        let data = &ff.data[start.min(ff.data.len())..];
+        Some((data[..len.min(data.len())].to_vec(), ff.enc_key))
+    }
+    /// how many chunks a full file has, None if the file is not full
+    pub async fn nchunks(&self, hash: FileHash) -> Option<usize> {
+        Some(self.full_size(hash).await?.div_ceil(FILE_CHUNK_SIZE))
+    }
+    /// a chunk of a full file encrypted to be served, in disk mode only the chunk is read,
+    /// None if the file is not full or has no such chunk
+    pub async fn get_enc_chunk(
+        &self,
+        hash: FileHash,
+        chunki: usize,
+    ) -> Option<SizedEncrypted<FileChunk, FILE_CHUNK_SIZE>> {
+        let start = chunki.checked_mul(FILE_CHUNK_SIZE)?;
+        let (chunk, enc_key) = self.read_full(hash, start, FILE_CHUNK_SIZE).await?;
+        (!chunk.is_empty()).then(|| enc_chunk(&chunk, chunki, &file_chunk_key(hash, &enc_key)))
+    }
+    /// the hashes of at most n chunks of a full file from first,
+    /// in disk mode only those chunks are read, None if the file is not full
+    pub async fn chunk_hashes_from(
+        &self,
+        hash: FileHash,
+        first: usize,
+        n: usize,
+    ) -> Option<Vec<Mac>> {
+        let start = first.checked_mul(FILE_CHUNK_SIZE)?;
+        let len = n.saturating_mul(FILE_CHUNK_SIZE);
+        let (data, _) = self.read_full(hash, start, len).await?;
+        Some(data.chunks(FILE_CHUNK_SIZE).map(chunk_hash).collect())
+    }
+    /// follows the download of a file, with a new value as chunks arrive,
+    /// a complete file gives a done progress right away,
+    /// the sender is dropped when the file is complete or removed, after the last value,
+    /// None if the file is unknown
+    pub async fn progress(&self, hash: FileHash) -> Option<watch::Receiver<FileProgress>> {
+        if let Some(fp) = self.file_parts.get_async(&hash).await {
+            return Some(fp.get().progress.subscribe());
+        }
+        let size = self.bytes_received(hash).await?;
+        Some(watch::channel(FileProgress::done(size)).1)
+    }
+    /// the leading bytes of the file received so far, up to the first missing chunk,
+    /// they are only checked if the chunk hashes are set, or once the file is complete,
+    /// None if the file is unknown
+    pub async fn received_prefix(&self, hash: FileHash) -> Option<Vec<u8>> {
+        if let Some(fp) = self.file_parts.get_async(&hash).await {
+            return Some(fp.get().prefix().to_vec());
+        }
+        if self.is_full(hash).await {
+            return self
+                .get_file(hash)
+                .await
+                .get()
+                .map(|ff| ff.get_all().to_vec());
+        }
+        None
+    }
     pub async fn get_file(&self, hash: FileHash) -> Arc<OnceCell<FullFile>> {
+        if let Some(enc_key) = self.on_disk.get_async(&hash).await.map(|x| *x.get()) {
+            if let Some(ff) = self.load(hash, enc_key).await {
+                // not kept in full_files, it's dropped once the caller is done with it
+                return Arc::new(OnceCell::from(ff));
+            }
+        }
         self.full_files
             .entry_async(hash)
             .await
@@ -146,3 +812,349 @@ impl FileStore {
             .clone()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn schedule_ranges_rotates() {
+        let ranges = [(0, 9), (20, 23)];
+        let schedule = schedule_ranges(&ranges, 2, 4, 0);
+        assert_eq!(schedule[0], vec![(0, 3), (8, 9)]);
+        assert_eq!(schedule[1], vec![(4, 7), (20, 23)]);
+        let schedule = schedule_ranges(&ranges, 2, 4, 1);
+        assert_eq!(schedule[0], vec![(4, 7), (20, 23)]);
+        assert_eq!(schedule[1], vec![(0, 3), (8, 9)]);
+        assert_eq!(
+            schedule_ranges(&ranges, 1, 4, 3),
+            vec![vec![(0, 9), (20, 23)]]
+        );
+    }
+    #[tokio::test]
+    async fn refcount() {
+        let store = FileStore::new();
+        let data = vec![42u8; FILE_CHUNK_SIZE + 1];
+        let key = EncKey::random(&RngSource::Thread);
+        let hash = store.add_done_with_key(data.clone(), key).await;
+        assert_eq!(store.add_done(data).await, hash);
+        assert_eq!(store.enc_key(hash).await, Some(key));
+        store.release(hash).await;
+        assert_eq!(store.missing_chunks(hash).await, Some(vec![]));
+        store.release(hash).await;
+        assert_eq!(store.missing_chunks(hash).await, None);
+    }
+    #[tokio::test]
+    async fn max_file_size() {
+        let store = FileStore::new().with_max_file_size(FILE_CHUNK_SIZE);
+        let hash = Mac(blake3::hash(b"big"));
+        assert!(
+            !store
+                .add_new(
+                    hash,
+                    FILE_CHUNK_SIZE + 1,
+                    EncKey::random(&RngSource::Thread)
+                )
+                .await
+        );
+        assert_eq!(store.missing_chunks(hash).await, None);
+        assert!(
+            store
+                .add_new(hash, FILE_CHUNK_SIZE, EncKey::random(&RngSource::Thread))
+                .await
+        );
+        assert_eq!(store.missing_chunks(hash).await, Some(vec![(0, 0)]));
+    }
+    #[tokio::test]
+    async fn received_prefix() {
+        let store = FileStore::new();
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 2 + 7).map(|x| x as u8).collect();
+        let key = EncKey::random(&RngSource::Thread);
+        let hash = Mac(blake3::hash(&data));
+        assert_eq!(store.bytes_received(hash).await, None);
+        assert!(store.add_new(hash, data.len(), key).await);
+        assert_eq!(store.bytes_received(hash).await, Some(0));
+        assert_eq!(store.received_prefix(hash).await, Some(vec![]));
+        let ff = FullFile::new(hash, data.clone(), key);
+        store
+            .add_enc_chunk(hash, 2, ff.get_enc_chunk(2))
+            .await
+            .unwrap();
+        assert_eq!(store.bytes_received(hash).await, Some(7));
+        assert_eq!(store.received_prefix(hash).await, Some(vec![]));
+        store
+            .add_enc_chunk(hash, 0, ff.get_enc_chunk(0))
+            .await
+            .unwrap();
+        assert_eq!(store.bytes_received(hash).await, Some(FILE_CHUNK_SIZE + 7));
+        assert_eq!(
+            store.received_prefix(hash).await.unwrap(),
+            &data[..FILE_CHUNK_SIZE]
+        );
+        store
+            .add_enc_chunk(hash, 1, ff.get_enc_chunk(1))
+            .await
+            .unwrap();
+        assert_eq!(store.bytes_received(hash).await, Some(data.len()));
+        assert_eq!(store.received_prefix(hash).await.unwrap(), data);
+    }
+    #[tokio::test]
+    async fn progress() {
+        let store = FileStore::new();
+        let data = vec![42u8; FILE_CHUNK_SIZE * 3 + 1];
+        let key = EncKey::random(&RngSource::Thread);
+        let hash = Mac(blake3::hash(&data));
+        assert!(store.progress(hash).await.is_none());
+        assert!(store.add_new(hash, data.len(), key).await);
+        let mut progress = store.progress(hash).await.unwrap();
+        assert_eq!(progress.borrow().chunks_missing, 4);
+        assert_eq!(progress.borrow().percent(), 0.0);
+        let ff = FullFile::new(hash, data.clone(), key);
+        store
+            .add_enc_chunk(hash, 3, ff.get_enc_chunk(3))
+            .await
+            .unwrap();
+        progress.changed().await.unwrap();
+        assert_eq!(
+            *progress.borrow_and_update(),
+            FileProgress {
+                bytes_received: 1,
+                size: data.len(),
+                chunks_missing: 3,
+                done: false,
+                failed: false,
+            }
+        );
+        // chunks received again are not progress
+        store
+            .add_enc_chunk(hash, 3, ff.get_enc_chunk(3))
+            .await
+            .unwrap();
+        assert!(!progress.has_changed().unwrap());
+        for chunki in 0..3 {
+            store
+                .add_enc_chunk(hash, chunki, ff.get_enc_chunk(chunki))
+                .await
+                .unwrap();
+        }
+        let last = *progress.borrow_and_update();
+        assert!(last.done);
+        assert_eq!(last.percent(), 100.0);
+        assert!(progress.changed().await.is_err());
+        assert!(store.progress(hash).await.unwrap().borrow().done);
+    }
+    #[tokio::test]
+    async fn progress_failed() {
+        let store = FileStore::new();
+        let data = vec![42u8; FILE_CHUNK_SIZE + 1];
+        let key = EncKey::random(&RngSource::Thread);
+        let hash = Mac(blake3::hash(b"something else"));
+        assert!(store.add_new(hash, data.len(), key).await);
+        let mut progress = store.progress(hash).await.unwrap();
+        let ff = FullFile::new(hash, data, key);
+        assert_eq!(
+            store.add_enc_chunk(hash, 0, ff.get_enc_chunk(0)).await,
+            Ok(false)
+        );
+        assert_eq!(
+            store.add_enc_chunk(hash, 1, ff.get_enc_chunk(1)).await,
+            Err(ChunkError::WrongHash)
+        );
+        let last = *progress.borrow_and_update();
+        assert!(last.failed && !last.done);
+        assert!(progress.changed().await.is_err());
+        assert!(store.progress(hash).await.is_none());
+    }
+    #[tokio::test]
+    async fn remove_incomplete() {
+        let store = FileStore::new();
+        let data = vec![42u8; FILE_CHUNK_SIZE * 2];
+        let key = EncKey::random(&RngSource::Thread);
+        let hash = Mac(blake3::hash(&data));
+        assert!(store.add_new(hash, data.len(), key).await);
+        let ff = FullFile::new(hash, data.clone(), key);
+        assert_eq!(
+            store.add_enc_chunk(hash, 0, ff.get_enc_chunk(0)).await,
+            Ok(false)
+        );
+        assert!(store.remove_incomplete(hash).await);
+        assert_eq!(store.missing_chunks(hash).await, None);
+        assert!(!store.remove_incomplete(hash).await);
+        // complete files are not touched
+        let hash = store.add_done(data).await;
+        assert!(!store.remove_incomplete(hash).await);
+        assert_eq!(store.missing_chunks(hash).await, Some(vec![]));
+    }
+    #[tokio::test]
+    async fn add_reader_same_hash() {
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 3 + 7).map(|x| x as u8).collect();
+        let hash = Mac(blake3::hash(&data));
+        let store = FileStore::new();
+        assert_eq!(store.add_reader(&data[..]).await.unwrap(), hash);
+        let ff = store.get_file(hash).await;
+        assert_eq!(ff.wait().await.get_all(), &data[..]);
+        let dir =
+            std::env::temp_dir().join(format!("decipi-{}", hex::encode(rand::random::<[u8; 8]>())));
+        std::fs::create_dir(&dir).unwrap();
+        let store = FileStore::with_dir(&dir);
+        assert_eq!(store.add_reader(&data[..]).await.unwrap(), hash);
+        let ff = store.get_file(hash).await;
+        assert_eq!(ff.wait().await.get_all(), &data[..]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[tokio::test]
+    async fn disk_chunks() {
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 3 + 7).map(|x| x as u8).collect();
+        let key = EncKey::random(&RngSource::Thread);
+        let ff = FullFile::new(Mac(blake3::hash(&data)), data.clone(), key);
+        let dir =
+            std::env::temp_dir().join(format!("decipi-{}", hex::encode(rand::random::<[u8; 8]>())));
+        std::fs::create_dir(&dir).unwrap();
+        for store in [FileStore::new(), FileStore::with_dir(&dir)] {
+            let hash = store.add_done_with_key(data.clone(), key).await;
+            assert_eq!(store.nchunks(hash).await, Some(4));
+            assert_eq!(
+                store.get_enc_chunk(hash, 3).await,
+                Some(ff.get_enc_chunk(3))
+            );
+            assert_eq!(store.get_enc_chunk(hash, 4).await, None);
+            assert_eq!(
+                store.chunk_hashes_from(hash, 1, 2).await,
+                Some(ff.chunk_hashes_from(1, 2))
+            );
+            assert_eq!(store.chunk_hashes_from(hash, 5, 2).await, Some(vec![]));
+        }
+        // only the chunk asked for is read, the rest of the file is not even checked
+        let hash = Mac(blake3::hash(&data));
+        let path = dir.join(hex::encode(hash.0.as_bytes()));
+        let mut corrupted = data.clone();
+        corrupted[0] ^= 1;
+        let store = FileStore::with_dir(&dir);
+        store.add_done_with_key(data, key).await;
+        std::fs::write(&path, &corrupted).unwrap();
+        assert!(store.get_file(hash).await.get().is_none());
+        assert_eq!(
+            store.get_enc_chunk(hash, 1).await,
+            Some(ff.get_enc_chunk(1))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn merkle_bad_chunk() {
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 3 + 7).map(|x| x as u8).collect();
+        let hashes = chunk_hashes(&data);
+        let root = merkle_root(&hashes);
+        let mut fp = FileParts::new(
+            Mac(blake3::hash(&data)),
+            data.len(),
+            EncKey::random(&RngSource::Thread),
+        );
+        assert!(!fp.set_chunk_hashes(root, hashes[1..].to_vec()));
+        assert!(fp.set_chunk_hashes(root, hashes));
+        assert!(!fp.add_chunk(1, &data[..FILE_CHUNK_SIZE]));
+        assert!(!fp.present[1]);
+        for chunki in 0..fp.nchunks() {
+            let range = fp.chunk_range(chunki);
+            assert!(fp.add_chunk(chunki, &data[range]));
+        }
+        assert_eq!(fp.get_all(), Some(data));
+    }
+    #[test]
+    fn merkle_node_is_not_a_leaf() {
+        let hashes = chunk_hashes(&[1u8; FILE_CHUNK_SIZE * 2]);
+        let mut node = hashes[0].0.as_bytes().to_vec();
+        node.extend_from_slice(hashes[1].0.as_bytes());
+        assert_ne!(merkle_root(&hashes), chunk_hash(&node));
+    }
+    #[tokio::test]
+    async fn chunk_hashes_from_peers() {
+        let store = FileStore::new();
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 3 + 7).map(|x| x as u8).collect();
+        let key = EncKey::random(&RngSource::Thread);
+        let hash = Mac(blake3::hash(&data));
+        let ff = FullFile::new(hash, data.clone(), key);
+        assert!(
+            store
+                .add_new_with_root(hash, data.len(), key, ff.chunks_root())
+                .await
+        );
+        assert_eq!(store.missing_chunk_hashes(hash).await, vec![0]);
+        assert!(
+            !store
+                .add_chunk_hashes(hash, 3, ff.chunk_hashes_from(2, 2))
+                .await
+        );
+        assert!(
+            store
+                .add_chunk_hashes(hash, 2, ff.chunk_hashes_from(2, 2))
+                .await
+        );
+        assert_eq!(store.missing_chunk_hashes(hash).await, vec![0]);
+        assert!(
+            store
+                .add_chunk_hashes(hash, 0, ff.chunk_hashes_from(0, MAX_CHUNK_HASHES))
+                .await
+        );
+        assert!(store.missing_chunk_hashes(hash).await.is_empty());
+        // right key and nonce, wrong data
+        let other = FullFile::new(hash, vec![0u8; data.len()], key);
+        assert_eq!(
+            store.add_enc_chunk(hash, 1, other.get_enc_chunk(1)).await,
+            Err(ChunkError::BadChunk)
+        );
+        assert_eq!(store.missing_chunks(hash).await, Some(vec![(0, 3)]));
+        for chunki in 0..3 {
+            assert_eq!(
+                store
+                    .add_enc_chunk(hash, chunki, ff.get_enc_chunk(chunki))
+                    .await,
+                Ok(false)
+            );
+        }
+        assert_eq!(
+            store.add_enc_chunk(hash, 3, ff.get_enc_chunk(3)).await,
+            Ok(true)
+        );
+        assert_eq!(
+            store.add_enc_chunk(hash, 3, ff.get_enc_chunk(3)).await,
+            Err(ChunkError::UnknownFile)
+        );
+    }
+    #[tokio::test]
+    async fn chunk_hashes_wrong_root() {
+        let store = FileStore::new();
+        let data = vec![42u8; FILE_CHUNK_SIZE * 2];
+        let key = EncKey::random(&RngSource::Thread);
+        let hash = Mac(blake3::hash(&data));
+        let ff = FullFile::new(hash, data.clone(), key);
+        assert!(store.add_new_with_root(hash, data.len(), key, hash).await);
+        assert!(!store.add_chunk_hashes(hash, 0, ff.chunk_hashes()).await);
+        // asked again
+        assert_eq!(store.missing_chunk_hashes(hash).await, vec![0]);
+    }
+    #[test]
+    fn chunk_nonce_is_index() {
+        let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 2 + 7).map(|x| x as u8).collect();
+        let hash = Mac(blake3::hash(&data));
+        let enc_key = EncKey::random(&RngSource::Thread);
+        let ff = FullFile::new(hash, data.clone(), enc_key);
+        let mut fp = FileParts::new(hash, data.len(), enc_key);
+        assert_ne!(ff.get_enc_chunk(0).nonce(), ff.get_enc_chunk(1).nonce());
+        assert!(!fp.add_enc_chunk(1, ff.get_enc_chunk(0)));
+        for chunki in 0..ff.nchunks() {
+            assert!(fp.add_enc_chunk(chunki, ff.get_enc_chunk(chunki)));
+        }
+        assert_eq!(fp.get_all(), Some(data));
+    }
+    #[test]
+    fn chunk_key_per_file() {
+        // two files with the same key and the same first chunk
+        let data: Vec<u8> = vec![7u8; FILE_CHUNK_SIZE * 2];
+        let mut other = data.clone();
+        other[FILE_CHUNK_SIZE] = 8;
+        let enc_key = EncKey::random(&RngSource::Thread);
+        let ff = FullFile::new(Mac(blake3::hash(&data)), data, enc_key);
+        let other = FullFile::new(Mac(blake3::hash(&other)), other, enc_key);
+        assert_eq!(ff.get_chunk(0), other.get_chunk(0));
+        assert_ne!(ff.get_enc_chunk(0), other.get_enc_chunk(0));
+    }
+}
@@ -0,0 +1,67 @@
+use crate::message::*;
+
+use scc::HashMap;
+
+/// enc keys that can be handed out, each to whoever satisfies its id
+#[derive(Default)]
+pub struct EncKeyStore {
+    keys: HashMap<EncKeyId, EncKey>,
+}
+impl EncKeyStore {
+    pub fn new() -> Self {
+        Self {
+            keys: HashMap::new(),
+        }
+    }
+    pub async fn add(&self, info: EncKeyInfo) {
+        self.keys.entry_async(info.id).await.insert_entry(info.key);
+    }
+    pub async fn remove(&self, id: &EncKeyId) {
+        self.keys.remove_async(id).await;
+    }
+    /// the key with the given id, None if it is unknown or ctx is not entitled to it
+    pub async fn get(&self, id: &EncKeyId, ctx: &AccessContext) -> Option<EncKeyInfo> {
+        if !id.is_satisfied_by(ctx) {
+            return None;
+        }
+        self.keys.get_async(id).await.map(|key| EncKeyInfo {
+            id: id.clone(),
+            key: *key.get(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+    #[tokio::test]
+    async fn access_control() {
+        let store = EncKeyStore::new();
+        let key = EncKey::random(&crate::RngSource::Thread);
+        store
+            .add(EncKeyInfo {
+                id: EncKeyId::ProblemSolved(1),
+                key,
+            })
+            .await;
+        let mut ctx = AccessContext {
+            entity: Entity::Participant,
+            psk: PubSigKey::dummy(),
+            published: HashSet::new(),
+            solved: HashSet::new(),
+        };
+        assert_eq!(store.get(&EncKeyId::ProblemSolved(1), &ctx).await, None);
+        ctx.solved.insert(1);
+        assert_eq!(
+            store
+                .get(&EncKeyId::ProblemSolved(1), &ctx)
+                .await
+                .map(|info| info.key),
+            Some(key)
+        );
+        // satisfied, but there is no such key
+        ctx.solved.insert(2);
+        assert_eq!(store.get(&EncKeyId::ProblemSolved(2), &ctx).await, None);
+    }
+}
@@ -1,74 +1,119 @@
 #![allow(dead_code)]
 
-#[cfg(all(feature = "server", feature = "client"))]
-compile_error!(
-    "Features \"server\" and \"client\" are mutually exclusive and cannot be enabled together"
-);
-
 mod common;
 pub mod file;
+pub mod keys;
 mod message;
+pub mod queue;
 mod socket;
+mod stats;
+mod stream;
 
 pub use common::*;
+pub use ipnet::IpNet;
+use keys::EncKeyStore;
 pub use message::*;
-use rand::{thread_rng, Rng};
+use queue::QueueLog;
+use queue::{QueueStore, QUEUE_PARTS_TIMEOUT};
+use rand::Rng;
+use scc::hash_map::Entry;
 use scc::HashMap;
-#[cfg(feature = "server")]
 use scc::HashSet;
 use socket::*;
+pub use socket::{NetRng, RngSource};
+use speedy::{LittleEndian, Readable, Writable};
+use stats::DropReason;
+pub use stats::{MessageCounts, NetStats};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Weak};
 use std::time::{Duration, SystemTime};
-#[cfg(feature = "server")]
+use tokio::io::DuplexStream;
 use tokio::join;
+use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc;
 use tokio::task;
 use tokio::task::AbortHandle;
 use tokio::time::sleep;
 use tracing::*;
 
-#[cfg(feature = "client")]
-/// messages delivered to a client
+/// messages delivered by Net::recv, which ones depends on the entity of the node
 pub enum RecvMessage {
+    // to the server
+    NewConnection(PeerAddr),
+    Submission(SubmissionMessage),
+    Question(QuestionMessage),
+    Evaluation(EvaluationMessage),
+    // to the others
     Queue(QueueMessage),
     File(FileMessage),
-    Request(RequestMessage),
     EncKey(EncKeyInfo),
+    ChunkHashes(ChunkHashesMessage),
+    // to anyone
+    Request(RequestMessage),
 }
-#[cfg(feature = "client")]
-/// messages sent from a client
+/// messages sent with Net::send
 pub enum SendMessage {
-    File(FileMessage),
-    Request(RequestMessage),
+    // from the server
+    Queue(QueueMessage),
+    EncKey(EncKeyInfo),
+    // from the others
     Submission(SubmissionMessage),
     Question(QuestionMessage),
+    Evaluation(EvaluationMessage),
+    // from anyone
+    File(FileMessage),
+    ChunkHashes(ChunkHashesMessage),
+    Request(RequestMessage),
 }
 
-#[cfg(feature = "server")]
-/// messages delivered to the server
-pub enum RecvMessage {
-    NewConnection(PeerAddr),
-    Request(RequestMessage),
-    Submission(SubmissionMessage),
-    Question(QuestionMessage),
+/// why a message could not be sent
+#[derive(Debug)]
+pub enum SendError {
+    /// there is no connection to the peer, it has to be established (again) first
+    NotConnected,
+    /// the address of the peer is not known
+    UnknownAddr,
+    Io(std::io::Error),
+    /// the message does not fit in a datagram, or a field of it is too long
+    MessageTooLarge,
+    /// only the server can send the message
+    NotAllowed,
 }
-#[cfg(feature = "server")]
-/// messages sent from the server
-pub enum SendMessage {
-    Queue(QueueMessage),
-    File(FileMessage),
-    EncKey(EncKeyInfo),
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotConnected => write!(f, "there is no connection to the peer"),
+            Self::UnknownAddr => write!(f, "the address of the peer is unknown"),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::MessageTooLarge => write!(f, "the message is too large"),
+            Self::NotAllowed => write!(f, "only the server can send the message"),
+        }
+    }
+}
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<std::io::Error> for SendError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+// speedy fails to write only if a length limit is exceeded
+impl From<speedy::Error> for SendError {
+    fn from(_e: speedy::Error) -> Self {
+        Self::MessageTooLarge
+    }
 }
 
-#[cfg(not(any(feature = "server", feature = "client")))]
-pub enum RecvMessage {}
-#[cfg(not(any(feature = "server", feature = "client")))]
-pub enum SendMessage {}
-
-#[cfg(feature = "server")]
 pub enum WBList<T: std::hash::Hash + std::cmp::Eq> {
     Whitelist(HashSet<T>),
     Blacklist(HashSet<T>),
 }
-#[cfg(feature = "server")]
 impl<T: std::hash::Hash + std::cmp::Eq> WBList<T> {
     async fn accept(&self, t: &T) -> bool {
         match self {
@@ -76,6 +121,26 @@ impl<T: std::hash::Hash + std::cmp::Eq> WBList<T> {
             Self::Blacklist(s) => !s.contains_async(t).await,
         }
     }
+    async fn allow(&self, t: T) {
+        match self {
+            Self::Whitelist(s) => {
+                let _ = s.insert_async(t).await;
+            }
+            Self::Blacklist(s) => {
+                s.remove_async(&t).await;
+            }
+        }
+    }
+    async fn deny(&self, t: T) {
+        match self {
+            Self::Whitelist(s) => {
+                s.remove_async(&t).await;
+            }
+            Self::Blacklist(s) => {
+                let _ = s.insert_async(t).await;
+            }
+        }
+    }
     fn new_accept_all() -> Self {
         Self::Blacklist(HashSet::new())
     }
@@ -83,45 +148,79 @@ impl<T: std::hash::Hash + std::cmp::Eq> WBList<T> {
         Self::Whitelist(HashSet::new())
     }
 }
-#[cfg(feature = "server")]
+impl WBList<IpNet> {
+    /// whitelist: ip is in any of the ranges, blacklist: ip is in none of the ranges
+    async fn accept_ip(&self, ip: &IpAddr) -> bool {
+        match self {
+            Self::Whitelist(s) => s.any_async(|net| net.contains(ip)).await,
+            Self::Blacklist(s) => !s.any_async(|net| net.contains(ip)).await,
+        }
+    }
+}
+/// a peer is accepted only if all of psk_list, addr_list and range_list accept it
 pub struct SingleFilter {
     psk_list: WBList<PubSigKey>,
     addr_list: WBList<PeerAddr>,
+    range_list: WBList<IpNet>,
 }
-#[cfg(feature = "server")]
 impl SingleFilter {
     async fn accept(&self, psk: &PubSigKey, addr: &PeerAddr) -> bool {
-        let (accept_psk, accept_addr) =
-            join!(self.psk_list.accept(psk), self.addr_list.accept(addr));
-        accept_psk && accept_addr
+        let ip = addr.ip();
+        let (accept_psk, accept_addr, accept_range) = join!(
+            self.psk_list.accept(psk),
+            self.addr_list.accept(addr),
+            self.range_list.accept_ip(&ip)
+        );
+        accept_psk && accept_addr && accept_range
+    }
+    async fn allow_range(&self, range: IpNet) {
+        self.range_list.allow(range).await;
+    }
+    async fn ban_range(&self, range: IpNet) {
+        self.range_list.deny(range).await;
+    }
+    async fn allow_psk(&self, psk: PubSigKey) {
+        self.psk_list.allow(psk).await;
+    }
+    async fn ban_psk(&self, psk: PubSigKey) {
+        self.psk_list.deny(psk).await;
+    }
+    async fn allow_addr(&self, addr: PeerAddr) {
+        self.addr_list.allow(addr).await;
+    }
+    async fn ban_addr(&self, addr: PeerAddr) {
+        self.addr_list.deny(addr).await;
     }
     fn new_psk(psk_white_list: HashSet<PubSigKey>) -> Self {
         Self {
             psk_list: WBList::<PubSigKey>::Whitelist(psk_white_list),
             addr_list: WBList::<PeerAddr>::new_accept_all(),
+            range_list: WBList::<IpNet>::new_accept_all(),
         }
     }
     fn new_accept_all() -> Self {
         Self {
             psk_list: WBList::<PubSigKey>::new_accept_all(),
             addr_list: WBList::<PeerAddr>::new_accept_all(),
+            range_list: WBList::<IpNet>::new_accept_all(),
         }
     }
     fn new_reject_all() -> Self {
         Self {
             psk_list: WBList::<PubSigKey>::new_reject_all(),
             addr_list: WBList::<PeerAddr>::new_reject_all(),
+            // already rejected by the other lists, ranges can only restrict further
+            range_list: WBList::<IpNet>::new_accept_all(),
         }
     }
 }
-#[cfg(feature = "server")]
+/// which peers can connect to us, as which entity
 pub struct Filter {
     server: SingleFilter,
     worker: SingleFilter,
     participant: SingleFilter,
     spectator: SingleFilter,
 }
-#[cfg(feature = "server")]
 impl Filter {
     async fn accept(&self, psk: &PubSigKey, addr: &PeerAddr, entity: Entity) -> bool {
         match entity {
@@ -131,6 +230,46 @@ impl Filter {
             Entity::Spectator => self.spectator.accept(psk, addr).await,
         }
     }
+    fn get(&self, entity: Entity) -> &SingleFilter {
+        match entity {
+            Entity::Server => &self.server,
+            Entity::Worker => &self.worker,
+            Entity::Participant => &self.participant,
+            Entity::Spectator => &self.spectator,
+        }
+    }
+    fn all(&self) -> [&SingleFilter; 4] {
+        [
+            &self.server,
+            &self.worker,
+            &self.participant,
+            &self.spectator,
+        ]
+    }
+    pub async fn allow_psk(&self, psk: PubSigKey, entity: Entity) {
+        self.get(entity).allow_psk(psk).await;
+    }
+    pub async fn ban_psk(&self, psk: PubSigKey) {
+        for f in self.all() {
+            f.ban_psk(psk).await;
+        }
+    }
+    pub async fn allow_addr(&self, addr: PeerAddr, entity: Entity) {
+        self.get(entity).allow_addr(addr).await;
+    }
+    pub async fn ban_addr(&self, addr: PeerAddr) {
+        for f in self.all() {
+            f.ban_addr(addr).await;
+        }
+    }
+    pub async fn allow_range(&self, range: IpNet, entity: Entity) {
+        self.get(entity).allow_range(range).await;
+    }
+    pub async fn ban_range(&self, range: IpNet) {
+        for f in self.all() {
+            f.ban_range(range).await;
+        }
+    }
     pub fn open_server(worker_white_list: HashSet<PubSigKey>) -> Self {
         Self {
             server: SingleFilter::new_reject_all(),
@@ -139,58 +278,111 @@ impl Filter {
             spectator: SingleFilter::new_accept_all(),
         }
     }
+    /// accepts no connections, eg for participants that only connect to others
+    pub fn reject_all() -> Self {
+        Self {
+            server: SingleFilter::new_reject_all(),
+            worker: SingleFilter::new_reject_all(),
+            participant: SingleFilter::new_reject_all(),
+            spectator: SingleFilter::new_reject_all(),
+        }
+    }
 }
 
-#[cfg(feature = "client")]
-pub struct Filter {}
-#[cfg(feature = "client")]
-impl Filter {
-    async fn accept(&self, _psk: &PubSigKey, _addr: &PeerAddr, _entity: Entity) -> bool {
-        false
+/// range from which the delay between keepalives is picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAliveInterval {
+    pub min: Duration,
+    pub max: Duration,
+}
+impl Default for KeepAliveInterval {
+    fn default() -> Self {
+        Self {
+            min: Duration::from_millis(250),
+            max: Duration::from_millis(25000),
+        }
     }
 }
-#[cfg(not(any(feature = "client", feature = "server")))]
-pub struct Filter {}
-#[cfg(not(any(feature = "client", feature = "server")))]
-impl Filter {
-    async fn accept(&self, _psk: &PubSigKey, _addr: &PeerAddr, _entity: Entity) -> bool {
-        false
+impl KeepAliveInterval {
+    // random delay before the first keepalive,
+    // so that connections started together don't keep sending at the same time
+    fn phase(&self, rng: &RngSource) -> Duration {
+        rng.with(|rng| rng.gen_range(Duration::ZERO..=self.max))
     }
 }
 
+// the reported address may be unspecified if the socket is bound to all interfaces
+fn is_behind_nat(reported: PeerAddr, observed: PeerAddr) -> bool {
+    reported.port() != observed.port()
+        || (!reported.ip().is_unspecified() && reported.ip() != observed.ip())
+}
+
 // TODO: disable keepalive if public ip (?)
-async fn keepalive(socket: SocketWriter, dest_addr: PeerAddr, mac_key: MacKey) {
-    const KEEPALIVE_MSG_SIZE: usize = 13;
-    let mut buf = [0u8; KEEPALIVE_MSG_SIZE];
-    const KA_DELAY_MIN: Duration = Duration::from_millis(250);
-    const KA_DELAY_MAX: Duration = Duration::from_millis(25000);
+async fn keepalive(transmitter: Transmitter, psk: PubSigKey, interval: KeepAliveInterval) {
+    sleep(interval.phase(transmitter.socket.rng())).await;
     loop {
-        let message = Message::Net(NetMessage::KeepAlive(
-            socket.psk(),
-            Macced::new(KeepAliveInner(SystemTime::now()), &mac_key),
-        ));
-        let interval = if socket.send_to(message, dest_addr, &mut buf).await.is_ok() {
-            thread_rng().gen_range(KA_DELAY_MIN..=KA_DELAY_MAX)
+        let message = Outgoing::KeepAlive(KeepAliveInner(SystemTime::now()));
+        let delay = if transmitter.send(psk, message).await.is_ok() {
+            transmitter
+                .socket
+                .rng()
+                .with(|rng| rng.gen_range(interval.min..=interval.max))
         } else {
-            KA_DELAY_MIN
+            interval.min
         };
-        sleep(interval).await;
+        sleep(delay).await;
+    }
+}
+/// when the mac key of a connection is replaced by a fresh key exchange,
+/// whichever comes first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    pub interval: Duration,
+    pub messages: u64, // sent with the current key
+}
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(3600),
+            messages: 1 << 24,
+        }
     }
 }
+// after a rekey, messages with the previous key are still accepted for this long,
+// since the peer may not have the new key yet
+const REKEY_GRACE: Duration = Duration::from_secs(10);
+
 struct Connection {
+    psk: PubSigKey,
     ka_ah: Option<AbortHandle>,
+    ka_interval: KeepAliveInterval,
+    last_seen: SystemTime,
     addr: PeerAddr,
-    mac_key: MacKey,
-    socket: SocketWriter,
+    mac_keys: MacKeys,
+    // recv key replaced by the last rekey
+    prev_recv_key: Option<MacKey>,
+    // pkk the peer sent in the exchange mac_keys come from
+    peer_pkk: Option<PubKexKey>,
+    // when mac_keys were set, and how many messages were sent with them
+    keyed_at: SystemTime,
+    sent: u64,
+    transmitter: Transmitter,
+    // entity the peer connected as
+    entity: Entity,
+    // address the peer says its socket is bound to
+    reported_addr: Option<PeerAddr>,
+    // smoothed round trip time, from keepalive acks
+    rtt: Option<Duration>,
 }
 impl Connection {
     pub async fn start_ka(&mut self) {
         self.abort_ka().await;
         self.ka_ah = Some({
-            let socket = self.socket.clone();
-            let addr = self.addr;
-            let mac_key = self.mac_key;
-            tokio::task::spawn(async move { keepalive(socket, addr, mac_key).await }).abort_handle()
+            let transmitter = self.transmitter.clone();
+            let psk = self.psk;
+            let interval = self.ka_interval;
+            tokio::task::spawn(async move { keepalive(transmitter, psk, interval).await })
+                .abort_handle()
         });
     }
     async fn abort_ka(&mut self) {
@@ -198,23 +390,244 @@ impl Connection {
             ah.abort();
         }
     }
-    pub fn new(addr: PeerAddr, mac_key: MacKey, socket: SocketWriter) -> Self {
+    pub fn new(
+        psk: PubSigKey,
+        addr: PeerAddr,
+        mac_keys: MacKeys,
+        transmitter: Transmitter,
+        ka_interval: KeepAliveInterval,
+        entity: Entity,
+    ) -> Self {
         Self {
+            psk,
             ka_ah: None,
+            ka_interval,
+            last_seen: SystemTime::now(),
             addr,
-            mac_key,
-            socket,
+            mac_keys,
+            prev_recv_key: None,
+            peer_pkk: None,
+            keyed_at: SystemTime::now(),
+            sent: 0,
+            transmitter,
+            entity,
+            reported_addr: None,
+            rtt: None,
+        }
+    }
+    /// changes the keepalive interval, restarting the keepalive if it was running
+    pub async fn set_ka_interval(&mut self, ka_interval: KeepAliveInterval) {
+        self.ka_interval = ka_interval;
+        if self.ka_ah.is_some() {
+            self.start_ka().await;
         }
     }
-    pub fn mac_key(&self) -> MacKey {
-        self.mac_key
+    pub fn mac_keys(&self) -> MacKeys {
+        self.mac_keys
     }
     pub fn addr(&self) -> PeerAddr {
         self.addr
     }
-    pub fn set_addr_mackey(&mut self, addr: PeerAddr, mac_key: MacKey) {
+    /// mac key to send a message with
+    fn use_mac_key(&mut self) -> MacKey {
+        self.sent += 1;
+        self.mac_keys.send
+    }
+    /// keys a received message can be macced with, the current one first
+    fn recv_mac_keys(&self) -> [MacKey; 2] {
+        let in_grace = SystemTime::now()
+            .duration_since(self.keyed_at)
+            .unwrap_or_default()
+            < REKEY_GRACE;
+        match self.prev_recv_key {
+            Some(prev) if in_grace => [self.mac_keys.recv, prev],
+            _ => [self.mac_keys.recv; 2],
+        }
+    }
+    fn needs_rekey(&self, policy: &RekeyPolicy) -> bool {
+        self.sent >= policy.messages
+            || SystemTime::now()
+                .duration_since(self.keyed_at)
+                .unwrap_or_default()
+                >= policy.interval
+    }
+    pub fn set_addr_mackeys(&mut self, addr: PeerAddr, mac_keys: MacKeys) {
         self.addr = addr;
-        self.mac_key = mac_key;
+        if mac_keys != self.mac_keys {
+            self.prev_recv_key = Some(self.mac_keys.recv);
+            self.mac_keys = mac_keys;
+            self.keyed_at = SystemTime::now();
+            self.sent = 0;
+        }
+    }
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
+    pub fn set_entity(&mut self, entity: Entity) {
+        self.entity = entity;
+    }
+    pub fn reported_addr(&self) -> Option<PeerAddr> {
+        self.reported_addr
+    }
+    /// round trip time to the peer, None until a keepalive we sent is answered
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+    fn add_rtt_sample(&mut self, sample: Duration) {
+        // same smoothing as tcp
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt * 7 / 8 + sample / 8,
+            None => sample,
+        });
+    }
+    /// last time a valid message was received from the peer
+    pub fn last_seen(&self) -> SystemTime {
+        self.last_seen
+    }
+    fn seen(&mut self) {
+        self.last_seen = SystemTime::now();
+    }
+    fn is_idle(&self, idle_timeout: Duration) -> bool {
+        SystemTime::now()
+            .duration_since(self.last_seen)
+            .unwrap_or_default()
+            > idle_timeout
+    }
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(ah) = self.ka_ah.take() {
+            ah.abort();
+        }
+    }
+}
+
+/// messages accepted recently, so that replays are dropped even while their timestamp is valid
+struct ReplayCache {
+    inner: std::sync::Mutex<ReplayCacheInner>,
+}
+#[derive(Default)]
+struct ReplayCacheInner {
+    seen: std::collections::HashSet<(PubSigKey, [u8; 32])>,
+    // the same entries, oldest timestamp first, the counter tells apart equal timestamps
+    by_time: std::collections::BTreeMap<(Timestamp, u64), (PubSigKey, [u8; 32])>,
+    counter: u64,
+}
+impl ReplayCache {
+    const MAX_ENTRIES: usize = 1 << 16;
+    fn new() -> Self {
+        Self {
+            inner: Default::default(),
+        }
+    }
+    /// returns false if the message with the given digest was already accepted from psk
+    fn check(
+        &self,
+        psk: PubSigKey,
+        digest: [u8; 32],
+        timestamp: Timestamp,
+        window: &TimestampWindow,
+    ) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        if inner.seen.contains(&(psk, digest)) {
+            return false;
+        }
+        // messages with an expired timestamp are dropped anyways,
+        // and when full the oldest one goes
+        while let Some(entry) = inner.by_time.first_entry() {
+            if is_timestamp_valid(entry.key().0, window) && inner.seen.len() < Self::MAX_ENTRIES {
+                break;
+            }
+            inner.seen.remove(&entry.remove());
+        }
+        inner.seen.insert((psk, digest));
+        inner
+            .by_time
+            .insert((timestamp, inner.counter), (psk, digest));
+        inner.counter += 1;
+        true
+    }
+}
+
+enum Bind {
+    Addr(SocketAddr),
+    DualStack(u16),
+}
+/// configuration of a Net, made by Net::builder
+pub struct NetBuilder {
+    signer: Arc<dyn Signer>,
+    entity: Entity,
+    contest_id: ContestId,
+    inbound_connection_filter: Filter,
+    bind: Bind,
+    obfuscation_key: ObfuscationKey,
+    timestamp_window: TimestampWindow,
+    ka_interval: KeepAliveInterval,
+    rng: RngSource,
+    server_psk: Option<PubSigKey>,
+    send_queue: Option<usize>,
+}
+impl NetBuilder {
+    /// address to bind to (eg to use a port that is forwarded), 0.0.0.0:0 by default
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind = Bind::Addr(addr);
+        self
+    }
+    /// binds to [::]:port instead, to talk to both ipv4 and ipv6 peers
+    pub fn dual_stack(mut self, port: u16) -> Self {
+        self.bind = Bind::DualStack(port);
+        self
+    }
+    /// all peers in the contest must use the same one (eg ObfuscationKey::from_contest_id)
+    pub fn obfuscation_key(mut self, key: ObfuscationKey) -> Self {
+        self.obfuscation_key = key;
+        self
+    }
+    /// how far off the timestamp of kex and keepalive messages can be
+    pub fn timestamp_window(mut self, window: TimestampWindow) -> Self {
+        self.timestamp_window = window;
+        self
+    }
+    /// keepalive interval of the connections without one set by Net::set_keepalive_interval
+    pub fn keepalive_interval(mut self, ka_interval: KeepAliveInterval) -> Self {
+        self.ka_interval = ka_interval;
+        self
+    }
+    /// randomness for key exchanges, nonces and jitter, thread_rng by default,
+    /// a seeded rng makes them reproducible (for tests only)
+    pub fn rng(mut self, rng: impl NetRng + Send + 'static) -> Self {
+        self.rng = RngSource::new(rng);
+        self
+    }
+    /// the server of the contest, whose signature queue messages must carry,
+    /// without it no queue messages are received (the server itself doesn't need it)
+    pub fn server_psk(mut self, psk: PubSigKey) -> Self {
+        self.server_psk = Some(psk);
+        self
+    }
+    /// sends to each peer go through a queue of capacity messages, sent by a task,
+    /// so that they leave in the order send was called even when called from many tasks,
+    /// Net::send returns once the message is queued, and waits while the queue is full
+    pub fn send_queue(mut self, capacity: usize) -> Self {
+        self.send_queue = Some(capacity);
+        self
+    }
+    /// errors if the socket cannot be bound
+    pub async fn build(self) -> anyhow::Result<Net> {
+        let (sr, sw) = match self.bind {
+            Bind::Addr(addr) => new_socket(addr, self.entity, self.signer, self.contest_id).await?,
+            Bind::DualStack(port) => {
+                new_dual_stack_socket(port, self.entity, self.signer, self.contest_id).await?
+            }
+        };
+        let mut net = Net::from_socket(sr, sw.with_rng(self.rng), self.inbound_connection_filter);
+        net.obfuscation_key = self.obfuscation_key;
+        net.timestamp_window = self.timestamp_window;
+        net.default_ka_interval = self.ka_interval;
+        net.server_psk = self.server_psk;
+        net.send_queue_capacity = self.send_queue;
+        Ok(net)
     }
 }
 
@@ -223,157 +636,820 @@ pub struct Net {
     sr: SocketReader,
     addr_to_psk: HashMap<PeerAddr, PubSigKey>,
     psk_to_addr: HashMap<PubSigKey, PeerAddr>,
-    initting: HashMap<(PubSigKey, PeerAddr), (Option<SecKexKey>, AbortHandle)>,
-    connections: HashMap<PubSigKey, Connection>,
+    // own kex key, peer kex key the connection was finalized with, task sending the merkle
+    initting: HashMap<(PubSigKey, PeerAddr), (SecKexKey, Option<PubKexKey>, AbortHandle)>,
+    connections: Arc<HashMap<PubSigKey, Connection>>,
+    streams: HashMap<(PubSigKey, StreamId), (mpsc::Sender<StreamMessage>, AbortHandle)>,
+    // reaper and rekeyer
+    background: std::sync::Mutex<Vec<AbortHandle>>,
+    // messages waiting to be sent to each peer, if sends are queued
+    send_queues: Arc<HashMap<PubSigKey, mpsc::Sender<(PubSigKey, Outgoing)>>>,
+    send_queue_capacity: Option<usize>,
     keepalivers: HashMap<PubSigKey, u32>,
+    ka_intervals: HashMap<PubSigKey, KeepAliveInterval>,
+    default_ka_interval: KeepAliveInterval,
+    kex_attempts: HashMap<PeerAddr, (SystemTime, u32)>,
+    replay_cache: ReplayCache,
+    timestamp_window: TimestampWindow,
+    obfuscation_key: ObfuscationKey,
     inbound_connection_filter: Filter,
+    // the one whose queue messages are accepted, unless this is the server
+    server_psk: Option<PubSigKey>,
+    queue_store: QueueStore,
+    queue_log: QueueLog,
 }
 impl Net {
+    /// signer can be a SecSigKey
     pub async fn new(
-        ssk: SecSigKey,
+        signer: impl Signer + 'static,
         entity: Entity,
         contest_id: ContestId,
         inbound_connection_filter: Filter,
     ) -> Self {
-        let (sr, sw) = new_socket("0.0.0.0:0", entity, ssk, contest_id)
+        Self::builder(signer, entity, contest_id, inbound_connection_filter)
+            .build()
             .await
-            .unwrap();
+            .unwrap()
+    }
+    /// the identity and the filter are required, the rest of the configuration has defaults,
+    /// see NetBuilder
+    pub fn builder(
+        signer: impl Signer + 'static,
+        entity: Entity,
+        contest_id: ContestId,
+        inbound_connection_filter: Filter,
+    ) -> NetBuilder {
+        NetBuilder {
+            signer: Arc::new(signer),
+            entity,
+            contest_id,
+            inbound_connection_filter,
+            bind: Bind::Addr(SocketAddr::from(([0, 0, 0, 0], 0))),
+            obfuscation_key: ObfuscationKey::default(),
+            timestamp_window: TimestampWindow::default(),
+            ka_interval: KeepAliveInterval::default(),
+            rng: RngSource::default(),
+            server_psk: None,
+            send_queue: None,
+        }
+    }
+    /// like new, but binds to the given address (eg to use a port that is forwarded)
+    pub async fn new_with_addr(
+        addr: impl ToSocketAddrs,
+        signer: impl Signer + 'static,
+        entity: Entity,
+        contest_id: ContestId,
+        inbound_connection_filter: Filter,
+    ) -> anyhow::Result<Self> {
+        let addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or(anyhow::anyhow!("could not resolve the bind address"))?;
+        Self::builder(signer, entity, contest_id, inbound_connection_filter)
+            .bind_addr(addr)
+            .build()
+            .await
+    }
+    /// like new, but binds to [::]:port and can talk to both ipv4 and ipv6 peers
+    pub async fn new_dual_stack(
+        port: u16,
+        signer: impl Signer + 'static,
+        entity: Entity,
+        contest_id: ContestId,
+        inbound_connection_filter: Filter,
+    ) -> anyhow::Result<Self> {
+        Self::builder(signer, entity, contest_id, inbound_connection_filter)
+            .dual_stack(port)
+            .build()
+            .await
+    }
+    fn from_socket(sr: SocketReader, sw: SocketWriter, inbound_connection_filter: Filter) -> Self {
         Self {
             sw,
             sr,
             psk_to_addr: HashMap::new(),
             addr_to_psk: HashMap::new(),
             initting: HashMap::new(),
-            connections: HashMap::new(),
+            connections: Arc::new(HashMap::new()),
+            streams: HashMap::new(),
+            background: std::sync::Mutex::new(vec![]),
+            send_queues: Arc::new(HashMap::new()),
+            send_queue_capacity: None,
             keepalivers: HashMap::new(),
+            ka_intervals: HashMap::new(),
+            default_ka_interval: KeepAliveInterval::default(),
+            kex_attempts: HashMap::new(),
+            replay_cache: ReplayCache::new(),
+            timestamp_window: TimestampWindow::default(),
+            obfuscation_key: ObfuscationKey::default(),
             inbound_connection_filter,
+            server_psk: None,
+            queue_store: QueueStore::new(),
+            queue_log: QueueLog::new(),
         }
     }
+    /// key that identifies this node, eg to be whitelisted by the organizer
     pub fn psk(&self) -> PubSigKey {
         self.sw.psk()
     }
-    async fn handle_net_message(&self, m: NetMessage, peer_addr: PeerAddr) {
+    /// address the socket is bound to, not necessarily the one seen by peers behind a nat
+    pub fn own_addr(&self) -> anyhow::Result<PeerAddr> {
+        self.sw.own_addr()
+    }
+    /// the randomness given to the builder, eg for the keys of the files this peer adds
+    pub fn rng(&self) -> &RngSource {
+        self.sw.rng()
+    }
+    /// sets the key used to obfuscate addresses, all peers in the contest must use the same one
+    /// (eg ObfuscationKey::from_contest_id)
+    pub fn with_obfuscation_key(mut self, key: ObfuscationKey) -> Self {
+        self.obfuscation_key = key;
+        self
+    }
+    pub fn obfuscation_key(&self) -> ObfuscationKey {
+        self.obfuscation_key
+    }
+    /// sets how far off the timestamp of kex and keepalive messages can be,
+    /// tighter makes replays harder, looser tolerates worse clocks and links
+    pub fn with_timestamp_window(mut self, window: TimestampWindow) -> Self {
+        self.timestamp_window = window;
+        self
+    }
+    pub fn timestamp_window(&self) -> TimestampWindow {
+        self.timestamp_window
+    }
+    async fn handle_net_message(
+        &self,
+        m: NetMessage,
+        peer_addr: PeerAddr,
+    ) -> Result<(), DropReason> {
         match m {
             NetMessage::Merkle(s) => {
                 let peer_id = s.who();
-                if let Some((
-                    (contest_id, timestamp, peer_pkk, Obfuscated(_peer_addr_local), entity),
-                    peer_id,
-                )) = s.inner(&peer_id)
+                let digest = blake3::hash(&s.signature().to_bytes()).into();
+                let Some(((contest_id, timestamp, peer_pkk, peer_addr_local, entity), peer_id)) =
+                    s.inner(&peer_id)
+                else {
+                    return Err(DropReason::BadSignature);
+                };
+                if !is_timestamp_valid(timestamp, &self.timestamp_window)
+                    || self.sw.contest_id() != contest_id
                 {
-                    if is_timestamp_valid(timestamp)
-                        && self.sw.contest_id() == contest_id
-                        && (self.initting.contains_async(&(peer_id, peer_addr)).await
-                            || self
-                                .inbound_connection_filter
-                                .accept(&peer_id, &peer_addr, entity)
-                                .await)
-                    {
-                        // finalize connection
-                        let Some(skk) = self
-                            .initting
-                            .entry_async((peer_id, peer_addr))
-                            .await
-                            .or_insert(new_initting(self.sw.clone(), peer_addr).await)
-                            .get_mut()
-                            .0
-                            .take()
-                        else {
-                            // skk is only taken in this function,
-                            // if it's None it means it was already finalized
-                            return;
-                        };
-                        let mac_key = MacKey::from(skk.diffie_hellman(&peer_pkk.into()));
-
-                        let mut occupied = self
-                            .connections
-                            .entry_async(peer_id)
-                            .await
-                            .or_insert(Connection::new(peer_addr, mac_key, self.sw.clone()));
-                        let c = occupied.get_mut();
-                        c.set_addr_mackey(peer_addr, mac_key);
-                        c.abort_ka().await;
-
-                        if *self
-                            .keepalivers
-                            .entry_async(peer_id)
-                            .await
-                            .or_insert(0)
-                            .get()
-                            > 0
-                        {
-                            c.start_ka().await;
+                    return Err(DropReason::Invalid);
+                }
+                let initting = self.initting.contains_async(&(peer_id, peer_addr)).await;
+                // if connected this is a rekey, the peer was accepted already
+                let (connected, done) =
+                    self.connections
+                        .get_async(&peer_id)
+                        .await
+                        .map_or((false, false), |c| {
+                            let c = c.get();
+                            (c.addr() == peer_addr, c.peer_pkk == Some(peer_pkk))
+                        });
+                if done && !initting {
+                    // a retransmission of an exchange that completed already
+                    return Ok(());
+                }
+                if !initting
+                    && !connected
+                    && !self
+                        .inbound_connection_filter
+                        .accept(&peer_id, &peer_addr, entity)
+                        .await
+                {
+                    return Err(DropReason::Filtered);
+                }
+                if !self
+                    .replay_cache
+                    .check(peer_id, digest, timestamp, &self.timestamp_window)
+                {
+                    return Err(DropReason::Replayed);
+                }
+                if !initting && !self.allow_kex_attempt(peer_addr).await {
+                    return Err(DropReason::RateLimited);
+                }
+                // finalize connection
+                let mut occupied = match self.initting.entry_async((peer_id, peer_addr)).await {
+                    Entry::Occupied(mut o) => {
+                        if o.get().2.is_finished() {
+                            // we gave up before the peer answered, or it's a rekey after
+                            // we stopped sending our pkk, start over
+                            *o.get_mut() =
+                                new_initting(self.sw.clone(), peer_addr, self.obfuscation_key)
+                                    .await;
                         }
+                        o
                     }
+                    Entry::Vacant(v) => v.insert_entry(
+                        new_initting(self.sw.clone(), peer_addr, self.obfuscation_key).await,
+                    ),
+                };
+                let (skk, finalized_with, _ah) = occupied.get_mut();
+                if *finalized_with == Some(peer_pkk) {
+                    // a retransmission of the merkle we already finalized with
+                    return Ok(());
+                }
+                // if the peer restarted its side of the exchange (eg it gave up before
+                // receiving our merkle) it derived its key from the new pkk and ours,
+                // the skk is kept until the kex completes so we can do the same
+                if finalized_with.is_some() {
+                    debug!("{} restarted the key exchange", peer_id);
+                }
+                *finalized_with = Some(peer_pkk);
+                let mac_keys = MacKeys::from_kex(
+                    skk.diffie_hellman(&peer_pkk.into()),
+                    (self.psk(), PubKexKey::from(&*skk)),
+                    (peer_id, peer_pkk),
+                );
+                drop(occupied);
+                let reported_addr = peer_addr_local.inner(&self.obfuscation_key);
+                match reported_addr {
+                    Some(reported) if is_behind_nat(reported, peer_addr) => debug!(
+                        "{} is behind a nat: reported {:?}, seen as {:?}",
+                        peer_id, reported, peer_addr
+                    ),
+                    Some(_) => {}
+                    None => debug!("{} uses a different obfuscation key", peer_id),
                 }
+                self.finalize_connection(
+                    peer_id,
+                    peer_addr,
+                    (mac_keys, Some(peer_pkk)),
+                    entity,
+                    reported_addr,
+                )
+                .await;
             }
             NetMessage::KeepAlive(peer_id, macced) => {
-                if let Some(mac_key) = self
+                let Some((recv_keys, send_key)) = self
                     .connections
                     .get_async(&peer_id)
                     .await
-                    .map(|x| x.get().mac_key())
+                    .map(|x| (x.get().recv_mac_keys(), x.get().mac_keys().send))
+                else {
+                    return Err(DropReason::UnknownPeer);
+                };
+                let digest = *macced.mac().0.as_bytes();
+                // only a keepalive with the current key shows the peer has it too
+                let current = macced.check(&recv_keys[0]);
+                let Some(timestamp) = macced.inner_any(&recv_keys) else {
+                    return Err(DropReason::MacFailed);
+                };
+                if !is_timestamp_valid(timestamp.0, &self.timestamp_window) {
+                    return Err(DropReason::Invalid);
+                }
+                if !self
+                    .replay_cache
+                    .check(peer_id, digest, timestamp.0, &self.timestamp_window)
                 {
-                    if let Some(timestamp) = macced.inner(&mac_key) {
-                        if is_timestamp_valid(timestamp.0) {
-                            if let Some(entry) =
-                                self.initting.get_async(&(peer_id, peer_addr)).await
-                            {
-                                if entry.get().0.is_none() {
-                                    let (_k, (_s, ah)) = entry.remove_entry();
-                                    ah.abort();
-                                } else {
-                                    warn!("A connection is re-establishing very quickly(?)");
-                                }
-                            }
-                        }
+                    return Err(DropReason::Replayed);
+                }
+                self.seen(peer_id).await;
+                if let Some(entry) = self
+                    .initting
+                    .get_async(&(peer_id, peer_addr))
+                    .await
+                    .filter(|_| current)
+                {
+                    if entry.get().1.is_some() {
+                        let (_k, (_skk, _pkk, ah)) = entry.remove_entry();
+                        ah.abort();
+                    } else {
+                        warn!("A connection is re-establishing very quickly(?)");
                     }
                 }
+                let ack = Message::Net(NetMessage::KeepAliveAck(
+                    self.psk(),
+                    Macced::new(KeepAliveAckInner(timestamp.0, SystemTime::now()), &send_key),
+                ));
+                let _ = self.sw.send_to(ack, peer_addr).await;
+            }
+            NetMessage::KeepAliveAck(peer_id, macced) => {
+                let Some(mut c) = self.connections.get_async(&peer_id).await else {
+                    return Err(DropReason::UnknownPeer);
+                };
+                let digest = *macced.mac().0.as_bytes();
+                let Some(KeepAliveAckInner(sent, timestamp)) =
+                    macced.inner_any(&c.get().recv_mac_keys())
+                else {
+                    return Err(DropReason::MacFailed);
+                };
+                if !is_timestamp_valid(timestamp, &self.timestamp_window) {
+                    return Err(DropReason::Invalid);
+                }
+                // sent is our own time, so it's fine to compare it with now
+                let Ok(rtt) = SystemTime::now().duration_since(sent) else {
+                    return Err(DropReason::Invalid);
+                };
+                if !self
+                    .replay_cache
+                    .check(peer_id, digest, timestamp, &self.timestamp_window)
+                {
+                    return Err(DropReason::Replayed);
+                }
+                c.get_mut().seen();
+                c.get_mut().add_rtt_sample(rtt);
+            }
+            NetMessage::Rendezvous(peer_id, macced) => {
+                let Some((mac_key, entity)) = self
+                    .connections
+                    .get_async(&peer_id)
+                    .await
+                    .map(|x| (x.get().mac_keys().recv, x.get().entity()))
+                else {
+                    return Err(DropReason::UnknownPeer);
+                };
+                if entity != Entity::Server {
+                    return Err(DropReason::Unexpected);
+                }
+                let digest = *macced.mac().0.as_bytes();
+                let Some((info, timestamp)) = macced.inner(&mac_key) else {
+                    return Err(DropReason::MacFailed);
+                };
+                if !is_timestamp_valid(timestamp, &self.timestamp_window) {
+                    return Err(DropReason::Invalid);
+                }
+                // the server sends each introduction a few times
+                if !self
+                    .replay_cache
+                    .check(peer_id, digest, timestamp, &self.timestamp_window)
+                {
+                    return Err(DropReason::Replayed);
+                }
+                self.seen(peer_id).await;
+                let Some(addr) = info.addr.inner(&self.obfuscation_key) else {
+                    return Err(DropReason::Invalid);
+                };
+                if info.psk == self.psk() || !addr.is_plausible() {
+                    return Err(DropReason::Invalid);
+                }
+                debug!("rendezvous with {} at {:?}", info.psk, addr);
+                self.update_peer_addr(info.psk, addr).await;
+                if !self.is_connected(info.psk).await {
+                    // the peer is doing the same, the first packets on each side open the nats
+                    // and the key exchange retries until the other side's packets get through
+                    self.start_initting(info.psk, addr).await;
+                }
             }
         }
+        Ok(())
+    }
+    /// there is at most one connection to each peer,
+    /// if the peer changed address it is moved to the new one
+    async fn finalize_connection(
+        &self,
+        peer_id: PubSigKey,
+        peer_addr: PeerAddr,
+        (mac_keys, peer_pkk): (MacKeys, Option<PubKexKey>),
+        entity: Entity,
+        reported_addr: Option<PeerAddr>,
+    ) {
+        let ka_interval = self.ka_interval(peer_id).await;
+        // the entry is held until the addresses are updated,
+        // so concurrent finalizes for the same peer don't interleave
+        let mut occupied = self
+            .connections
+            .entry_async(peer_id)
+            .await
+            .or_insert(Connection::new(
+                peer_id,
+                peer_addr,
+                mac_keys,
+                self.transmitter(),
+                ka_interval,
+                entity,
+            ));
+        let c = occupied.get_mut();
+        let old_addr = c.addr();
+        c.set_addr_mackeys(peer_addr, mac_keys);
+        c.peer_pkk = peer_pkk;
+        c.set_entity(entity);
+        c.reported_addr = reported_addr;
+        c.seen();
+        c.abort_ka().await;
+        if old_addr != peer_addr {
+            self.addr_to_psk
+                .remove_if_async(&old_addr, |psk| *psk == peer_id)
+                .await;
+        }
+        self.psk_to_addr
+            .entry_async(peer_id)
+            .await
+            .insert_entry(peer_addr);
+        self.addr_to_psk
+            .entry_async(peer_addr)
+            .await
+            .insert_entry(peer_id);
+        if *self
+            .keepalivers
+            .entry_async(peer_id)
+            .await
+            .or_insert(0)
+            .get()
+            > 0
+        {
+            c.start_ka().await;
+        }
+        drop(occupied);
+        // key exchanges with the peer at other addresses are not needed anymore
+        self.initting
+            .retain_async(|(ipsk, iaddr), (_skk, _pkk, ah)| {
+                if *ipsk == peer_id && *iaddr != peer_addr {
+                    ah.abort();
+                    false
+                } else {
+                    true
+                }
+            })
+            .await;
+    }
+    /// smoothed round trip time to psk, if connected and measured
+    pub async fn rtt(&self, psk: PubSigKey) -> Option<Duration> {
+        self.connections
+            .get_async(&psk)
+            .await
+            .and_then(|c| c.get().rtt())
+    }
+    /// address psk says its socket is bound to, as of the last key exchange,
+    /// if it differs from the one we see the peer is behind a nat
+    pub async fn reported_addr(&self, psk: PubSigKey) -> Option<PeerAddr> {
+        self.connections
+            .get_async(&psk)
+            .await
+            .and_then(|c| c.get().reported_addr())
+    }
+    /// checks the mac of a message received from addr,
+    /// returns its content and who sent it
+    async fn unmac<T: Writable<LittleEndian>>(
+        &self,
+        m: Macced<T>,
+        addr: PeerAddr,
+    ) -> Result<(T, PubSigKey), DropReason> {
+        let Some(psk) = self.addr_to_psk.get_async(&addr).await.map(|x| *x.get()) else {
+            return Err(DropReason::UnknownPeer);
+        };
+        let Some(mac_keys) = self
+            .connections
+            .get_async(&psk)
+            .await
+            .map(|x| x.get().recv_mac_keys())
+        else {
+            return Err(DropReason::UnknownPeer);
+        };
+        let Some(inner) = m.inner_any(&mac_keys) else {
+            return Err(DropReason::MacFailed);
+        };
+        self.seen(psk).await;
+        Ok((inner, psk))
+    }
+    fn drop_message(&self, reason: DropReason, addr: PeerAddr) {
+        self.sr.stats().dropped(reason);
+        debug!("dropping message from {:?}: {:?}", addr, reason);
+    }
+    /// counters of what was sent and received
+    pub fn stats(&self) -> NetStats {
+        self.sr.stats().snapshot()
     }
 
-    pub async fn update_peer_addr(&self, psk: PubSigKey, addr: PeerAddr) {
-        self.psk_to_addr.entry_async(psk).await.insert_entry(addr);
-        self.addr_to_psk.entry_async(addr).await.insert_entry(psk);
-        if let Some(mut oc) = self.connections.get_async(&psk).await {
-            let c = oc.get_mut();
-            let mac_key = c.mac_key();
-            c.set_addr_mackey(addr, mac_key);
-            if *self.keepalivers.entry_async(psk).await.or_insert(0).get() > 0 {
-                c.start_ka().await;
+    /// limits how many key exchanges started by others can be in progress,
+    /// both in total and for a single address
+    async fn allow_kex_attempt(&self, addr: PeerAddr) -> bool {
+        const KEX_WINDOW: Duration = Duration::from_secs(10);
+        const MAX_KEX_PER_WINDOW: u32 = 8;
+        const MAX_INITTING: usize = 1024;
+        const MAX_KEX_ATTEMPTS_TRACKED: usize = 4096;
+        if self.initting.len() >= MAX_INITTING {
+            // the ones that gave up may not have been swept by the reaper yet
+            self.remove_finished_initting().await;
+        }
+        if self.initting.len() >= MAX_INITTING {
+            return false;
+        }
+        let now = SystemTime::now();
+        let in_window =
+            |start: &SystemTime| now.duration_since(*start).unwrap_or_default() <= KEX_WINDOW;
+        if self.kex_attempts.len() >= MAX_KEX_ATTEMPTS_TRACKED {
+            self.kex_attempts
+                .retain_async(|_addr, (start, _cnt)| in_window(start))
+                .await;
+        }
+        let mut occupied = match self.kex_attempts.entry_async(addr).await {
+            Entry::Occupied(o) => o,
+            // too many addresses tried within the window, new ones wait for them to leave it
+            Entry::Vacant(_) if self.kex_attempts.len() >= MAX_KEX_ATTEMPTS_TRACKED => {
+                return false;
             }
+            Entry::Vacant(v) => v.insert_entry((now, 0)),
+        };
+        let (start, cnt) = occupied.get_mut();
+        if !in_window(start) {
+            *start = now;
+            *cnt = 0;
         }
+        *cnt += 1;
+        *cnt <= MAX_KEX_PER_WINDOW
     }
-    pub async fn wait_connection(&self, psk: PubSigKey) {
-        // TODO: don't poll, use futures
-        // (consider https://docs.rs/async-lock/latest/async_lock/struct.OnceCell.html#method.wait)
-        while !self.connections.contains_async(&psk).await {
-            tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+    async fn seen(&self, psk: PubSigKey) {
+        if let Some(mut c) = self.connections.get_async(&psk).await {
+            c.get_mut().seen();
         }
     }
-    pub async fn inc_keepalive(&self, psk: PubSigKey) {
-        let cnt = {
-            let entry = self.keepalivers.entry_async(psk).await;
-            let mut occupied = entry.or_insert(0);
-            let ka = occupied.get_mut();
-            *ka += 1;
-            *ka
-        };
-        if cnt == 1 {
-            if let Some(mut c) = self.connections.get_async(&psk).await {
-                c.get_mut().start_ka().await;
-            } else if let Some(addr_entry) = self.psk_to_addr.get_async(&psk).await {
-                let addr = *addr_entry.get();
-                let _ = self
-                    .initting
-                    .insert_async((psk, addr), new_initting(self.sw.clone(), addr).await)
-                    .await;
-            }
+    /// removes the connections that have not received anything for longer than idle_timeout,
+    /// including the ones we keep alive ourselves, since a live peer acks our keepalives
+    pub async fn reap_idle_connections(&self, idle_timeout: Duration) {
+        let mut dead = vec![];
+        self.connections
+            .retain_async(|psk, c| {
+                if c.is_idle(idle_timeout) {
+                    dead.push(*psk);
+                    false
+                } else {
+                    true
+                }
+            })
+            .await;
+        for psk in dead {
+            debug!("removing idle connection to {:?}", psk);
+            self.abort_initting(psk).await;
+            self.send_queues.remove_async(&psk).await;
         }
     }
-    pub async fn dec_keepalive(&self, psk: PubSigKey) {
-        let cnt = {
+    async fn abort_initting(&self, psk: PubSigKey) {
+        self.initting
+            .retain_async(|(ipsk, _addr), (_skk, _pkk, ah)| {
+                if *ipsk == psk {
+                    ah.abort();
+                    false
+                } else {
+                    true
+                }
+            })
+            .await;
+    }
+    /// starts a key exchange with psk at addr, unless there is one already,
+    /// whose kex key would be lost while its task keeps sending the public half
+    async fn start_initting(&self, psk: PubSigKey, addr: PeerAddr) {
+        if let Entry::Vacant(v) = self.initting.entry_async((psk, addr)).await {
+            v.insert_entry(new_initting(self.sw.clone(), addr, self.obfuscation_key).await);
+        }
+    }
+    /// replaces the mac key of the connection to psk with a fresh key exchange,
+    /// the connection stays up while the exchange completes
+    pub async fn rekey(&self, psk: PubSigKey) -> anyhow::Result<()> {
+        let addr = self
+            .connections
+            .get_async(&psk)
+            .await
+            .ok_or(anyhow::anyhow!("rekeying a connection that does not exist"))?
+            .get()
+            .addr();
+        let initting = new_initting(self.sw.clone(), addr, self.obfuscation_key).await;
+        match self.initting.entry_async((psk, addr)).await {
+            Entry::Occupied(mut o) => {
+                let (_skk, _pkk, ah) = std::mem::replace(o.get_mut(), initting);
+                ah.abort();
+            }
+            Entry::Vacant(v) => {
+                v.insert_entry(initting);
+            }
+        }
+        Ok(())
+    }
+    /// starts rekeying the connections that used their key for too long, until shutdown
+    pub fn start_rekeyer(self: &Arc<Self>, policy: RekeyPolicy) {
+        let ah = task::spawn(self.clone().rekeyer(policy)).abort_handle();
+        self.background.lock().unwrap().push(ah);
+    }
+    async fn rekeyer(self: Arc<Self>, policy: RekeyPolicy) {
+        loop {
+            sleep(REKEY_GRACE).await;
+            let mut stale = vec![];
+            self.connections
+                .scan_async(|psk, c| {
+                    if c.needs_rekey(&policy) {
+                        stale.push(*psk);
+                    }
+                })
+                .await;
+            for psk in stale {
+                if !self.kex_in_progress(psk).await {
+                    debug!("rekeying the connection to {}", psk);
+                    let _ = self.rekey(psk).await;
+                }
+            }
+        }
+    }
+    /// opens a reliable, ordered byte stream to psk, both peers must open it with the same id and key,
+    /// the data is encrypted with enc_key and sent in chunks with retransmissions,
+    /// the stream ends when both sides shut down their writing half,
+    /// it breaks (reads and writes fail) if the connection is lost or the peer stops acking
+    pub async fn open_stream(
+        self: &Arc<Self>,
+        psk: PubSigKey,
+        id: StreamId,
+        enc_key: EncKey,
+    ) -> anyhow::Result<DuplexStream> {
+        if !self.is_connected(psk).await {
+            anyhow::bail!("Trying to open a stream, but there is no connection");
+        }
+        let Entry::Vacant(v) = self.streams.entry_async((psk, id)).await else {
+            anyhow::bail!("stream {} to {} is already open", id, psk);
+        };
+        let (tx, rx) = mpsc::channel(stream::STREAM_QUEUE);
+        let (local, remote) = tokio::io::duplex(stream::WINDOW_BYTES);
+        // the entry is locked until inserted, so the stream can't close itself before that
+        let ah = task::spawn(stream::run_stream(
+            self.clone(),
+            psk,
+            id,
+            enc_key,
+            remote,
+            rx,
+        ))
+        .abort_handle();
+        v.insert_entry((tx, ah));
+        Ok(local)
+    }
+    async fn close_stream(&self, psk: PubSigKey, id: StreamId) {
+        self.streams.remove_async(&(psk, id)).await;
+    }
+    async fn handle_stream_message(
+        &self,
+        m: StreamMessage,
+        psk: PubSigKey,
+    ) -> Result<(), DropReason> {
+        let Some(tx) = self
+            .streams
+            .get_async(&(psk, m.stream_id()))
+            .await
+            .map(|x| x.get().0.clone())
+        else {
+            return Err(DropReason::Unexpected);
+        };
+        // a full queue drops the message, the peer sends it again
+        tx.try_send(m).map_err(|_| DropReason::RateLimited)
+    }
+    async fn send_stream_message(&self, psk: PubSigKey, m: StreamMessage) -> Result<(), SendError> {
+        self.transmitter().send(psk, Outgoing::Stream(m)).await
+    }
+    /// starts removing idle connections, the key exchanges that gave up,
+    /// and the queue messages that were not completed in time, until shutdown
+    pub fn start_reaper(self: &Arc<Self>, idle_timeout: Duration) {
+        let ah = task::spawn(self.clone().reaper(idle_timeout)).abort_handle();
+        self.background.lock().unwrap().push(ah);
+    }
+    async fn reaper(self: Arc<Self>, idle_timeout: Duration) {
+        loop {
+            sleep(idle_timeout / 4).await;
+            self.reap_idle_connections(idle_timeout).await;
+            self.remove_finished_initting().await;
+            self.queue_store.remove_expired(QUEUE_PARTS_TIMEOUT).await;
+        }
+    }
+    async fn ka_interval(&self, psk: PubSigKey) -> KeepAliveInterval {
+        self.ka_intervals
+            .get_async(&psk)
+            .await
+            .map(|x| *x.get())
+            .unwrap_or(self.default_ka_interval)
+    }
+    /// sets the keepalive interval for the connection to psk,
+    /// applies to the current connection as well as future ones
+    pub async fn set_keepalive_interval(&self, psk: PubSigKey, ka_interval: KeepAliveInterval) {
+        self.ka_intervals
+            .entry_async(psk)
+            .await
+            .insert_entry(ka_interval);
+        if let Some(mut c) = self.connections.get_async(&psk).await {
+            c.get_mut().set_ka_interval(ka_interval).await;
+        }
+    }
+    pub async fn update_peer_addr(&self, psk: PubSigKey, addr: PeerAddr) {
+        self.psk_to_addr.entry_async(psk).await.insert_entry(addr);
+        self.addr_to_psk.entry_async(addr).await.insert_entry(psk);
+        if let Some(mut oc) = self.connections.get_async(&psk).await {
+            let c = oc.get_mut();
+            let mac_keys = c.mac_keys();
+            c.set_addr_mackeys(addr, mac_keys);
+            if *self.keepalivers.entry_async(psk).await.or_insert(0).get() > 0 {
+                c.start_ka().await;
+            }
+        }
+    }
+    pub async fn is_connected(&self, psk: PubSigKey) -> bool {
+        self.connections.contains_async(&psk).await
+    }
+    /// adds many known peers at once, eg from a contest config file,
+    /// connections are only started when needed as with update_peer_addr
+    pub async fn bootstrap(&self, peers: Vec<(PubSigKey, PeerAddr)>) {
+        for (psk, addr) in peers {
+            self.update_peer_addr(psk, addr).await;
+        }
+    }
+    /// stops all the tasks of this Net: keepalives, key exchanges, streams, reaper and rekeyer,
+    /// the socket is closed when the last reference to the Net is dropped,
+    /// which none of its tasks hold anymore
+    pub async fn shutdown(&self) {
+        for ah in self.background.lock().unwrap().drain(..) {
+            ah.abort();
+        }
+        self.streams
+            .retain_async(|_k, (_tx, ah)| {
+                ah.abort();
+                false
+            })
+            .await;
+        // dropping a queue ends the task draining it
+        self.send_queues.retain_async(|_k, _q| false).await;
+        self.initting
+            .retain_async(|_k, (_skk, _pkk, ah)| {
+                ah.abort();
+                false
+            })
+            .await;
+        // dropping a connection aborts its keepalive
+        self.connections.retain_async(|_k, _c| false).await;
+    }
+    /// waits until there is a connection to psk,
+    /// returns false if the key exchange with psk gave up (or was never started)
+    pub async fn wait_connection(&self, psk: PubSigKey) -> bool {
+        // TODO: don't poll, use futures
+        // (consider https://docs.rs/async-lock/latest/async_lock/struct.OnceCell.html#method.wait)
+        loop {
+            if self.connections.contains_async(&psk).await {
+                return true;
+            }
+            if !self.kex_in_progress(psk).await {
+                self.remove_stale_initting(psk).await;
+                return false;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+        }
+    }
+    /// like wait_connection, but gives up after timeout,
+    /// stopping the key exchanges with psk that are still in progress
+    pub async fn wait_connection_timeout(&self, psk: PubSigKey, timeout: Duration) -> bool {
+        match tokio::time::timeout(timeout, self.wait_connection(psk)).await {
+            Ok(connected) => connected,
+            Err(_) => {
+                self.initting
+                    .retain_async(|(ipsk, _addr), (_skk, pkk, ah)| {
+                        if *ipsk == psk && pkk.is_none() {
+                            ah.abort();
+                            false
+                        } else {
+                            true
+                        }
+                    })
+                    .await;
+                self.connections.contains_async(&psk).await
+            }
+        }
+    }
+    async fn kex_in_progress(&self, psk: PubSigKey) -> bool {
+        let mut in_progress = false;
+        self.initting
+            .scan_async(|(ipsk, _addr), (_skk, _pkk, ah)| {
+                in_progress |= *ipsk == psk && !ah.is_finished();
+            })
+            .await;
+        in_progress
+    }
+    /// removes the key exchanges with any peer whose task is over,
+    /// eg the ones started by merkles from peers that never answered
+    async fn remove_finished_initting(&self) {
+        self.initting
+            .retain_async(|_k, (_skk, _pkk, ah)| !ah.is_finished())
+            .await;
+    }
+    /// removes the key exchanges with psk that gave up without completing
+    async fn remove_stale_initting(&self, psk: PubSigKey) {
+        self.initting
+            .retain_async(|(ipsk, _addr), (_skk, pkk, ah)| {
+                !(*ipsk == psk && pkk.is_none() && ah.is_finished())
+            })
+            .await;
+    }
+    pub async fn inc_keepalive(&self, psk: PubSigKey) {
+        let cnt = {
+            let entry = self.keepalivers.entry_async(psk).await;
+            let mut occupied = entry.or_insert(0);
+            let ka = occupied.get_mut();
+            *ka += 1;
+            *ka
+        };
+        if cnt == 1 {
+            if let Some(mut c) = self.connections.get_async(&psk).await {
+                c.get_mut().start_ka().await;
+            } else if let Some(addr_entry) = self.psk_to_addr.get_async(&psk).await {
+                let addr = *addr_entry.get();
+                self.start_initting(psk, addr).await;
+            }
+        }
+    }
+    pub async fn dec_keepalive(&self, psk: PubSigKey) {
+        let cnt = {
             let entry = self.keepalivers.entry_async(psk).await;
             let mut occupied = entry.or_insert(0);
             let ka = occupied.get_mut();
@@ -391,205 +1467,449 @@ impl Net {
         }
     }
 }
-// server only
-#[cfg(feature = "server")]
+// what a node does depends on whether it is the server
 impl Net {
-    pub async fn recv(&self, buf: &mut [u8]) -> (RecvMessage, PubSigKey) {
+    fn is_server(&self) -> bool {
+        self.sw.entity() == Entity::Server
+    }
+    /// rejects psk as any entity and drops the current connection to it, if any
+    pub async fn ban_peer(&self, psk: PubSigKey) {
+        self.inbound_connection_filter.ban_psk(psk).await;
+        self.connections.remove_async(&psk).await;
+        self.abort_initting(psk).await;
+        self.send_queues.remove_async(&psk).await;
+    }
+    /// accepts psk as entity,
+    /// only affects connections established from now on
+    pub async fn allow_peer(&self, psk: PubSigKey, entity: Entity) {
+        self.inbound_connection_filter.allow_psk(psk, entity).await;
+    }
+    /// rejects addresses in range as any entity and drops the current connections from it
+    pub async fn ban_range(&self, range: IpNet) {
+        self.inbound_connection_filter.ban_range(range).await;
+        let mut banned = vec![];
+        self.connections
+            .retain_async(|psk, c| {
+                if range.contains(&c.addr().ip()) {
+                    banned.push(*psk);
+                    false
+                } else {
+                    true
+                }
+            })
+            .await;
+        for psk in banned {
+            self.abort_initting(psk).await;
+            self.send_queues.remove_async(&psk).await;
+        }
+    }
+    /// accepts addresses in range as entity (if the range list for entity is a whitelist),
+    /// only affects connections established from now on
+    pub async fn allow_range(&self, range: IpNet, entity: Entity) {
+        self.inbound_connection_filter
+            .allow_range(range, entity)
+            .await;
+    }
+    /// sends each of a and b the address the other is seen at,
+    /// so that they can connect to each other even if both are behind a nat.
+    /// Both start a key exchange as soon as they receive it, this works as long as
+    /// the nats keep the mappings open for longer than the key exchange timeout,
+    /// usually at least 30 seconds for udp.
+    /// The introductions are sent a few times in the background
+    pub async fn rendezvous(&self, a: PubSigKey, b: PubSigKey) -> anyhow::Result<()> {
+        let info = |psk: PubSigKey| async move {
+            self.connections.get_async(&psk).await.map(|c| {
+                let c = c.get();
+                (c.addr(), c.entity())
+            })
+        };
+        let (Some((addr_a, entity_a)), Some((addr_b, entity_b))) = (info(a).await, info(b).await)
+        else {
+            anyhow::bail!("rendezvous between peers that are not connected");
+        };
+        let now = SystemTime::now();
+        for (to, other, other_addr, other_entity) in
+            [(a, b, addr_b, entity_b), (b, a, addr_a, entity_a)]
+        {
+            let info = QPeerInfo {
+                psk: other,
+                addr: Obfuscated::new(other_addr, &self.obfuscation_key),
+                entity: other_entity,
+            };
+            debug!("introducing {} to {}", other, to);
+            let m = Outgoing::Rendezvous((info, now));
+            // nothing acks it, and a lost one leaves the peers unable to connect
+            let transmitter = self.transmitter();
+            task::spawn(async move { transmitter.repeat(to, m, &RETRANSMIT_DELAYS).await });
+        }
+        Ok(())
+    }
+    /// errors only if the socket fails,
+    /// the server gets the messages from the participants and workers, the others the ones from the server
+    /// (queue messages are accepted only from the server_psk given to NetBuilder)
+    pub async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<(RecvMessage, PubSigKey)> {
         loop {
-            let (m, addr) = self.sr.recv_from(buf).await;
-            match m {
-                Message::Net(nm) => {
-                    self.handle_net_message(nm, addr).await;
-                }
-                Message::Request(rm) => {
-                    if let Some(psk) = self.addr_to_psk.get_async(&addr).await.map(|x| *x.get()) {
-                        if let Some(mac_key) = self
-                            .connections
-                            .get_async(&psk)
-                            .await
-                            .map(|x| x.get().mac_key())
-                        {
-                            if let Some(inner) = rm.inner(&mac_key) {
-                                return (RecvMessage::Request(inner), psk);
-                            }
-                        }
+            let (m, addr) = self.sr.recv_from(buf).await?;
+            if let Some(received) = self.handle_message(m, addr).await {
+                return Ok(received);
+            }
+        }
+    }
+    /// like recv, but receives all the datagrams already waiting in the socket at once,
+    /// at most bufs.len() / MAX_DATAGRAM_SIZE of them, adding the messages to deliver to out,
+    /// returns once at least one was added
+    pub async fn recv_batch(
+        &self,
+        bufs: &mut [u8],
+        out: &mut Vec<(RecvMessage, PubSigKey)>,
+    ) -> anyhow::Result<()> {
+        let mut batch = vec![];
+        loop {
+            self.sr.recv_batch(bufs, &mut batch).await?;
+            for (m, addr) in batch.drain(..) {
+                if let Some(received) = self.handle_message(m, addr).await {
+                    out.push(received);
+                }
+            }
+            if !out.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+    /// the message to deliver, if any
+    async fn handle_message(&self, m: Message, addr: PeerAddr) -> Option<(RecvMessage, PubSigKey)> {
+        let is_server = self.is_server();
+        match m {
+            Message::Net(nm) => {
+                if let Err(reason) = self.handle_net_message(nm, addr).await {
+                    self.drop_message(reason, addr);
+                }
+            }
+            Message::Request(rm) => match self.unmac(rm, addr).await {
+                Ok((RequestMessage::Rendezvous(target), psk)) if is_server => {
+                    if let Err(e) = self.rendezvous(psk, target).await {
+                        debug!("could not introduce {} to {}: {}", psk, target, e);
                     }
                 }
-                Message::Submission(sm) => {
-                    if let Some(psk) = self.addr_to_psk.get_async(&addr).await.map(|x| *x.get()) {
-                        if let Some(mac_key) = self
-                            .connections
-                            .get_async(&psk)
-                            .await
-                            .map(|x| x.get().mac_key())
-                        {
-                            if let Some(inner) = sm.inner(&mac_key) {
-                                return (RecvMessage::Submission(inner), psk);
-                            }
-                        }
+                Ok((RequestMessage::Queue(ranges), psk)) if is_server => {
+                    if let Err(e) = self.resend_queue(psk, &ranges).await {
+                        debug!("could not resend queue messages to {}: {}", psk, e);
                     }
                 }
-                Message::Question(qm) => {
-                    if let Some(psk) = self.addr_to_psk.get_async(&addr).await.map(|x| *x.get()) {
-                        if let Some(mac_key) = self
-                            .connections
-                            .get_async(&psk)
-                            .await
-                            .map(|x| x.get().mac_key())
-                        {
-                            if let Some(inner) = qm.inner(&mac_key) {
-                                return (RecvMessage::Question(inner), psk);
-                            }
-                        }
+                Ok((inner, psk)) => return Some((RecvMessage::Request(inner), psk)),
+                Err(reason) => self.drop_message(reason, addr),
+            },
+            Message::Submission(sm) if is_server => match self.unmac(sm, addr).await {
+                Ok((inner, psk)) => {
+                    if !self.is_spectator(psk).await {
+                        return Some((RecvMessage::Submission(inner), psk));
                     }
+                    self.drop_message(DropReason::FromSpectator, addr);
                 }
-                _ => {}
-            }
+                Err(reason) => self.drop_message(reason, addr),
+            },
+            Message::Question(qm) if is_server => match self.unmac(qm, addr).await {
+                Ok((inner, psk)) => {
+                    if !self.is_spectator(psk).await {
+                        return Some((RecvMessage::Question(inner), psk));
+                    }
+                    self.drop_message(DropReason::FromSpectator, addr);
+                }
+                Err(reason) => self.drop_message(reason, addr),
+            },
+            Message::Evaluation(em) if is_server => match self.unmac(em, addr).await {
+                // workers can only speak for themselves
+                Ok((inner, psk)) => {
+                    if !self.is_worker(psk).await {
+                        self.drop_message(DropReason::Unexpected, addr);
+                    } else if inner.evaluation_id().evaluator != psk {
+                        self.drop_message(DropReason::Invalid, addr);
+                    } else {
+                        return Some((RecvMessage::Evaluation(inner), psk));
+                    }
+                }
+                Err(reason) => self.drop_message(reason, addr),
+            },
+            Message::Queue(qm) if !is_server => match self.unmac(qm, addr).await {
+                Ok((part, psk)) => {
+                    // only the server publishes to the queue
+                    let Some(server_psk) = self.server_psk.filter(|s| *s == psk) else {
+                        self.drop_message(DropReason::Unexpected, addr);
+                        return None;
+                    };
+                    // None until all the parts are received
+                    let data = self.queue_store.add_part(psk, part).await?;
+                    let Some(data) = unpack_queue_message(&data) else {
+                        self.drop_message(DropReason::Malformed, addr);
+                        return None;
+                    };
+                    let Ok(signed) = SignedQueueMessage::read_from_buffer(&data) else {
+                        self.drop_message(DropReason::Malformed, addr);
+                        return None;
+                    };
+                    let Some(inner) = signed.inner(&server_psk) else {
+                        self.drop_message(DropReason::BadSignature, addr);
+                        return None;
+                    };
+                    return Some((RecvMessage::Queue(inner.0), psk));
+                }
+                Err(reason) => self.drop_message(reason, addr),
+            },
+            Message::File(fm) if !is_server => match self.unmac(fm, addr).await {
+                Ok((inner, psk)) => return Some((RecvMessage::File(inner), psk)),
+                Err(reason) => self.drop_message(reason, addr),
+            },
+            Message::EncKey(em) if !is_server => match self.unmac(em, addr).await {
+                Ok((inner, psk)) => return Some((RecvMessage::EncKey(inner), psk)),
+                Err(reason) => self.drop_message(reason, addr),
+            },
+            Message::ChunkHashes(hm) if !is_server => match self.unmac(hm, addr).await {
+                Ok((inner, psk)) => return Some((RecvMessage::ChunkHashes(inner), psk)),
+                Err(reason) => self.drop_message(reason, addr),
+            },
+            Message::Stream(sm) => match self.unmac(sm, addr).await {
+                Ok((inner, psk)) => {
+                    if let Err(reason) = self.handle_stream_message(inner, psk).await {
+                        self.drop_message(reason, addr);
+                    }
+                }
+                Err(reason) => self.drop_message(reason, addr),
+            },
+            _ => self.drop_message(DropReason::Unexpected, addr),
         }
+        None
     }
-    pub async fn send(&self, m: SendMessage, psk: PubSigKey, buf: &mut [u8]) -> anyhow::Result<()> {
-        let mac_key = self
+    /// sends again the queue messages in the requested ranges,
+    /// at most MAX_QUEUE_RESEND of them per request
+    async fn resend_queue(&self, psk: PubSigKey, ranges: &[(u32, u32)]) -> anyhow::Result<()> {
+        for m in self.queue_log.get_ranges(ranges, MAX_QUEUE_RESEND).await {
+            self.send(SendMessage::Queue(m), psk).await?;
+        }
+        Ok(())
+    }
+    /// answers a RequestMessage::EncKey from psk with the key, if psk is entitled to it,
+    /// published and solved describe the contest as seen by psk, its entity is the one it connected as,
+    /// returns false if the request was dropped
+    pub async fn answer_enc_key_request(
+        &self,
+        keys: &EncKeyStore,
+        id: EncKeyId,
+        psk: PubSigKey,
+        published: std::collections::HashSet<u32>,
+        solved: std::collections::HashSet<ProblemId>,
+    ) -> anyhow::Result<bool> {
+        let Some(entity) = self
             .connections
             .get_async(&psk)
             .await
-            .ok_or(anyhow::anyhow!(
-                "Trying to send message, but there is no connection"
-            ))?
-            .get()
-            .mac_key();
-        let addr = *self
-            .psk_to_addr
+            .map(|c| c.get().entity())
+        else {
+            return Ok(false);
+        };
+        let ctx = AccessContext {
+            entity,
+            psk,
+            published,
+            solved,
+        };
+        let Some(info) = keys.get(&id, &ctx).await else {
+            debug!("not giving {:?} to {}", id, psk);
+            return Ok(false);
+        };
+        self.send(SendMessage::EncKey(info), psk).await?;
+        Ok(true)
+    }
+    async fn is_spectator(&self, psk: PubSigKey) -> bool {
+        self.connections
             .get_async(&psk)
             .await
-            .ok_or(anyhow::anyhow!(
-                "Trying to send message, could not find addr from psk"
-            ))?
-            .get();
-        let message = match m {
+            .is_some_and(|c| c.get().entity() == Entity::Spectator)
+    }
+    async fn is_worker(&self, psk: PubSigKey) -> bool {
+        self.connections
+            .get_async(&psk)
+            .await
+            .is_some_and(|c| c.get().entity() == Entity::Worker)
+    }
+    pub async fn send(&self, m: SendMessage, psk: PubSigKey) -> Result<(), SendError> {
+        if matches!(m, SendMessage::Queue(_) | SendMessage::EncKey(_)) && !self.is_server() {
+            return Err(SendError::NotAllowed);
+        }
+        if !self.connections.contains_async(&psk).await {
+            return Err(SendError::NotConnected);
+        }
+        let transmitter = self.transmitter();
+        let m = match m {
             SendMessage::Queue(m) => {
-                Message::Queue(Macced::new(Signed::new((m, ()), &self.sw.ssk()), &mac_key))
+                self.queue_log.add(m.clone()).await;
+                let id = m.id;
+                let data = SignedQueueMessage::try_new((m, ()), self.sw.signer().as_ref())?
+                    .write_to_vec()?;
+                let data = pack_queue_message(&data);
+                for part in QueuePart::split(id, &data) {
+                    transmitter.send(psk, Outgoing::Queue(part)).await?;
+                }
+                return Ok(());
             }
-            SendMessage::File(m) => Message::File(Macced::new(m, &mac_key)),
-            SendMessage::EncKey(m) => Message::EncKey(Macced::new(m, &mac_key)),
+            SendMessage::EncKey(m) => Outgoing::EncKey(m),
+            SendMessage::Submission(m) => Outgoing::Submission(m),
+            SendMessage::Question(m) => Outgoing::Question(m),
+            SendMessage::Evaluation(m) => Outgoing::Evaluation(m),
+            SendMessage::File(m) => Outgoing::File(m),
+            SendMessage::ChunkHashes(m) => Outgoing::ChunkHashes(m),
+            SendMessage::Request(m) => Outgoing::Request(m),
         };
-        self.sw.send_to(message, addr, buf).await
+        transmitter.send(psk, m).await
+    }
+    fn transmitter(&self) -> Transmitter {
+        Transmitter {
+            socket: self.sw.clone(),
+            connections: Arc::downgrade(&self.connections),
+            send_queues: Arc::downgrade(&self.send_queues),
+            send_queue_capacity: self.send_queue_capacity,
+        }
     }
 }
-// client only
-#[cfg(feature = "client")]
-impl Net {
-    pub async fn recv(&self, server_psk: PubSigKey, buf: &mut [u8]) -> (RecvMessage, PubSigKey) {
-        loop {
-            let (m, addr) = self.sr.recv_from(buf).await;
-            match m {
-                Message::Net(nm) => {
-                    self.handle_net_message(nm, addr).await;
-                }
-                Message::Queue(qm) => {
-                    if let Some(psk) = self.addr_to_psk.get_async(&addr).await.map(|x| *x.get()) {
-                        if let Some(mac_key) = self
-                            .connections
-                            .get_async(&psk)
-                            .await
-                            .map(|x| x.get().mac_key())
-                        {
-                            if let Some(signed) = qm.inner(&mac_key) {
-                                if let Some(inner) = signed.inner(&server_psk) {
-                                    return (RecvMessage::Queue(inner.0), psk);
-                                }
-                            }
-                        }
-                    }
-                }
-                Message::File(fm) => {
-                    if let Some(psk) = self.addr_to_psk.get_async(&addr).await.map(|x| *x.get()) {
-                        if let Some(mac_key) = self
-                            .connections
-                            .get_async(&psk)
-                            .await
-                            .map(|x| x.get().mac_key())
-                        {
-                            if let Some(inner) = fm.inner(&mac_key) {
-                                return (RecvMessage::File(inner), psk);
-                            }
-                        }
-                    }
-                }
-                Message::Request(rm) => {
-                    if let Some(psk) = self.addr_to_psk.get_async(&addr).await.map(|x| *x.get()) {
-                        if let Some(mac_key) = self
-                            .connections
-                            .get_async(&psk)
-                            .await
-                            .map(|x| x.get().mac_key())
-                        {
-                            if let Some(inner) = rm.inner(&mac_key) {
-                                return (RecvMessage::Request(inner), psk);
-                            }
-                        }
-                    }
-                }
-                Message::EncKey(em) => {
-                    if let Some(psk) = self.addr_to_psk.get_async(&addr).await.map(|x| *x.get()) {
-                        if let Some(mac_key) = self
-                            .connections
-                            .get_async(&psk)
-                            .await
-                            .map(|x| x.get().mac_key())
-                        {
-                            if let Some(inner) = em.inner(&mac_key) {
-                                return (RecvMessage::EncKey(inner), psk);
-                            }
-                        }
-                    }
-                }
-                _ => {}
+/// a message to a connected peer, macced only when it is actually sent,
+/// so that one that waited in a send queue across a rekey goes out with the new key
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+enum Outgoing {
+    Queue(QueuePart),
+    EncKey(EncKeyInfo),
+    Submission(SubmissionMessage),
+    Question(QuestionMessage),
+    Evaluation(EvaluationMessage),
+    File(FileMessage),
+    ChunkHashes(ChunkHashesMessage),
+    Request(RequestMessage),
+    Stream(StreamMessage),
+    KeepAlive(KeepAliveInner),
+    Rendezvous(RendezvousInner),
+}
+impl Outgoing {
+    fn mac(self, own_psk: PubSigKey, key: &MacKey) -> Result<Message, SendError> {
+        Ok(match self {
+            Self::Queue(m) => Message::Queue(Macced::new(m, key)),
+            Self::EncKey(m) => Message::EncKey(Macced::try_new(m, key)?),
+            Self::Submission(m) => Message::Submission(Macced::try_new(m, key)?),
+            Self::Question(m) => Message::Question(Macced::try_new(m, key)?),
+            Self::Evaluation(m) => Message::Evaluation(Macced::try_new(m, key)?),
+            Self::File(m) => Message::File(Macced::try_new(m, key)?),
+            Self::ChunkHashes(m) => Message::ChunkHashes(Macced::try_new(m, key)?),
+            Self::Request(m) => Message::Request(Macced::try_new(m, key)?),
+            Self::Stream(m) => Message::Stream(Macced::new(m, key)),
+            Self::KeepAlive(m) => Message::Net(NetMessage::KeepAlive(own_psk, Macced::new(m, key))),
+            Self::Rendezvous(m) => {
+                Message::Net(NetMessage::Rendezvous(own_psk, Macced::new(m, key)))
             }
-        }
+        })
     }
-    pub async fn send(&self, m: SendMessage, psk: PubSigKey, buf: &mut [u8]) -> anyhow::Result<()> {
-        let mac_key = self
-            .connections
-            .get_async(&psk)
+}
+/// sends to connected peers, through their send queues if sends are queued,
+/// can be held by the tasks that send on their own since it doesn't keep the Net alive
+#[derive(Clone)]
+struct Transmitter {
+    socket: SocketWriter,
+    connections: Weak<HashMap<PubSigKey, Connection>>,
+    send_queues: Weak<HashMap<PubSigKey, mpsc::Sender<(PubSigKey, Outgoing)>>>,
+    send_queue_capacity: Option<usize>,
+}
+impl Transmitter {
+    /// sends m now, or queues it if sends are queued
+    async fn send(&self, psk: PubSigKey, m: Outgoing) -> Result<(), SendError> {
+        let Some(capacity) = self.send_queue_capacity else {
+            return self.send_now(psk, m).await;
+        };
+        // otherwise it would only fail once it is its turn, the length doesn't depend on the key
+        datagram_len(&m.clone().mac(self.socket.psk(), &MacKey::from([0; 32]))?)?;
+        let send_queues = self.send_queues.upgrade().ok_or(SendError::NotConnected)?;
+        let queue = send_queues
+            .entry_async(psk)
             .await
-            .ok_or(anyhow::anyhow!(
-                "Trying to send message, but there is no connection"
-            ))?
+            .or_insert_with(|| {
+                let (tx, rx) = mpsc::channel(capacity);
+                task::spawn(drain_send_queue(self.clone(), rx));
+                tx
+            })
             .get()
-            .mac_key();
-        let addr = *self
-            .psk_to_addr
+            .clone();
+        // the queue is closed once the connection is dropped
+        queue
+            .send((psk, m))
+            .await
+            .map_err(|_| SendError::NotConnected)
+    }
+    /// macs m with the current key of the connection and sends it to its current address
+    async fn send_now(&self, psk: PubSigKey, m: Outgoing) -> Result<(), SendError> {
+        let connections = self.connections.upgrade().ok_or(SendError::NotConnected)?;
+        let (mac_key, addr) = connections
             .get_async(&psk)
             .await
-            .ok_or(anyhow::anyhow!(
-                "Trying to send message, could not find addr from psk"
-            ))?
-            .get();
-        let message = match m {
-            SendMessage::File(m) => Message::File(Macced::new(m, &mac_key)),
-            SendMessage::Request(m) => Message::Request(Macced::new(m, &mac_key)),
-            SendMessage::Submission(m) => Message::Submission(Macced::new(m, &mac_key)),
-            SendMessage::Question(m) => Message::Question(Macced::new(m, &mac_key)),
-        };
-        self.sw.send_to(message, addr, buf).await
+            .ok_or(SendError::NotConnected)
+            .map(|mut c| (c.get_mut().use_mac_key(), c.get().addr()))?;
+        drop(connections);
+        self.socket
+            .send_to(m.mac(self.socket.psk(), &mac_key)?, addr)
+            .await
+    }
+    /// sends m now and again after each delay, for messages that are not acked,
+    /// so receiving them more than once must be harmless
+    async fn repeat(&self, psk: PubSigKey, m: Outgoing, delays: &[Duration]) {
+        for delay in std::iter::once(&Duration::ZERO).chain(delays) {
+            sleep(*delay).await;
+            if let Err(e) = self.send(psk, m.clone()).await {
+                debug!("could not send to {}: {}", psk, e);
+            }
+        }
+    }
+}
+/// sends the messages of a send queue in order, until the queue is dropped
+async fn drain_send_queue(transmitter: Transmitter, mut rx: mpsc::Receiver<(PubSigKey, Outgoing)>) {
+    while let Some((psk, m)) = rx.recv().await {
+        if let Err(e) = transmitter.send_now(psk, m).await {
+            debug!("could not send queued message to {}: {}", psk, e);
+        }
     }
 }
+// the merkle is resent with exponential backoff between these intervals,
+// until the key exchange completes or KEX_TIMEOUT passes
+const KEX_MIN_INTERVAL: Duration = Duration::from_millis(50);
+const KEX_MAX_INTERVAL: Duration = Duration::from_secs(5);
+const KEX_TIMEOUT: Duration = Duration::from_secs(60);
+// queue messages sent again for a single request
+const MAX_QUEUE_RESEND: usize = 64;
 
 async fn new_initting(
     socket: SocketWriter,
     peer_addr: PeerAddr,
-) -> (Option<SecKexKey>, AbortHandle) {
-    let skk = SecKexKey::random_from_rng(thread_rng());
-    let abort_handle = task::spawn(send_kex_loop(socket, (&skk).into(), peer_addr)).abort_handle();
-    (Some(skk), abort_handle)
+    obfuscation_key: ObfuscationKey,
+) -> (SecKexKey, Option<PubKexKey>, AbortHandle) {
+    let skk = socket.rng().with(|rng| SecKexKey::random_from_rng(rng));
+    let abort_handle = task::spawn(send_kex_loop(
+        socket,
+        (&skk).into(),
+        peer_addr,
+        obfuscation_key,
+    ))
+    .abort_handle();
+    (skk, None, abort_handle)
 }
 
-async fn send_kex_loop(socket: SocketWriter, pkk: PubKexKey, peer_addr: PeerAddr) {
-    let mut buf = [0u8; 153];
+/// returns when it gives up, the entry in Net::initting stays (with a finished AbortHandle)
+/// until the reaper or allow_kex_attempt sweeps it, or a new merkle from the peer restarts it
+async fn send_kex_loop(
+    socket: SocketWriter,
+    pkk: PubKexKey,
+    peer_addr: PeerAddr,
+    obfuscation_key: ObfuscationKey,
+) {
     let contest_id = socket.contest_id();
-    let obf_addr = Obfuscated(socket.own_addr().unwrap());
-    let ssk = socket.ssk();
+    let obf_addr = Obfuscated::new(socket.own_addr().unwrap(), &obfuscation_key);
+    let signer = socket.signer();
     let psk = socket.psk();
+    let start = std::time::Instant::now();
+    let mut interval = KEX_MIN_INTERVAL;
     loop {
         let _ = socket
             .send_to(
@@ -599,19 +1919,1018 @@ async fn send_kex_loop(socket: SocketWriter, pkk: PubKexKey, peer_addr: PeerAddr
                             contest_id,
                             SystemTime::now(),
                             pkk,
-                            obf_addr,
+                            obf_addr.clone(),
                             socket.entity(),
                         ),
                         psk,
                     ),
-                    &ssk,
+                    signer.as_ref(),
                 ))),
                 peer_addr,
-                &mut buf,
             )
             .await;
-        let interval =
-            thread_rng().gen_range(Duration::from_millis(40)..Duration::from_millis(400));
-        sleep(interval).await;
+        if start.elapsed() > KEX_TIMEOUT {
+            debug!("giving up key exchange with {:?}", peer_addr);
+            return;
+        }
+        let delay = socket
+            .rng()
+            .with(|rng| rng.gen_range(interval / 2..=interval));
+        sleep(delay).await;
+        interval = (interval * 2).min(KEX_MAX_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn packet(m: &Message) -> Vec<u8> {
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let length = encode(m, PROTOCOL_VERSION, &mut buf).unwrap();
+        buf[..length].to_vec()
+    }
+    async fn test_net(addr: &str) -> Net {
+        test_net_with_key(addr, [42u8; 32]).await
+    }
+    async fn test_net_with_key(addr: &str, key: [u8; 32]) -> Net {
+        let ssk = SecSigKey::from_bytes(&key);
+        Net::new_with_addr(addr, ssk, Entity::Participant, 0, Filter::reject_all())
+            .await
+            .unwrap()
+    }
+    async fn test_server(addr: &str) -> Net {
+        let ssk = SecSigKey::from_bytes(&[42u8; 32]);
+        let filter = Filter::open_server(HashSet::new());
+        Net::new_with_addr(addr, ssk, Entity::Server, 0, filter)
+            .await
+            .unwrap()
+    }
+    /// a socket that net knows as a connected peer with the dummy key,
+    /// to send it hand-made packets
+    async fn fake_peer(net: &Net, entity: Entity) -> (tokio::net::UdpSocket, PubSigKey, MacKey) {
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = PeerAddr::from(peer.local_addr().unwrap());
+        let psk = PubSigKey::dummy();
+        let mac_key = MacKey::dummy();
+        let _ = net.addr_to_psk.insert_async(peer_addr, psk).await;
+        let _ = net.psk_to_addr.insert_async(psk, peer_addr).await;
+        let _ = net
+            .connections
+            .insert_async(
+                psk,
+                Connection::new(
+                    psk,
+                    peer_addr,
+                    MacKeys::symmetric(mac_key),
+                    net.transmitter(),
+                    KeepAliveInterval::default(),
+                    entity,
+                ),
+            )
+            .await;
+        (peer, psk, mac_key)
+    }
+    #[tokio::test]
+    async fn bind_addr() {
+        let net = test_net("127.0.0.1:0").await;
+        let addr = net.own_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert_eq!(net.psk(), (&SecSigKey::from_bytes(&[42u8; 32])).into());
+        assert_eq!(
+            std::net::SocketAddr::from(addr).ip(),
+            std::net::Ipv4Addr::LOCALHOST
+        );
+    }
+    #[tokio::test]
+    async fn builder() {
+        let filter = Filter::reject_all();
+        let window = TimestampWindow {
+            future: Duration::from_secs(1),
+            past: Duration::from_secs(2),
+        };
+        let ka_interval = KeepAliveInterval {
+            min: Duration::from_millis(10),
+            max: Duration::from_millis(20),
+        };
+        let net = Net::builder(
+            SecSigKey::from_bytes(&[42u8; 32]),
+            Entity::Worker,
+            3,
+            filter,
+        )
+        .bind_addr("127.0.0.1:0".parse().unwrap())
+        .obfuscation_key(ObfuscationKey::from_contest_id(3))
+        .timestamp_window(window)
+        .keepalive_interval(ka_interval)
+        .build()
+        .await
+        .unwrap();
+        assert_eq!(
+            std::net::SocketAddr::from(net.own_addr().unwrap()).ip(),
+            std::net::Ipv4Addr::LOCALHOST
+        );
+        assert_eq!(net.sw.contest_id(), 3);
+        assert_eq!(net.sw.entity(), Entity::Worker);
+        assert_eq!(net.obfuscation_key(), ObfuscationKey::from_contest_id(3));
+        assert_eq!(net.timestamp_window(), window);
+        assert_eq!(net.ka_interval(PubSigKey::dummy()).await, ka_interval);
+    }
+    #[tokio::test]
+    async fn send_not_connected() {
+        let net = test_net("127.0.0.1:0").await;
+        let m = SendMessage::File(FileMessage {
+            hash: Mac([0u8; 32].into()),
+            piece: 0,
+            data: SizedEncrypted::new(
+                FileChunk([0u8; FILE_CHUNK_SIZE]),
+                &EncKey::random(&RngSource::Thread),
+                &RngSource::Thread,
+            ),
+        });
+        assert!(matches!(
+            net.send(m, PubSigKey::dummy()).await,
+            Err(SendError::NotConnected)
+        ));
+    }
+    #[tokio::test]
+    async fn seeded_rng() {
+        use rand::SeedableRng;
+        let mut runs = vec![];
+        for _ in 0..2 {
+            let filter = Filter::reject_all();
+            let net = Net::builder(
+                SecSigKey::from_bytes(&[42u8; 32]),
+                Entity::Participant,
+                0,
+                filter,
+            )
+            .bind_addr("127.0.0.1:0".parse().unwrap())
+            .rng(rand::rngs::StdRng::seed_from_u64(7))
+            .build()
+            .await
+            .unwrap();
+            let addr = net.own_addr().unwrap();
+            let (skk, _pkk, ah) = new_initting(net.sw.clone(), addr, net.obfuscation_key).await;
+            ah.abort();
+            // a real message, with a key and a nonce from the rng
+            let key = EncKey::random(net.rng());
+            let encrypted = Encrypted::new(vec![1u8, 2, 3], &key, net.rng());
+            let files = file::FileStore::new().with_rng(net.rng().clone());
+            let hash = files.add_done(vec![4u8, 5, 6]).await;
+            runs.push((
+                PubKexKey::from(&skk),
+                encrypted,
+                files.enc_key(hash).await.unwrap(),
+            ));
+        }
+        assert_eq!(runs[0], runs[1]);
+    }
+    #[tokio::test]
+    async fn shutdown_aborts_tasks() {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let net = Arc::new(test_net("127.0.0.1:0").await);
+        net.start_reaper(Duration::from_secs(60));
+        net.start_rekeyer(RekeyPolicy::default());
+        let (_sock, psk, _mac) = fake_peer(&net, Entity::Worker).await;
+        net.inc_keepalive(psk).await;
+        let _stream = net
+            .open_stream(psk, 1, EncKey::random(net.rng()))
+            .await
+            .unwrap();
+        let kex_psk = PubSigKey::from(&SecSigKey::from_bytes(&[1; 32]));
+        net.update_peer_addr(kex_psk, "127.0.0.1:1".parse().unwrap())
+            .await;
+        net.inc_keepalive(kex_psk).await;
+        // reaper, rekeyer, keepalive, stream and key exchange
+        assert!(metrics.num_alive_tasks() >= 5);
+        net.shutdown().await;
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(metrics.num_alive_tasks(), 0);
+        // nothing keeps the Net, and so the socket, alive
+        assert!(Arc::into_inner(net).is_some());
+    }
+    #[tokio::test]
+    async fn start_initting_once() {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let net = test_net("127.0.0.1:0").await;
+        let psk = PubSigKey::dummy();
+        let addr: PeerAddr = "127.0.0.1:1".parse().unwrap();
+        net.start_initting(psk, addr).await;
+        let tasks = metrics.num_alive_tasks();
+        let skk = PubKexKey::from(&net.initting.get_async(&(psk, addr)).await.unwrap().get().0);
+        // a second exchange would send a pkk whose skk is not kept
+        net.start_initting(psk, addr).await;
+        assert_eq!(metrics.num_alive_tasks(), tasks);
+        assert_eq!(
+            PubKexKey::from(&net.initting.get_async(&(psk, addr)).await.unwrap().get().0),
+            skk
+        );
+        net.shutdown().await;
+    }
+    #[tokio::test]
+    async fn finished_initting_removed() {
+        let net = test_net("127.0.0.1:0").await;
+        let addr: PeerAddr = "127.0.0.1:1".parse().unwrap();
+        for i in 0..4 {
+            let psk = PubSigKey::from(&SecSigKey::from_bytes(&[i; 32]));
+            net.start_initting(psk, addr).await;
+            if i % 2 == 0 {
+                net.initting
+                    .get_async(&(psk, addr))
+                    .await
+                    .unwrap()
+                    .get()
+                    .2
+                    .abort();
+            }
+        }
+        sleep(Duration::from_millis(50)).await;
+        net.remove_finished_initting().await;
+        assert_eq!(net.initting.len(), 2);
+        net.shutdown().await;
+    }
+    #[tokio::test]
+    async fn full_initting_swept() {
+        let net = test_net("127.0.0.1:0").await;
+        let addr: PeerAddr = "127.0.0.1:1".parse().unwrap();
+        for i in 0..1024u32 {
+            let mut key = [0; 32];
+            key[..4].copy_from_slice(&i.to_le_bytes());
+            let psk = PubSigKey::from(&SecSigKey::from_bytes(&key));
+            net.start_initting(psk, addr).await;
+            net.initting
+                .get_async(&(psk, addr))
+                .await
+                .unwrap()
+                .get()
+                .2
+                .abort();
+        }
+        sleep(Duration::from_millis(50)).await;
+        // all of them gave up, so they don't count
+        assert!(net.allow_kex_attempt(addr).await);
+        assert!(net.initting.is_empty());
+    }
+    #[tokio::test]
+    async fn kex_attempts_capped() {
+        let net = test_net("127.0.0.1:0").await;
+        for port in 0..4096 {
+            assert!(
+                net.allow_kex_attempt(PeerAddr::from(std::net::SocketAddr::from((
+                    [127, 0, 0, 1],
+                    port
+                ))))
+                .await
+            );
+        }
+        // all of them are still in the window
+        assert!(!net.allow_kex_attempt("127.0.0.2:1".parse().unwrap()).await);
+        assert!(net.allow_kex_attempt("127.0.0.1:1".parse().unwrap()).await);
+        assert_eq!(net.kex_attempts.len(), 4096);
+    }
+    #[tokio::test]
+    async fn spectator_submission_rejected() {
+        let net = test_server("127.0.0.1:0").await;
+        let server_addr = std::net::SocketAddr::from(net.sw.own_addr().unwrap());
+        let (peer, psk, mac_key) = fake_peer(&net, Entity::Spectator).await;
+        let submission = Message::Submission(Macced::new(
+            SubmissionMessage::new(
+                0,
+                Mac(blake3::hash(b"source")),
+                6,
+                LimitsPreset::Native,
+                EncKey::random(&RngSource::Thread),
+            ),
+            &mac_key,
+        ));
+        let submission = packet(&submission);
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        peer.send_to(&submission, server_addr).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), net.recv(&mut buf))
+                .await
+                .is_err()
+        );
+        net.connections
+            .get_async(&psk)
+            .await
+            .unwrap()
+            .get_mut()
+            .set_entity(Entity::Participant);
+        peer.send_to(&submission, server_addr).await.unwrap();
+        let (m, from) = tokio::time::timeout(Duration::from_millis(200), net.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(m, RecvMessage::Submission(_)));
+        assert_eq!(from, psk);
+    }
+    #[tokio::test]
+    async fn queue_resend() {
+        let net = test_server("127.0.0.1:0").await;
+        let server_addr = std::net::SocketAddr::from(net.sw.own_addr().unwrap());
+        let (peer, psk, mac_key) = fake_peer(&net, Entity::Participant).await;
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let mut pbuf = [0u8; MAX_PACKET_SIZE];
+        for id in 0..3 {
+            let message =
+                QueueMessageInner::Announcement(QAnnouncement::new(id.to_string(), None).unwrap());
+            let m = QueueMessage {
+                id,
+                timestamp: SystemTime::now(),
+                message,
+            };
+            net.send(SendMessage::Queue(m), psk).await.unwrap();
+            peer.recv(&mut pbuf).await.unwrap();
+        }
+        let request = Message::Request(Macced::new(RequestMessage::Queue(vec![(1, 5)]), &mac_key));
+        peer.send_to(&packet(&request), server_addr).await.unwrap();
+        let _ = tokio::time::timeout(Duration::from_millis(200), net.recv(&mut buf)).await;
+        let mut ids = vec![];
+        while let Ok(Ok(len)) =
+            tokio::time::timeout(Duration::from_millis(100), peer.recv(&mut pbuf)).await
+        {
+            let Ok(Message::Queue(part)) = decode(&pbuf[..len], PROTOCOL_VERSION) else {
+                panic!("expected a queue message");
+            };
+            ids.push(part.inner(&mac_key).unwrap().id);
+        }
+        assert_eq!(ids, vec![1, 2]);
+    }
+    #[tokio::test]
+    async fn enc_key_request() {
+        let net = test_server("127.0.0.1:0").await;
+        let (peer, psk, mac_key) = fake_peer(&net, Entity::Participant).await;
+        let keys = EncKeyStore::new();
+        let key = EncKey::random(&RngSource::Thread);
+        for id in [
+            EncKeyId::IsEntity(Entity::Worker),
+            EncKeyId::IsEntity(Entity::Participant),
+        ] {
+            keys.add(EncKeyInfo { id, key }).await;
+        }
+        let answer = |id| {
+            net.answer_enc_key_request(
+                &keys,
+                id,
+                psk,
+                std::collections::HashSet::new(),
+                std::collections::HashSet::new(),
+            )
+        };
+        assert!(!answer(EncKeyId::IsEntity(Entity::Worker)).await.unwrap());
+        assert!(answer(EncKeyId::IsEntity(Entity::Participant))
+            .await
+            .unwrap());
+        let mut pbuf = [0u8; MAX_PACKET_SIZE];
+        let len = tokio::time::timeout(Duration::from_millis(100), peer.recv(&mut pbuf))
+            .await
+            .unwrap()
+            .unwrap();
+        let Ok(Message::EncKey(macced)) = decode(&pbuf[..len], PROTOCOL_VERSION) else {
+            panic!("expected an enc key");
+        };
+        assert_eq!(macced.inner(&mac_key).unwrap().key, key);
+        // only one answer was sent
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), peer.recv(&mut pbuf))
+                .await
+                .is_err()
+        );
+    }
+    #[tokio::test]
+    async fn evaluation_from_worker() {
+        let net = test_server("127.0.0.1:0").await;
+        let server_addr = std::net::SocketAddr::from(net.sw.own_addr().unwrap());
+        let (peer, psk, mac_key) = fake_peer(&net, Entity::Worker).await;
+        let proof = |evaluator| {
+            let evaluation_id = EvaluationId {
+                submission_id: SubmissionId {
+                    submitter: PubSigKey::dummy(),
+                    problem_id: 0,
+                    file_id: Mac(blake3::hash(b"source")),
+                },
+                evaluator,
+            };
+            let m = EvaluationMessage::Proof(QEvaluationProof {
+                evaluation_id,
+                detailhs: Mac(blake3::hash(b"details")),
+                test_hashes: vec![],
+            });
+            packet(&Message::Evaluation(Macced::new(m, &mac_key)))
+        };
+        // about someone else's evaluation
+        peer.send_to(&proof(net.psk()), server_addr).await.unwrap();
+        peer.send_to(&proof(psk), server_addr).await.unwrap();
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let (m, from) = tokio::time::timeout(Duration::from_millis(200), net.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(m, RecvMessage::Evaluation(m) if m.evaluation_id().evaluator == psk));
+        assert_eq!(from, psk);
+        let stats = net.stats();
+        assert_eq!(stats.received_per_kind.evaluation, 2);
+        assert_eq!(stats.invalid, 1);
+    }
+    #[tokio::test]
+    async fn wait_connection_timeout() {
+        let net = test_net("127.0.0.1:0").await;
+        let psk = PubSigKey::dummy();
+        // nobody is listening there
+        net.update_peer_addr(psk, "127.0.0.1:1".parse().unwrap())
+            .await;
+        net.inc_keepalive(psk).await;
+        assert!(
+            !net.wait_connection_timeout(psk, Duration::from_millis(100))
+                .await
+        );
+        assert!(net.initting.is_empty());
+    }
+    #[tokio::test]
+    async fn stats_mac_failed() {
+        let net = test_net("127.0.0.1:0").await;
+        let server_addr = std::net::SocketAddr::from(net.sw.own_addr().unwrap());
+        let (peer, _, _) = fake_peer(&net, Entity::Participant).await;
+        let skk = SecKexKey::random_from_rng(rand::thread_rng());
+        let pkk = PubKexKey::from(&skk);
+        let wrong_mac_key = MacKey::from(skk.diffie_hellman(&pkk.into()));
+        let request = Message::Request(Macced::new(
+            RequestMessage::Queue(vec![(0, 0)]),
+            &wrong_mac_key,
+        ));
+        peer.send_to(&packet(&request), server_addr).await.unwrap();
+        peer.send_to(&[PROTOCOL_VERSION, 255, 255], server_addr)
+            .await
+            .unwrap();
+        peer.send_to(&[PROTOCOL_VERSION + 1, 255, 255], server_addr)
+            .await
+            .unwrap();
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let recv = net.recv(&mut buf);
+        assert!(tokio::time::timeout(Duration::from_millis(200), recv)
+            .await
+            .is_err());
+        let stats = net.stats();
+        assert_eq!(stats.received, 1);
+        assert_eq!(stats.received_per_kind.request, 1);
+        assert_eq!(stats.mac_failed, 1);
+        assert_eq!(stats.malformed, 1);
+        assert_eq!(stats.wrong_version, 1);
+    }
+    #[tokio::test]
+    async fn keepalive_rtt() {
+        let net = test_net("127.0.0.1:0").await;
+        let server_addr = std::net::SocketAddr::from(net.sw.own_addr().unwrap());
+        let (peer, psk, mac_key) = fake_peer(&net, Entity::Participant).await;
+        assert_eq!(net.rtt(psk).await, None);
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        // a keepalive from the peer gets answered with its own timestamp
+        let sent = SystemTime::now();
+        let ka = Message::Net(NetMessage::KeepAlive(
+            psk,
+            Macced::new(KeepAliveInner(sent), &mac_key),
+        ));
+        peer.send_to(&packet(&ka), server_addr).await.unwrap();
+        let recv = net.recv(&mut buf);
+        let _ = tokio::time::timeout(Duration::from_millis(100), recv).await;
+        let mut pbuf = [0u8; MAX_PACKET_SIZE];
+        let len = tokio::time::timeout(Duration::from_millis(100), peer.recv(&mut pbuf))
+            .await
+            .unwrap()
+            .unwrap();
+        let Ok(Message::Net(NetMessage::KeepAliveAck(from, macced))) =
+            decode(&pbuf[..len], PROTOCOL_VERSION)
+        else {
+            panic!("expected a keepalive ack");
+        };
+        assert_eq!(from, net.psk());
+        assert_eq!(macced.inner(&mac_key).unwrap().0, sent);
+        // an ack for a keepalive sent 100ms ago gives an rtt sample
+        let ack = Message::Net(NetMessage::KeepAliveAck(
+            psk,
+            Macced::new(
+                KeepAliveAckInner(sent - Duration::from_millis(100), SystemTime::now()),
+                &mac_key,
+            ),
+        ));
+        peer.send_to(&packet(&ack), server_addr).await.unwrap();
+        let recv = net.recv(&mut buf);
+        let _ = tokio::time::timeout(Duration::from_millis(100), recv).await;
+        let rtt = net.rtt(psk).await.unwrap();
+        assert!(rtt >= Duration::from_millis(100));
+        assert!(rtt < Duration::from_secs(1));
+    }
+    #[tokio::test]
+    async fn reap_kept_alive() {
+        let net = test_net("127.0.0.1:0").await;
+        let (_peer, psk, _) = fake_peer(&net, Entity::Server).await;
+        // the peer never answers our keepalives
+        net.inc_keepalive(psk).await;
+        net.reap_idle_connections(Duration::from_secs(60)).await;
+        assert!(net.is_connected(psk).await);
+        sleep(Duration::from_millis(20)).await;
+        net.reap_idle_connections(Duration::from_millis(10)).await;
+        assert!(!net.is_connected(psk).await);
+    }
+    #[tokio::test]
+    async fn timestamp_window() {
+        let net = test_net("127.0.0.1:0")
+            .await
+            .with_timestamp_window(TimestampWindow {
+                future: Duration::from_secs(1),
+                past: Duration::from_secs(1),
+            });
+        let server_addr = std::net::SocketAddr::from(net.sw.own_addr().unwrap());
+        let (peer, psk, mac_key) = fake_peer(&net, Entity::Participant).await;
+        let now = SystemTime::now();
+        for timestamp in [
+            now - Duration::from_secs(2),
+            now + Duration::from_secs(2),
+            now - Duration::from_millis(500),
+        ] {
+            let ka = Message::Net(NetMessage::KeepAlive(
+                psk,
+                Macced::new(KeepAliveInner(timestamp), &mac_key),
+            ));
+            peer.send_to(&packet(&ka), server_addr).await.unwrap();
+        }
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let recv = net.recv(&mut buf);
+        let _ = tokio::time::timeout(Duration::from_millis(200), recv).await;
+        assert_eq!(net.stats().invalid, 2);
+        // only the one inside the window is answered
+        let mut pbuf = [0u8; MAX_PACKET_SIZE];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), peer.recv(&mut pbuf))
+                .await
+                .is_ok()
+        );
+    }
+    #[tokio::test]
+    async fn finalize_moves_connection() {
+        let net = test_net("127.0.0.1:0").await;
+        let psk = PubSigKey::dummy();
+        let old_addr: PeerAddr = "127.0.0.1:1".parse().unwrap();
+        let new_addr: PeerAddr = "127.0.0.1:2".parse().unwrap();
+        let _ = net
+            .initting
+            .insert_async(
+                (psk, old_addr),
+                new_initting(net.sw.clone(), old_addr, net.obfuscation_key).await,
+            )
+            .await;
+        net.finalize_connection(
+            psk,
+            old_addr,
+            (MacKeys::symmetric(MacKey::dummy()), None),
+            Entity::Participant,
+            None,
+        )
+        .await;
+        net.finalize_connection(
+            psk,
+            new_addr,
+            (MacKeys::symmetric(MacKey::dummy()), None),
+            Entity::Participant,
+            None,
+        )
+        .await;
+        assert_eq!(net.connections.len(), 1);
+        assert_eq!(
+            net.connections.get_async(&psk).await.unwrap().get().addr(),
+            new_addr
+        );
+        assert_eq!(
+            *net.psk_to_addr.get_async(&psk).await.unwrap().get(),
+            new_addr
+        );
+        assert!(net.addr_to_psk.get_async(&old_addr).await.is_none());
+        assert!(net.addr_to_psk.get_async(&new_addr).await.is_some());
+        assert!(net.initting.is_empty());
+    }
+    type Received = tokio::sync::mpsc::UnboundedReceiver<(RecvMessage, PubSigKey)>;
+    /// receives in the background, delivering the messages to the returned channel
+    fn recv_loop(net: Arc<Net>) -> Received {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        task::spawn(async move {
+            let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                if let Ok(m) = net.recv(&mut buf).await {
+                    let _ = tx.send(m);
+                }
+            }
+        });
+        rx
+    }
+    /// two nets on loopback keeping each other alive, with their recv_loop channels
+    async fn net_pair_received() -> ((Arc<Net>, Received), (Arc<Net>, Received)) {
+        pair_received(
+            test_net_with_key("127.0.0.1:0", [1u8; 32]).await,
+            test_net_with_key("127.0.0.1:0", [2u8; 32]).await,
+        )
+        .await
+    }
+    /// like net_pair_received, with the given nets
+    async fn pair_received(a: Net, b: Net) -> ((Arc<Net>, Received), (Arc<Net>, Received)) {
+        let (a, b) = (Arc::new(a), Arc::new(b));
+        let (a_rx, b_rx) = (recv_loop(a.clone()), recv_loop(b.clone()));
+        let ka_interval = KeepAliveInterval {
+            min: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        for (net, other) in [(&a, &b), (&b, &a)] {
+            net.set_keepalive_interval(other.psk(), ka_interval).await;
+            net.update_peer_addr(other.psk(), other.own_addr().unwrap())
+                .await;
+        }
+        ((a, a_rx), (b, b_rx))
+    }
+    async fn net_pair() -> (Arc<Net>, Arc<Net>) {
+        let ((a, _), (b, _)) = net_pair_received().await;
+        (a, b)
+    }
+    /// connects a and b and waits until both key exchanges are over,
+    /// both connect since clients don't accept connections
+    async fn connect_pair(a: &Net, b: &Net) {
+        tokio::join!(a.inc_keepalive(b.psk()), b.inc_keepalive(a.psk()));
+        let timeout = Duration::from_secs(5);
+        assert!(a.wait_connection_timeout(b.psk(), timeout).await);
+        assert!(b.wait_connection_timeout(a.psk(), timeout).await);
+        wait_kex_done(a, b).await;
+    }
+    async fn mac_keys(net: &Net, psk: PubSigKey) -> Option<MacKeys> {
+        net.connections
+            .get_async(&psk)
+            .await
+            .map(|c| c.get().mac_keys())
+    }
+    /// waits until the keepalives are accepted on both sides, which ends both exchanges
+    async fn wait_kex_done(a: &Net, b: &Net) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !a.initting.is_empty() || !b.initting.is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+    #[tokio::test]
+    async fn simultaneous_connect() {
+        let (a, b) = net_pair().await;
+        // both start a key exchange before hearing from the other
+        tokio::join!(a.inc_keepalive(b.psk()), b.inc_keepalive(a.psk()));
+        let timeout = Duration::from_secs(5);
+        assert!(a.wait_connection_timeout(b.psk(), timeout).await);
+        assert!(b.wait_connection_timeout(a.psk(), timeout).await);
+        wait_kex_done(&a, &b).await;
+        assert_eq!(
+            mac_keys(&a, b.psk()).await,
+            mac_keys(&b, a.psk()).await.map(MacKeys::reversed)
+        );
+        assert!(a.stats().mac_failed == 0 && b.stats().mac_failed == 0);
+    }
+    #[tokio::test]
+    async fn request_delivered() {
+        let ((a, _), (b, mut b_received)) = net_pair_received().await;
+        connect_pair(&a, &b).await;
+        let m = RequestMessage::File(Mac([7u8; 32].into()), vec![(0, 3)]);
+        a.send(SendMessage::Request(m.clone()), b.psk())
+            .await
+            .unwrap();
+        let (received, psk) = tokio::time::timeout(Duration::from_secs(5), b_received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(psk, a.psk());
+        assert!(matches!(received, RecvMessage::Request(r) if r == m));
+    }
+    #[tokio::test]
+    async fn send_queue() {
+        let a = Net::builder(
+            SecSigKey::from_bytes(&[1u8; 32]),
+            Entity::Participant,
+            0,
+            Filter::reject_all(),
+        )
+        .bind_addr("127.0.0.1:0".parse().unwrap())
+        .send_queue(4)
+        .build()
+        .await
+        .unwrap();
+        let b = test_net_with_key("127.0.0.1:0", [2u8; 32]).await;
+        let ((a, _), (b, mut b_received)) = pair_received(a, b).await;
+        connect_pair(&a, &b).await;
+        let request = |i| RequestMessage::File(Mac([7u8; 32].into()), vec![(i, i)]);
+        // more than the queue holds, send waits for room
+        for i in 0..32 {
+            a.send(SendMessage::Request(request(i)), b.psk())
+                .await
+                .unwrap();
+        }
+        for i in 0..32 {
+            let (received, _psk) = tokio::time::timeout(Duration::from_secs(5), b_received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(matches!(received, RecvMessage::Request(r) if r == request(i)));
+        }
+        // still checked when sending, not when the message gets its turn
+        let too_large = RequestMessage::File(Mac([7u8; 32].into()), vec![(0, 0); 1000]);
+        assert!(matches!(
+            a.send(SendMessage::Request(too_large), b.psk()).await,
+            Err(SendError::MessageTooLarge)
+        ));
+        assert!(matches!(
+            a.send(SendMessage::Request(request(0)), PubSigKey::dummy())
+                .await,
+            Err(SendError::NotConnected)
+        ));
+    }
+    #[tokio::test]
+    async fn send_queue_rekey() {
+        let net = Net::builder(
+            SecSigKey::from_bytes(&[1u8; 32]),
+            Entity::Participant,
+            0,
+            Filter::reject_all(),
+        )
+        .bind_addr("127.0.0.1:0".parse().unwrap())
+        .send_queue(4)
+        .build()
+        .await
+        .unwrap();
+        let (peer, psk, _) = fake_peer(&net, Entity::Server).await;
+        let new_key = MacKey::from([3u8; 32]);
+        let mut c = net.connections.get_async(&psk).await.unwrap();
+        net.send_stream_message(psk, StreamMessage::Ack(1, 2))
+            .await
+            .unwrap();
+        // the connection is rekeyed while the message waits in the queue
+        c.get_mut().mac_keys = MacKeys::symmetric(new_key);
+        drop(c);
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let length = tokio::time::timeout(Duration::from_secs(5), peer.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            decode(&buf[..length], PROTOCOL_VERSION).unwrap(),
+            Message::Stream(Macced::new(StreamMessage::Ack(1, 2), &new_key))
+        );
+    }
+    #[tokio::test]
+    async fn repeat_message() {
+        let net = test_net("127.0.0.1:0").await;
+        let (peer, psk, mac_key) = fake_peer(&net, Entity::Server).await;
+        let m = Outgoing::KeepAlive(KeepAliveInner(SystemTime::now()));
+        let message = m.clone().mac(net.psk(), &mac_key).unwrap();
+        let delays = [Duration::from_millis(10), Duration::from_millis(20)];
+        net.transmitter().repeat(psk, m, &delays).await;
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        for _ in 0..3 {
+            let length = peer.recv(&mut buf).await.unwrap();
+            assert_eq!(decode(&buf[..length], PROTOCOL_VERSION).unwrap(), message);
+        }
+    }
+    #[tokio::test]
+    async fn server_and_participant() {
+        let server = Arc::new(test_server("127.0.0.1:0").await);
+        let participant = Net::builder(
+            SecSigKey::from_bytes(&[1u8; 32]),
+            Entity::Participant,
+            0,
+            Filter::reject_all(),
+        )
+        .bind_addr("127.0.0.1:0".parse().unwrap())
+        .server_psk(server.psk())
+        .build()
+        .await
+        .unwrap();
+        let participant = Arc::new(participant);
+        let mut server_received = recv_loop(server.clone());
+        let mut participant_received = recv_loop(participant.clone());
+        let server_psk = server.psk();
+        participant
+            .update_peer_addr(server_psk, server.own_addr().unwrap())
+            .await;
+        participant.inc_keepalive(server_psk).await;
+        let timeout = Duration::from_secs(5);
+        assert!(
+            participant
+                .wait_connection_timeout(server_psk, timeout)
+                .await
+        );
+        tokio::time::timeout(timeout, async {
+            while !server.is_connected(participant.psk()).await {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let m = SubmissionMessage::new(
+            0,
+            Mac(blake3::hash(b"source")),
+            6,
+            LimitsPreset::Native,
+            EncKey::random(&RngSource::Thread),
+        );
+        participant
+            .send(SendMessage::Submission(m), server_psk)
+            .await
+            .unwrap();
+        let (received, psk) = tokio::time::timeout(timeout, server_received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(psk, participant.psk());
+        assert!(matches!(received, RecvMessage::Submission(_)));
+        let m = QueueMessage {
+            id: 0,
+            timestamp: SystemTime::now(),
+            message: QueueMessageInner::Announcement(
+                QAnnouncement::new("hello".to_string(), None).unwrap(),
+            ),
+        };
+        server
+            .send(SendMessage::Queue(m.clone()), participant.psk())
+            .await
+            .unwrap();
+        let (received, psk) = tokio::time::timeout(timeout, participant_received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(psk, server_psk);
+        assert!(matches!(received, RecvMessage::Queue(q) if q == m));
+        // only the server publishes queue messages
+        assert!(matches!(
+            participant.send(SendMessage::Queue(m), server_psk).await,
+            Err(SendError::NotAllowed)
+        ));
+    }
+    #[tokio::test]
+    async fn rekey() {
+        let (a, b) = net_pair().await;
+        connect_pair(&a, &b).await;
+        let old = mac_keys(&a, b.psk()).await.unwrap();
+        assert!(a.rekey(b.psk()).await.is_ok());
+        wait_kex_done(&a, &b).await;
+        let new = mac_keys(&a, b.psk()).await.unwrap();
+        assert_ne!(new, old);
+        assert_eq!(mac_keys(&b, a.psk()).await, Some(new.reversed()));
+        // messages sent before the peer got the new key are still accepted for a while
+        let m = Macced::new(RequestMessage::Queue(vec![(0, 0)]), &old.send);
+        assert!(b.unmac(m, a.own_addr().unwrap()).await.is_ok());
+        assert!(a.rekey(PubSigKey::dummy()).await.is_err());
+    }
+    #[tokio::test]
+    async fn stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (a, b) = net_pair().await;
+        connect_pair(&a, &b).await;
+        let key = EncKey::random(&RngSource::Thread);
+        let mut sa = a.open_stream(b.psk(), 7, key).await.unwrap();
+        let mut sb = b.open_stream(a.psk(), 7, key).await.unwrap();
+        assert!(a.open_stream(b.psk(), 7, key).await.is_err());
+        // more than fits in the window, so the sender has to wait for acks
+        let data: Vec<u8> = (0..3 * stream::WINDOW_BYTES).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let writer = task::spawn(async move {
+            sa.write_all(&sent).await.unwrap();
+            sa.shutdown().await.unwrap();
+            let mut reply = vec![];
+            sa.read_to_end(&mut reply).await.unwrap();
+            reply
+        });
+        let mut received = vec![];
+        tokio::time::timeout(Duration::from_secs(10), sb.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, data);
+        sb.write_all(b"done").await.unwrap();
+        sb.shutdown().await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(10), writer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, b"done");
+        // both ends are done, but still ack retransmissions for a while
+        sleep(Duration::from_millis(100)).await;
+        assert!(a.streams.contains_async(&(b.psk(), 7)).await);
+        sleep(stream::STREAM_LINGER + Duration::from_millis(500)).await;
+        assert!(!a.streams.contains_async(&(b.psk(), 7)).await);
+        assert!(!b.streams.contains_async(&(a.psk(), 7)).await);
+    }
+    #[tokio::test]
+    async fn bootstrap() {
+        let net = test_net("127.0.0.1:0").await;
+        let peers: Vec<(PubSigKey, PeerAddr)> = (1..4)
+            .map(|i| {
+                (
+                    PubSigKey::from(&SecSigKey::from_bytes(&[i; 32])),
+                    format!("127.0.0.1:{}", i).parse().unwrap(),
+                )
+            })
+            .collect();
+        net.bootstrap(peers.clone()).await;
+        for (psk, addr) in peers {
+            assert_eq!(*net.psk_to_addr.get_async(&psk).await.unwrap().get(), addr);
+            assert_eq!(*net.addr_to_psk.get_async(&addr).await.unwrap().get(), psk);
+        }
+        assert!(net.connections.is_empty());
+    }
+    #[tokio::test]
+    async fn rendezvous_starts_kex() {
+        let net = test_net("127.0.0.1:0").await;
+        let net_addr = std::net::SocketAddr::from(net.own_addr().unwrap());
+        let (server, server_psk, mac_key) = fake_peer(&net, Entity::Participant).await;
+        let other = PubSigKey::from(&SecSigKey::from_bytes(&[1; 32]));
+        let other_addr: PeerAddr = "127.0.0.1:1".parse().unwrap();
+        let rendezvous = |timestamp| {
+            let info = QPeerInfo {
+                psk: other,
+                addr: Obfuscated::new(other_addr, &net.obfuscation_key),
+                entity: Entity::Participant,
+            };
+            packet(&Message::Net(NetMessage::Rendezvous(
+                server_psk,
+                Macced::new((info, timestamp), &mac_key),
+            )))
+        };
+        let m = rendezvous(SystemTime::now());
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        for entity in [Entity::Participant, Entity::Server] {
+            net.connections
+                .get_async(&server_psk)
+                .await
+                .unwrap()
+                .get_mut()
+                .set_entity(entity);
+            server.send_to(&m, net_addr).await.unwrap();
+            let recv = net.recv(&mut buf);
+            assert!(tokio::time::timeout(Duration::from_millis(100), recv)
+                .await
+                .is_err());
+            // only the server can introduce peers
+            assert_eq!(
+                net.initting.contains_async(&(other, other_addr)).await,
+                entity == Entity::Server
+            );
+        }
+        // a copy, eg captured and replayed to redirect the key exchange, or a stale one
+        for m in [m, rendezvous(SystemTime::now() - Duration::from_secs(60))] {
+            server.send_to(&m, net_addr).await.unwrap();
+        }
+        let recv = net.recv(&mut buf);
+        let _ = tokio::time::timeout(Duration::from_millis(100), recv).await;
+        let stats = net.stats();
+        assert_eq!(stats.unexpected, 1);
+        assert_eq!(stats.replayed, 1);
+        assert_eq!(stats.invalid, 1);
+    }
+    #[test]
+    fn behind_nat() {
+        let addr = |s: &str| s.parse::<PeerAddr>().unwrap();
+        assert!(!is_behind_nat(addr("1.2.3.4:5"), addr("1.2.3.4:5")));
+        assert!(!is_behind_nat(addr("[::]:5"), addr("1.2.3.4:5")));
+        assert!(is_behind_nat(addr("[::]:5"), addr("1.2.3.4:6")));
+        assert!(is_behind_nat(addr("192.168.1.2:5"), addr("1.2.3.4:5")));
+    }
+    #[test]
+    fn keepalive_phase() {
+        let interval = KeepAliveInterval::default();
+        let phases: Vec<Duration> = (0..16)
+            .map(|_| interval.phase(&RngSource::Thread))
+            .collect();
+        assert!(phases.iter().all(|&phase| phase <= interval.max));
+        assert!(phases.iter().any(|&phase| phase > Duration::ZERO));
+    }
+    #[test]
+    fn replay_cache() {
+        let cache = ReplayCache::new();
+        let window = TimestampWindow::default();
+        let now = SystemTime::now();
+        let psk = PubSigKey::dummy();
+        assert!(cache.check(psk, [1; 32], now, &window));
+        assert!(!cache.check(psk, [1; 32], now, &window));
+        assert!(cache.check(psk, [2; 32], now, &window));
+        // expired entries go first
+        let old = now - Duration::from_secs(3600);
+        assert!(cache.check(psk, [3; 32], old, &window));
+        assert!(cache.check(psk, [4; 32], now, &window));
+        assert!(cache.check(psk, [3; 32], old, &window));
+        // then the oldest ones, when full
+        for i in 0..ReplayCache::MAX_ENTRIES as u32 {
+            let mut digest = [5; 32];
+            digest[..4].copy_from_slice(&i.to_le_bytes());
+            let timestamp = now + Duration::from_millis(1);
+            assert!(cache.check(psk, digest, timestamp, &window));
+        }
+        assert_eq!(
+            cache.inner.lock().unwrap().seen.len(),
+            ReplayCache::MAX_ENTRIES
+        );
+        assert!(cache.check(psk, [1; 32], now, &window));
     }
 }
@@ -1,30 +1,47 @@
 // Here I define the message type for networking
+use crate::socket::RngSource;
 use chacha20::cipher::{KeyIvInit, StreamCipher};
-use chacha20::ChaCha8;
 use core::hash::Hash;
 use derive_more::{From, Into};
-use ed25519_dalek::Signer;
 use ordered_float::NotNan;
+use rand::{CryptoRng, RngCore};
 use speedy::{Context, LittleEndian, Readable, Reader, Writable, Writer};
+use std::collections::HashSet;
 use std::marker::PhantomData;
 use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 
 pub type Timestamp = SystemTime;
-pub fn is_timestamp_valid(timestamp: Timestamp) -> bool {
+/// how far from now a timestamp can be for a message to be accepted,
+/// the defaults are meant for loosely synchronized clocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampWindow {
+    pub future: Duration,
+    pub past: Duration,
+}
+impl Default for TimestampWindow {
+    fn default() -> Self {
+        Self {
+            future: Duration::from_secs(20),
+            past: Duration::from_secs(40),
+        }
+    }
+}
+pub fn is_timestamp_valid(timestamp: Timestamp, window: &TimestampWindow) -> bool {
     let now = SystemTime::now();
     if timestamp > now {
-        timestamp.duration_since(now).unwrap() < Duration::from_secs(20)
+        timestamp.duration_since(now).unwrap() < window.future
     } else {
-        now.duration_since(timestamp).unwrap() < Duration::from_secs(40)
+        now.duration_since(timestamp).unwrap() < window.past
     }
 }
 
 pub type ContestId = u128;
 pub type ProblemId = u32;
 pub type SecSigKey = ed25519_dalek::SigningKey;
-pub type SecKexKey = x25519_dalek::EphemeralSecret;
+// reusable so that the kex can be finalized again if the peer restarts it
+pub type SecKexKey = x25519_dalek::ReusableSecret;
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Readable, Writable)]
 #[repr(u8)]
@@ -96,13 +113,41 @@ impl EncKey {
     fn dummy() -> Self {
         Self([42; 32].into())
     }
-    pub fn random() -> Self {
-        Self(rand::random::<[u8; 32]>().into())
+    pub fn random(rng: &RngSource) -> Self {
+        rng.with(|rng| Self::random_from_rng(rng))
+    }
+    pub fn random_from_rng<R: RngCore + CryptoRng + ?Sized>(rng: &mut R) -> Self {
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        Self(key.into())
+    }
+    /// a different key for each id, so that one key can be given for several files
+    /// whose chunks are encrypted with the same nonces
+    pub fn derive(&self, id: &[u8]) -> Self {
+        let key: [u8; 32] = self.0.into();
+        Self((*blake3::keyed_hash(&key, id).as_bytes()).into())
     }
 }
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone, From, Into)]
 pub struct EncNonce(chacha20::Nonce);
+impl EncNonce {
+    pub fn random(rng: &RngSource) -> Self {
+        rng.with(|rng| Self::random_from_rng(rng))
+    }
+    pub fn random_from_rng<R: RngCore + CryptoRng + ?Sized>(rng: &mut R) -> Self {
+        let mut nonce = [0u8; 12];
+        rng.fill_bytes(&mut nonce);
+        Self(nonce.into())
+    }
+    /// never repeats for different counters under the same key,
+    /// unlike random nonces that may collide after many messages
+    pub fn from_counter(counter: u64) -> Self {
+        let mut octets = [0xffu8; 12];
+        octets[..8].copy_from_slice(&counter.to_le_bytes());
+        Self(octets.into())
+    }
+}
 impl<'a, C> Readable<'a, C> for EncNonce
 where
     C: Context,
@@ -201,6 +246,12 @@ impl From<&SecSigKey> for PubSigKey {
         Self(ssk.verifying_key())
     }
 }
+// same format accepted by from_str
+impl std::fmt::Display for PubSigKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0.as_bytes()))
+    }
+}
 impl FromStr for PubSigKey {
     type Err = anyhow::Error;
 
@@ -274,6 +325,50 @@ impl MacKey {
         Self(alice_shared_secret.to_bytes())
     }
 }
+/// the keys of a connection, one for each direction,
+/// so that a message reflected back to its sender is not accepted
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct MacKeys {
+    pub send: MacKey,
+    pub recv: MacKey,
+}
+impl MacKeys {
+    /// both peers get the same pair, swapped,
+    /// whichever of them started the exchange
+    pub fn from_kex(
+        ss: x25519_dalek::SharedSecret,
+        own: (PubSigKey, PubKexKey),
+        peer: (PubSigKey, PubKexKey),
+    ) -> Self {
+        let key = |from: (PubSigKey, PubKexKey), to: (PubSigKey, PubKexKey)| {
+            let mut hasher = blake3::Hasher::new_derive_key("decipi directional mac key");
+            hasher.update(ss.as_bytes());
+            for (psk, pkk) in [from, to] {
+                hasher.update(psk.0.as_bytes());
+                hasher.update(pkk.0.as_bytes());
+            }
+            MacKey(hasher.finalize().into())
+        };
+        Self {
+            send: key(own, peer),
+            recv: key(peer, own),
+        }
+    }
+    /// the same key both ways
+    pub fn symmetric(key: MacKey) -> Self {
+        Self {
+            send: key,
+            recv: key,
+        }
+    }
+    /// the keys as seen by the peer
+    pub fn reversed(self) -> Self {
+        Self {
+            send: self.recv,
+            recv: self.send,
+        }
+    }
+}
 impl From<x25519_dalek::SharedSecret> for MacKey {
     fn from(ss: x25519_dalek::SharedSecret) -> Self {
         Self(ss.to_bytes())
@@ -282,6 +377,26 @@ impl From<x25519_dalek::SharedSecret> for MacKey {
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone, From, Into)]
 pub struct Signature(ed25519_dalek::Signature);
+
+/// signs with the key of this node,
+/// implement it to keep the key somewhere it can't be read from (eg an hsm)
+pub trait Signer: std::fmt::Debug + Send + Sync {
+    fn sign(&self, msg: &[u8]) -> Signature;
+    fn psk(&self) -> PubSigKey;
+}
+impl Signer for SecSigKey {
+    fn sign(&self, msg: &[u8]) -> Signature {
+        Signature(ed25519_dalek::Signer::sign(self, msg))
+    }
+    fn psk(&self) -> PubSigKey {
+        self.into()
+    }
+}
+impl Signature {
+    pub fn to_bytes(&self) -> [u8; 64] {
+        self.0.to_bytes()
+    }
+}
 impl<'a, C> Readable<'a, C> for Signature
 where
     C: Context,
@@ -362,6 +477,39 @@ where
     }
 }
 
+/// keyed blake3 of data, which is a MAC as long as the key is secret and uniformly random
+pub fn compute_mac(data: &[u8], key: &MacKey) -> Mac {
+    Mac(blake3::keyed_hash(&key.0, data))
+}
+/// checks mac against data in constant time (blake3::Hash's eq is constant time)
+pub fn verify_mac(data: &[u8], key: &MacKey, mac: &Mac) -> bool {
+    compute_mac(data, key).0 == mac.0
+}
+/// same as compute_mac of the serialized data, but hashes it while serializing,
+/// so that checking a received message doesn't allocate
+fn compute_mac_of<T: Writable<LittleEndian>>(data: &T, key: &MacKey) -> Result<Mac, speedy::Error> {
+    let mut hasher = blake3::Hasher::new_keyed(&key.0);
+    data.write_to_stream(&mut hasher)?;
+    Ok(Mac(hasher.finalize()))
+}
+
+/// what a signed T is about, the signature covers the protocol version and the domain
+/// before the data, so that it can't be passed off as a signature of another kind of message
+pub trait SignatureDomain {
+    const DOMAIN: &'static str;
+}
+fn signed_bytes<T, W>(data: &(T, W)) -> Result<Vec<u8>, speedy::Error>
+where
+    T: Writable<LittleEndian> + SignatureDomain,
+    W: Writable<LittleEndian>,
+{
+    let mut buf = vec![PROTOCOL_VERSION];
+    buf.extend_from_slice(T::DOMAIN.as_bytes());
+    buf.push(0);
+    buf.extend(data.write_to_vec()?);
+    Ok(buf)
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable, Copy)]
 pub struct Signed<T, W>
 where
@@ -373,11 +521,11 @@ where
 }
 impl<T, W> Signed<T, W>
 where
-    T: Writable<LittleEndian>,
+    T: Writable<LittleEndian> + SignatureDomain,
     W: Writable<LittleEndian> + Copy,
 {
     pub fn check(&self, pk: &PubSigKey) -> bool {
-        if let Ok(buf) = self.data.write_to_vec() {
+        if let Ok(buf) = signed_bytes(&self.data) {
             pk.0.verify_strict(&buf, &self.signature.0).is_ok()
         } else {
             false
@@ -393,13 +541,17 @@ where
     pub fn who(&self) -> W {
         self.data.1
     }
-    pub fn new(data: (T, W), sk: &SecSigKey) -> Self {
-        let buf = data.write_to_vec().unwrap();
+    pub fn signature(&self) -> Signature {
+        self.signature
+    }
+    /// panics if data can't be serialized, use try_new unless its size is bounded
+    pub fn new<S: Signer + ?Sized>(data: (T, W), sk: &S) -> Self {
+        Self::try_new(data, sk).unwrap()
+    }
+    pub fn try_new<S: Signer + ?Sized>(data: (T, W), sk: &S) -> Result<Self, speedy::Error> {
+        let buf = signed_bytes(&data)?;
         let signature = sk.sign(&buf);
-        Self {
-            data,
-            signature: Signature(signature),
-        }
+        Ok(Self { data, signature })
     }
 }
 #[derive(PartialEq, Eq, Debug, Copy, Clone, Readable, Writable)]
@@ -412,11 +564,7 @@ where
     T: Writable<LittleEndian>,
 {
     pub fn check(&self, key: &MacKey) -> bool {
-        if let Ok(buf) = self.data.write_to_vec() {
-            self.mac.0 == blake3::keyed_hash(&key.0, &buf)
-        } else {
-            false
-        }
+        compute_mac_of(&self.data, key).is_ok_and(|mac| mac.0 == self.mac.0)
     }
     pub fn inner(self, key: &MacKey) -> Option<T> {
         if self.check(key) {
@@ -425,41 +573,89 @@ where
             None
         }
     }
+    /// like inner, but accepts any of the keys
+    pub fn inner_any(self, keys: &[MacKey]) -> Option<T> {
+        if keys.iter().any(|key| self.check(key)) {
+            Some(self.data)
+        } else {
+            None
+        }
+    }
+    pub fn mac(&self) -> Mac {
+        self.mac
+    }
+    /// panics if data can't be serialized, use try_new unless its size is bounded
     pub fn new(data: T, key: &MacKey) -> Self {
-        let buf = data.write_to_vec().unwrap();
-        let h = blake3::keyed_hash(&key.0, &buf);
-        Self { data, mac: Mac(h) }
+        Self::try_new(data, key).unwrap()
+    }
+    pub fn try_new(data: T, key: &MacKey) -> Result<Self, speedy::Error> {
+        let mac = compute_mac_of(&data, key)?;
+        Ok(Self { data, mac })
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy, From)]
-pub struct Obfuscated<T: Writable<LittleEndian> + for<'a> Readable<'a, LittleEndian>>(pub T);
 const OBFUSCATION_BYTES: [u8; 32] = [
     185, 174, 209, 69, 42, 248, 31, 131, 3, 22, 177, 242, 148, 120, 109, 165, 163, 207, 114, 158,
     146, 106, 82, 236, 83, 188, 149, 239, 189, 232, 255, 90,
 ];
+/// key xored into obfuscated data, both peers must use the same one
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct ObfuscationKey(pub [u8; 32]);
+impl Default for ObfuscationKey {
+    fn default() -> Self {
+        Self(OBFUSCATION_BYTES)
+    }
+}
+impl ObfuscationKey {
+    pub fn from_contest_id(contest_id: ContestId) -> Self {
+        Self(blake3::derive_key(
+            "decipi obfuscation key",
+            &contest_id.to_le_bytes(),
+        ))
+    }
+    fn apply(&self, data: &mut [u8]) {
+        for (i, v) in data.iter_mut().enumerate() {
+            *v ^= self.0[i & 31];
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Obfuscated<T: Writable<LittleEndian> + for<'a> Readable<'a, LittleEndian>> {
+    data: Vec<u8>,
+    _phantom: PhantomData<T>,
+}
 impl<T> Obfuscated<T> where T: Writable<LittleEndian> + for<'a> Readable<'a, LittleEndian> {
-    pub fn inner(self) -> T {
-        self.0
+    pub fn new(data: T, key: &ObfuscationKey) -> Self {
+        let mut buf = data.write_to_vec().unwrap();
+        key.apply(&mut buf);
+        Self {
+            data: buf,
+            _phantom: PhantomData,
+        }
+    }
+    pub fn inner(self, key: &ObfuscationKey) -> Option<T> {
+        let mut buf = self.data;
+        key.apply(&mut buf);
+        T::read_from_buffer(&buf).ok()
     }
 }
 impl<'a, C, T> Readable<'a, C> for Obfuscated<T>
 where
     C: Context,
-    T: Readable<'a, C>,
     T: Writable<LittleEndian> + for<'b> Readable<'b, LittleEndian>,
 {
     #[inline]
     fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
-        let mut data: Vec<u8> = reader.read_value()?;
-        for (i, v) in data.iter_mut().enumerate() {
-            *v ^= OBFUSCATION_BYTES[i & 31];
-        }
-        Ok(Obfuscated(T::read_from_buffer(&data)?))
+        let data: Vec<u8> = reader.read_value()?;
+        Ok(Obfuscated {
+            data,
+            _phantom: PhantomData,
+        })
     }
     #[inline]
     fn minimum_bytes_needed() -> usize {
-        4 + <T as Readable<'a, C>>::minimum_bytes_needed()
+        <Vec<u8> as Readable<'a, C>>::minimum_bytes_needed()
     }
 }
 impl<C, T> Writable<C> for Obfuscated<T>
@@ -472,18 +668,25 @@ where
     where
         W: ?Sized + Writer<C>,
     {
-        let mut data = self.0.write_to_vec()?;
-        for (i, v) in data.iter_mut().enumerate() {
-            *v ^= OBFUSCATION_BYTES[i & 31];
-        }
-        writer.write_value(&data)
+        writer.write_value(&self.data)
     }
     #[inline]
     fn bytes_needed(&self) -> Result<usize, C::Error> {
-        Ok(4 + self.0.bytes_needed()?)
+        <Vec<u8> as Writable<C>>::bytes_needed(&self.data)
     }
 }
 
+#[cfg(not(feature = "chacha20"))]
+type Cipher = chacha20::ChaCha8;
+#[cfg(feature = "chacha20")]
+type Cipher = chacha20::ChaCha20;
+/// rounds of the cipher used by Encrypted and SizedEncrypted,
+/// peers built with a different one can't decrypt each other's data (see PROTOCOL_VERSION)
+#[cfg(not(feature = "chacha20"))]
+pub const CIPHER_ROUNDS: u8 = 8;
+#[cfg(feature = "chacha20")]
+pub const CIPHER_ROUNDS: u8 = 20;
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Encrypted<T: Writable<LittleEndian> + for<'a> Readable<'a, LittleEndian>> {
     data: Vec<u8>,
@@ -536,14 +739,17 @@ where
     T: Writable<LittleEndian> + for<'a> Readable<'a, LittleEndian>,
 {
     pub fn inner(self, key: &EncKey) -> Option<T> {
-        let mut cipher = ChaCha8::new(&key.0, &self.nonce.into());
+        let mut cipher = Cipher::new(&key.0, &self.nonce.into());
         let mut buf = self.data;
         cipher.apply_keystream(&mut buf);
         T::read_from_buffer(&buf).ok()
     }
-    pub fn new(data: T, key: &EncKey) -> Self {
-        let nonce: EncNonce = EncNonce(rand::random::<[u8; 12]>().into()); //TODO: is this good?
-        let mut cipher = ChaCha8::new(&key.0, &nonce.into());
+    pub fn new(data: T, key: &EncKey, rng: &RngSource) -> Self {
+        Self::with_nonce(data, key, EncNonce::random(rng))
+    }
+    /// the nonce must not be reused with the same key
+    pub fn with_nonce(data: T, key: &EncKey, nonce: EncNonce) -> Self {
+        let mut cipher = Cipher::new(&key.0, &nonce.into());
         let mut buf = data.write_to_vec().unwrap();
         cipher.apply_keystream(&mut buf);
         Encrypted {
@@ -553,6 +759,21 @@ where
         }
     }
 }
+impl Encrypted<Vec<u8>> {
+    /// like inner, but the bytes are decrypted in place and returned without copying them
+    pub fn inner_bytes(self, key: &EncKey) -> Option<Vec<u8>> {
+        let mut cipher = Cipher::new(&key.0, &self.nonce.into());
+        let mut buf = self.data;
+        cipher.apply_keystream(&mut buf);
+        // the serialized Vec<u8> is its u32 length followed by the bytes
+        let len = u32::from_le_bytes(buf.get(..4)?.try_into().ok()?);
+        if len as usize != buf.len() - 4 {
+            return None;
+        }
+        buf.drain(..4);
+        Some(buf)
+    }
+}
 
 // speedy Readable and Writable derives are currently bugged with const generics
 #[derive(PartialEq, Eq, Debug, Clone, Copy)] //, Readable, Writable)]
@@ -609,14 +830,21 @@ where
     T: Writable<LittleEndian> + for<'a> Readable<'a, LittleEndian>,
 {
     pub fn inner(self, key: &EncKey) -> Option<T> {
-        let mut cipher = ChaCha8::new(&key.0, &self.nonce.into());
+        let mut cipher = Cipher::new(&key.0, &self.nonce.into());
         let mut buf = self.data;
         cipher.apply_keystream(&mut buf);
         T::read_from_buffer(&buf).ok()
     }
-    pub fn new(data: T, key: &EncKey) -> Self {
-        let nonce: EncNonce = EncNonce(rand::random::<[u8; 12]>().into()); //TODO: is this good?
-        let mut cipher = ChaCha8::new(&key.0, &nonce.into());
+    pub fn nonce(&self) -> EncNonce {
+        self.nonce
+    }
+    pub fn new(data: T, key: &EncKey, rng: &RngSource) -> Self {
+        Self::with_nonce(data, key, EncNonce::random(rng))
+    }
+    /// the nonce must not be reused with the same key,
+    /// eg use EncNonce::from_counter with the index of a file chunk
+    pub fn with_nonce(data: T, key: &EncKey, nonce: EncNonce) -> Self {
+        let mut cipher = Cipher::new(&key.0, &nonce.into());
         let mut buf = [0u8; N];
         data.write_to_buffer(&mut buf).unwrap();
         cipher.apply_keystream(&mut buf);
@@ -673,7 +901,11 @@ where
 pub struct SubScore(NotNan<f64>);
 impl TryFrom<f64> for SubScore {
     type Error = ();
+    /// scores are in 0..=1
     fn try_from(f: f64) -> Result<Self, Self::Error> {
+        if !(0f64..=1f64).contains(&f) {
+            return Err(());
+        }
         Ok(SubScore(NotNan::new(f).map_err(|_| ())?))
     }
 }
@@ -689,11 +921,7 @@ where
     #[inline]
     fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
         let v: f64 = reader.read_value()?;
-        if (0f64..=1f64).contains(&v) {
-            Ok(Self::try_from(v).unwrap())
-        } else {
-            Err(speedy::Error::custom("score not in 0..=1").into())
-        }
+        Self::try_from(v).map_err(|_| speedy::Error::custom("score not in 0..=1").into())
     }
     #[inline]
     fn minimum_bytes_needed() -> usize {
@@ -717,14 +945,119 @@ where
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone, Hash, Readable, Writable)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone, Hash)]
 pub struct PeerAddr {
     ip: IpAddr,
     port: u16,
+    // only meaningful for ipv6, needed to reach link-local addresses
+    flowinfo: u32,
+    scope_id: u32,
 }
 impl PeerAddr {
     pub fn new(ip: IpAddr, port: u16) -> Self {
-        Self { ip, port }
+        Self {
+            ip,
+            port,
+            flowinfo: 0,
+            scope_id: 0,
+        }
+    }
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    pub fn scope_id(&self) -> u32 {
+        self.scope_id
+    }
+    pub fn flowinfo(&self) -> u32 {
+        self.flowinfo
+    }
+    /// false for addresses no peer can be reached at,
+    /// eg from a buggy or malicious peer info
+    pub fn is_plausible(&self) -> bool {
+        let ip_ok = match self.ip {
+            IpAddr::V4(ip) => !(ip.is_unspecified() || ip.is_multicast() || ip.is_broadcast()),
+            IpAddr::V6(ip) => !(ip.is_unspecified() || ip.is_multicast()),
+        };
+        ip_ok && self.port != 0
+    }
+    /// ignored for ipv4 addresses
+    pub fn with_scope_id(mut self, scope_id: u32) -> Self {
+        if self.ip.is_ipv6() {
+            self.scope_id = scope_id;
+        }
+        self
+    }
+}
+// same as (IpAddr, u16) unless flowinfo or scope_id are set,
+// in which case the ip tag is 2 and they follow the port
+impl<'a, C> Readable<'a, C> for PeerAddr
+where
+    C: Context,
+{
+    #[inline]
+    fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
+        match reader.read_u8()? {
+            0 => Ok(Self::new(
+                IpAddr::V4(reader.read_value()?),
+                reader.read_value()?,
+            )),
+            1 => Ok(Self::new(
+                IpAddr::V6(reader.read_value()?),
+                reader.read_value()?,
+            )),
+            2 => Ok(Self {
+                ip: IpAddr::V6(reader.read_value()?),
+                port: reader.read_value()?,
+                flowinfo: reader.read_value()?,
+                scope_id: reader.read_value()?,
+            }),
+            _ => Err(speedy::Error::custom("invalid PeerAddr ip tag").into()),
+        }
+    }
+    #[inline]
+    fn minimum_bytes_needed() -> usize {
+        7
+    }
+}
+impl<C> Writable<C> for PeerAddr
+where
+    C: Context,
+{
+    #[inline]
+    fn write_to<W>(&self, writer: &mut W) -> Result<(), C::Error>
+    where
+        W: ?Sized + Writer<C>,
+    {
+        match self.ip {
+            IpAddr::V4(ip) => {
+                writer.write_u8(0)?;
+                writer.write_value(&ip)?;
+                writer.write_value(&self.port)
+            }
+            IpAddr::V6(ip) if self.flowinfo == 0 && self.scope_id == 0 => {
+                writer.write_u8(1)?;
+                writer.write_value(&ip)?;
+                writer.write_value(&self.port)
+            }
+            IpAddr::V6(ip) => {
+                writer.write_u8(2)?;
+                writer.write_value(&ip)?;
+                writer.write_value(&self.port)?;
+                writer.write_value(&self.flowinfo)?;
+                writer.write_value(&self.scope_id)
+            }
+        }
+    }
+    #[inline]
+    fn bytes_needed(&self) -> Result<usize, C::Error> {
+        Ok(match self.ip {
+            IpAddr::V4(_) => 7,
+            IpAddr::V6(_) if self.flowinfo == 0 && self.scope_id == 0 => 19,
+            IpAddr::V6(_) => 27,
+        })
     }
 }
 impl FromStr for PeerAddr {
@@ -736,9 +1069,15 @@ impl FromStr for PeerAddr {
 }
 impl From<std::net::SocketAddr> for PeerAddr {
     fn from(addr: std::net::SocketAddr) -> Self {
-        Self {
-            ip: addr.ip(),
-            port: addr.port(),
+        match addr {
+            SocketAddr::V6(addr) if addr.ip().to_ipv4_mapped().is_none() => Self {
+                ip: IpAddr::V6(*addr.ip()),
+                port: addr.port(),
+                flowinfo: addr.flowinfo(),
+                scope_id: addr.scope_id(),
+            },
+            // ipv4 peers seen by a dual stack socket are ipv4-mapped ipv6 addresses
+            _ => Self::new(addr.ip().to_canonical(), addr.port()),
         }
     }
 }
@@ -746,17 +1085,27 @@ impl From<PeerAddr> for std::net::SocketAddr {
     fn from(addr: PeerAddr) -> std::net::SocketAddr {
         match addr.ip {
             IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, addr.port)),
-            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, addr.port, 0, 0)),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                addr.port,
+                addr.flowinfo,
+                addr.scope_id,
+            )),
         }
     }
 }
 
 // to avoid ip fragmentation
 pub const MAX_PACKET_SIZE: usize = 1280;
-pub const MAX_MESSAGE_SIZE: usize = MAX_PACKET_SIZE - 48; // 40 ipv6 header, 8 udp header
-                                                          // check at compile time that a message (in rust memory, not the actual message being transmitted)
-                                                          // fits in the maximum size
-                                                          //const _: () = [(); 1][(core::mem::size_of::<Message>() <= MAX_MESSAGE_SIZE) as usize ^ 1];
+pub const MAX_DATAGRAM_SIZE: usize = MAX_PACKET_SIZE - 48; // 40 ipv6 header, 8 udp header
+/// first byte of every datagram, bump WIRE_VERSION on incompatible changes to Message,
+/// the high bit is set when the cipher has 20 rounds, since such peers can't talk to ChaCha8 ones
+pub const PROTOCOL_VERSION: u8 = WIRE_VERSION | if CIPHER_ROUNDS == 20 { 0x80 } else { 0 };
+const WIRE_VERSION: u8 = 11;
+pub const MAX_MESSAGE_SIZE: usize = MAX_DATAGRAM_SIZE - 1; // 1 version byte
+                                                           // check at compile time that a message (in rust memory, not the actual message being transmitted)
+                                                           // fits in the maximum size
+                                                           //const _: () = [(); 1][(core::mem::size_of::<Message>() <= MAX_MESSAGE_SIZE) as usize ^ 1];
 
 #[allow(clippy::large_enum_variant)]
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
@@ -764,37 +1113,49 @@ pub const MAX_MESSAGE_SIZE: usize = MAX_PACKET_SIZE - 48; // 40 ipv6 header, 8 u
 #[speedy(tag_type = u8)]
 pub enum Message {
     Net(NetMessage),
-    Queue(Macced<Signed<QueueMessage, ()>>),
+    Queue(Macced<QueuePart>),
     File(Macced<FileMessage>),
     EncKey(Macced<EncKeyInfo>),
     Request(Macced<RequestMessage>),
     Submission(Macced<SubmissionMessage>),
     Question(Macced<QuestionMessage>),
+    Evaluation(Macced<EvaluationMessage>),
+    Stream(Macced<StreamMessage>),
+    ChunkHashes(Macced<ChunkHashesMessage>),
 }
 
 // Net
-#[derive(PartialEq, Eq, Debug, Clone, Readable, Writable, Copy)]
+#[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
 #[repr(u8)]
 #[speedy(tag_type = u8)]
 pub enum NetMessage {
     // Entity here is only really useful when connecting to server
     // for choosing to be participant, spectator or whatever
-    Merkle(
-        Signed<
-            (
-                ContestId,
-                Timestamp,
-                PubKexKey,
-                Obfuscated<PeerAddr>,
-                Entity,
-            ),
-            PubSigKey,
-        >,
-    ),
+    Merkle(Signed<MerkleData, PubSigKey>),
     KeepAlive(PubSigKey, Macced<KeepAliveInner>),
+    // sent by the server to both peers of a rendezvous with the address it sees the other at,
+    // so they can start a key exchange with each other at the same time
+    Rendezvous(PubSigKey, Macced<RendezvousInner>),
+    // answer to a keepalive, to measure the round trip time
+    KeepAliveAck(PubSigKey, Macced<KeepAliveAckInner>),
+}
+pub type MerkleData = (
+    ContestId,
+    Timestamp,
+    PubKexKey,
+    Obfuscated<PeerAddr>,
+    Entity,
+);
+impl SignatureDomain for MerkleData {
+    const DOMAIN: &'static str = "decipi merkle";
 }
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable, Copy)]
 pub struct KeepAliveInner(pub Timestamp);
+// the peer to connect to, when the server sent it
+pub type RendezvousInner = (QPeerInfo, Timestamp);
+/// timestamp of the keepalive being answered, time of the answer
+#[derive(PartialEq, Eq, Debug, Clone, Readable, Writable, Copy)]
+pub struct KeepAliveAckInner(pub Timestamp, pub Timestamp);
 
 pub type QueueMessageId = u32;
 // Queue
@@ -804,6 +1165,65 @@ pub struct QueueMessage {
     pub timestamp: Timestamp,
     pub message: QueueMessageInner,
 }
+impl SignatureDomain for QueueMessage {
+    const DOMAIN: &'static str = "decipi queue message";
+}
+pub type SignedQueueMessage = Signed<QueueMessage, ()>;
+// - message tag - mac - id - part - nparts - vec length
+pub const QUEUE_PART_SIZE: usize = MAX_MESSAGE_SIZE - 1 - 32 - 4 - 2 - 2 - 4;
+pub const MAX_QUEUE_PARTS: u16 = 64;
+// a serialized SignedQueueMessage is split in parts that each fit in a packet
+#[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
+pub struct QueuePart {
+    pub id: QueueMessageId,
+    pub part: u16,
+    pub nparts: u16,
+    pub data: Vec<u8>,
+}
+impl QueuePart {
+    pub fn split(id: QueueMessageId, data: &[u8]) -> Vec<Self> {
+        let nparts = data.len().div_ceil(QUEUE_PART_SIZE).max(1) as u16;
+        (0..nparts)
+            .map(|part| {
+                let sl = part as usize * QUEUE_PART_SIZE;
+                let sr = (sl + QUEUE_PART_SIZE).min(data.len());
+                Self {
+                    id,
+                    part,
+                    nparts,
+                    data: data[sl..sr].to_vec(),
+                }
+            })
+            .collect()
+    }
+}
+// the reassembled parts start with one of these
+const QUEUE_RAW: u8 = 0;
+const QUEUE_DEFLATED: u8 = 1;
+pub const MAX_QUEUE_MESSAGE_SIZE: usize = 1 << 20;
+/// prepares a serialized SignedQueueMessage to be split,
+/// compressing it if it does not fit in one part and it helps
+pub fn pack_queue_message(data: &[u8]) -> Vec<u8> {
+    #[cfg(feature = "compression")]
+    if data.len() >= QUEUE_PART_SIZE {
+        let compressed = miniz_oxide::deflate::compress_to_vec(data, 6);
+        if compressed.len() < data.len() {
+            return [&[QUEUE_DEFLATED], &compressed[..]].concat();
+        }
+    }
+    [&[QUEUE_RAW], data].concat()
+}
+/// inverse of pack_queue_message, the signature is checked after this
+pub fn unpack_queue_message(data: &[u8]) -> Option<Vec<u8>> {
+    match data.split_first()? {
+        (&QUEUE_RAW, data) => Some(data.to_vec()),
+        // whatever the compression feature of the sender
+        (&QUEUE_DEFLATED, data) => {
+            miniz_oxide::inflate::decompress_to_vec_with_limit(data, MAX_QUEUE_MESSAGE_SIZE).ok()
+        }
+        _ => None,
+    }
+}
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
 #[repr(u8)]
 #[speedy(tag_type = u8)]
@@ -829,6 +1249,23 @@ pub struct QAnnouncement {
     pub text: String,
     pub context: Option<ProblemId>,
 }
+impl QAnnouncement {
+    pub fn new(text: String, context: Option<ProblemId>) -> anyhow::Result<Self> {
+        check_text_length(&text)?;
+        Ok(Self { text, context })
+    }
+}
+// announcements and questions have their length serialized as a u8
+pub const MAX_TEXT_LENGTH: usize = u8::MAX as usize;
+fn check_text_length(text: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        text.len() <= MAX_TEXT_LENGTH,
+        "text is {} bytes long, at most {} are allowed",
+        text.len(),
+        MAX_TEXT_LENGTH
+    );
+    Ok(())
+}
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Readable, Writable)]
 pub struct SubmissionId {
     pub submitter: PubSigKey,
@@ -845,6 +1282,7 @@ pub struct QSubmission {
     pub submitter: PubSigKey,
     pub problem_id: ProblemId,
     pub file_desc: QFileDesc,
+    pub limits_preset: LimitsPreset,
 }
 impl QSubmission {
     pub fn submission_id(&self) -> SubmissionId {
@@ -872,40 +1310,68 @@ pub struct QEvaluation {
     pub evaluation_id: EvaluationId,
     pub score: SubScore,
     pub detailhs_hash: DetailHash,
+    // of the first MAX_VERDICTS tests, by test_id
+    pub verdicts: Vec<TestVerdict>,
 }
 impl QEvaluation {
-    pub fn new(evp: QEvaluationProof, score: SubScore) -> Self {
+    pub fn new(evp: QEvaluationProof, score: SubScore, verdicts: Vec<TestVerdict>) -> Self {
         let data = evp.evaluation_id.get_public_hash_data();
         let key = evp.detailhs.0;
-        let detailhs_hash = Mac(blake3::keyed_hash(&key.into(), &data));
+        let detailhs_hash = compute_mac(&data, &MacKey::from(<[u8; 32]>::from(key)));
         Self {
             evaluation_id: evp.evaluation_id,
             score,
             detailhs_hash,
+            verdicts,
         }
     }
 }
+/// what happened on a single test, the points it got are only counted in the score
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Readable, Writable)]
+#[repr(u8)]
+#[speedy(tag_type = u8)]
+pub enum TestVerdict {
+    Scored,
+    TLE,
+    MLE,
+    RTE,
+    // the output could not be collected, zero points but not a wrong answer
+    MFO,
+}
+// the verdicts that fit in an evaluation message along with the rest of it
+pub const MAX_VERDICTS: usize = MAX_MESSAGE_SIZE - 1 - 32 - 1 - 100 - 8 - 32 - 4;
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
 pub struct QEvaluationProof {
     pub evaluation_id: EvaluationId,
     pub detailhs: DetailHash,
+    // of the first MAX_TEST_HASHES tests, by test_id,
+    // so that evaluators that disagree can find out on which tests
+    pub test_hashes: Vec<TestHash>,
 }
 impl QEvaluationProof {
     pub fn check(&self, ev: &QEvaluation) -> bool {
         self.evaluation_id != ev.evaluation_id && {
             let data = ev.evaluation_id.get_public_hash_data();
             let key = self.detailhs.0;
-            let p = blake3::keyed_hash(&key.into(), &data);
-            p == ev.detailhs_hash.0
+            verify_mac(
+                &data,
+                &MacKey::from(<[u8; 32]>::from(key)),
+                &ev.detailhs_hash,
+            )
         }
     }
     pub fn hash(&self) -> DetailHash {
         let data = self.evaluation_id.get_public_hash_data();
         let key = self.detailhs.0;
-        Mac(blake3::keyed_hash(&key.into(), &data))
+        compute_mac(&data, &MacKey::from(<[u8; 32]>::from(key)))
     }
 }
 pub type DetailHash = Mac;
+/// the start of the hash of the execution of a test, enough to tell two of them apart,
+/// detailhs is the one that is committed to
+pub type TestHash = [u8; 8];
+// the test hashes that fit in a proof message along with the rest of it
+pub const MAX_TEST_HASHES: usize = (MAX_MESSAGE_SIZE - 1 - 32 - 1 - 100 - 32 - 4) / 8;
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Readable, Writable)]
 #[repr(u8)]
@@ -919,6 +1385,26 @@ pub enum EncKeyId {
     Or(Vec<EncKeyId>), // you have any of these requirements
     And(Vec<EncKeyId>), // you have all of these requirements
 }
+impl EncKeyId {
+    pub fn is_satisfied_by(&self, ctx: &AccessContext) -> bool {
+        match self {
+            Self::CustomPublic(id) => ctx.published.contains(id),
+            Self::IsEntity(entity) => ctx.entity == *entity,
+            Self::IsClient(psk) => ctx.psk == *psk,
+            Self::ProblemSolved(problem_id) => ctx.solved.contains(problem_id),
+            Self::Or(v) => v.iter().any(|x| x.is_satisfied_by(ctx)),
+            Self::And(v) => v.iter().all(|x| x.is_satisfied_by(ctx)),
+        }
+    }
+}
+/// what is known about a requester when deciding if they should get an enc key
+#[derive(Debug, Clone)]
+pub struct AccessContext {
+    pub entity: Entity,
+    pub psk: PubSigKey,
+    pub published: HashSet<u32>, // CustomPublic ids published to the queue
+    pub solved: HashSet<ProblemId>, // problems solved by the requester
+}
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
 pub struct EncKeyInfo {
     pub id: EncKeyId,
@@ -928,16 +1414,55 @@ pub struct EncKeyInfo {
 pub struct QProblemDesc {
     pub id: ProblemId,
     pub statement: QFileDesc,
-    pub generator_file: QFileDesc,
-    pub scorer_file: QFileDesc, // TODO: give unique names to all the scoring phases(?)
-    pub n_testcases: u32,       // TODO: do we care about encrypting this?
+    pub tests: QTests,
+    pub scorer: QScorer,
+    pub n_testcases: u32, // TODO: do we care about encrypting this?
+    // the presets submissions to this problem may declare, set by the organizers
+    pub limits_presets: Vec<LimitsPreset>,
+}
+impl QProblemDesc {
+    pub fn allows(&self, preset: LimitsPreset) -> bool {
+        self.limits_presets.contains(&preset)
+    }
+}
+/// where the inputs of the tests of a problem come from
+#[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
+#[repr(u8)]
+#[speedy(tag_type = u8)]
+pub enum QTests {
+    // a wasm generator, run by the evaluator for each test
+    Generated(QFileDesc),
+    // a serialized Vec<Vec<u8>>, the input of each test by test_id
+    Static(QFileDesc),
+}
+/// how the output of a submission on a test is scored
+#[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
+#[repr(u8)]
+#[speedy(tag_type = u8)]
+pub enum QScorer {
+    // an eval wasm, TODO: give unique names to all the scoring phases(?)
+    Eval(QFileDesc),
+    // a serialized Vec<Vec<u8>>, the expected output of each test by test_id
+    Diff(DiffMode, QFileDesc),
+}
+/// how the output is compared with the expected one by a diff scorer
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Readable, Writable)]
+#[repr(u8)]
+#[speedy(tag_type = u8)]
+pub enum DiffMode {
+    Exact,
+    // ignoring whitespace at the end of lines, and empty lines at the end
+    TrailingWhitespace,
+    // as sequences of whitespace separated tokens
+    Tokens,
 }
 
 pub type FileHash = Mac;
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
 pub struct QFileDesc {
     pub hash: FileHash,
-    pub size: u32,                                      // length in bytes
+    pub chunks_root: Mac, // merkle root over the hashes of the chunks
+    pub size: u32,        // length in bytes
     pub key_encrypting_key: EncKeyId, // id of the key used to encrypt the encrypting key
     pub enc_encrypting_key: SizedEncrypted<EncKey, 32>, // encrypted key used to encrypt the file
 }
@@ -951,6 +1476,30 @@ pub struct FileMessage {
     pub piece: u32, //inc id (offset = piece*FILE_CHUNK_SIZE)
     pub data: SizedEncrypted<FileChunk, FILE_CHUNK_SIZE>,
 }
+// - message tag - mac - hash - first - vec length
+pub const MAX_CHUNK_HASHES: usize = (MAX_MESSAGE_SIZE - 1 - 32 - 32 - 4 - 4) / 32;
+// the hashes of the chunks of a file from the first one, at most MAX_CHUNK_HASHES of them,
+// see RequestMessage::ChunkHashes
+pub type ChunkHashesMessage = (FileHash, u32, Vec<Mac>);
+
+// Evaluation
+/// sent by a worker to the server, to be published to the queue:
+/// first the evaluation, then the proof once the evaluation is in the queue
+#[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
+#[repr(u8)]
+#[speedy(tag_type = u8)]
+pub enum EvaluationMessage {
+    Evaluation(QEvaluation),
+    Proof(QEvaluationProof),
+}
+impl EvaluationMessage {
+    pub fn evaluation_id(&self) -> &EvaluationId {
+        match self {
+            Self::Evaluation(e) => &e.evaluation_id,
+            Self::Proof(p) => &p.evaluation_id,
+        }
+    }
+}
 
 // Question
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
@@ -959,29 +1508,178 @@ pub struct QuestionMessage {
     pub text: String,
     pub context: Option<ProblemId>,
 }
+impl QuestionMessage {
+    pub fn new(text: String, context: Option<ProblemId>) -> anyhow::Result<Self> {
+        check_text_length(&text)?;
+        Ok(Self { text, context })
+    }
+}
 
 // Submission
+/// declared by the submitter, workers expand it to the limits the submission runs with,
+/// only the ones listed in the QProblemDesc are accepted
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Default, Readable, Writable)]
+#[repr(u8)]
+#[speedy(tag_type = u8)]
+pub enum LimitsPreset {
+    // compiled straight to wasm
+    #[default]
+    Native,
+    // run by an interpreter compiled to wasm
+    Interpreted,
+}
+impl FromStr for LimitsPreset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(Self::Native),
+            "interpreted" => Ok(Self::Interpreted),
+            _ => Err(anyhow::anyhow!(
+                "LimitsPreset must be one of: native, interpreted"
+            )),
+        }
+    }
+}
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
 pub struct SubmissionMessage {
     pub problem_id: ProblemId,
     pub file_id: FileHash,
     pub file_size: u32,
+    pub limits_preset: LimitsPreset,
     enc_key: EncKey,
 }
+impl SubmissionMessage {
+    pub fn new(
+        problem_id: ProblemId,
+        file_id: FileHash,
+        file_size: u32,
+        limits_preset: LimitsPreset,
+        enc_key: EncKey,
+    ) -> Self {
+        Self {
+            problem_id,
+            file_id,
+            file_size,
+            limits_preset,
+            enc_key,
+        }
+    }
+    pub fn enc_key(&self) -> EncKey {
+        self.enc_key
+    }
+}
+
+// Stream
+pub type StreamId = u32;
+// - message tag - mac - stream tag - id - offset - fin - vec length - nonce - inner vec length
+pub const STREAM_CHUNK_SIZE: usize = MAX_MESSAGE_SIZE - 1 - 32 - 1 - 4 - 8 - 1 - 4 - 12 - 4;
+/// a byte stream between two peers, see Net::open_stream
+#[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
+#[repr(u8)]
+#[speedy(tag_type = u8)]
+pub enum StreamMessage {
+    // bytes starting at offset, fin if the stream ends after them
+    Data(StreamId, u64, bool, Encrypted<Vec<u8>>),
+    // everything before offset was received
+    Ack(StreamId, u64),
+}
+impl StreamMessage {
+    pub fn stream_id(&self) -> StreamId {
+        match self {
+            Self::Data(id, ..) | Self::Ack(id, _) => *id,
+        }
+    }
+}
 
 // Request
 #[derive(PartialEq, Eq, Debug, Clone, Readable, Writable)]
 #[repr(u8)]
 #[speedy(tag_type = u8)]
 pub enum RequestMessage {
-    File(Vec<(u32, u32)>),  //[id,id]
-    Queue(Vec<(u32, u32)>), //[id,id]
+    File(FileHash, Vec<(u32, u32)>), //[id,id]
+    Queue(Vec<(u32, u32)>),          //[id,id]
     EncKey(EncKeyId),
+    // asks the server to introduce us to a peer, see NetMessage::Rendezvous
+    Rendezvous(PubSigKey),
+    // asks for the chunk hashes of a file from the given one, to check its chunks on arrival
+    ChunkHashes(FileHash, u32),
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    #[test]
+    fn timestamp_window() {
+        let window = TimestampWindow {
+            future: Duration::from_secs(1),
+            past: Duration::from_secs(2),
+        };
+        let now = SystemTime::now();
+        let ms = Duration::from_millis;
+        assert!(is_timestamp_valid(now, &window));
+        assert!(is_timestamp_valid(now + ms(900), &window));
+        // now moves forward, so the future bound is checked with some slack
+        assert!(!is_timestamp_valid(now + ms(1100), &window));
+        assert!(is_timestamp_valid(now - ms(1900), &window));
+        assert!(!is_timestamp_valid(now - ms(2000), &window));
+        let default = TimestampWindow::default();
+        assert!(is_timestamp_valid(now + ms(19_900), &default));
+        assert!(!is_timestamp_valid(now + ms(20_100), &default));
+        assert!(is_timestamp_valid(now - ms(39_900), &default));
+        assert!(!is_timestamp_valid(now - ms(40_000), &default));
+    }
+    #[test]
+    fn directional_mac_keys() {
+        let (a_skk, b_skk) = (
+            SecKexKey::random_from_rng(rand::thread_rng()),
+            SecKexKey::random_from_rng(rand::thread_rng()),
+        );
+        let a = (
+            PubSigKey::from(&SecSigKey::from_bytes(&[1; 32])),
+            PubKexKey::from(&a_skk),
+        );
+        let b = (
+            PubSigKey::from(&SecSigKey::from_bytes(&[2; 32])),
+            PubKexKey::from(&b_skk),
+        );
+        let a_keys = MacKeys::from_kex(a_skk.diffie_hellman(&b.1 .0), a, b);
+        let b_keys = MacKeys::from_kex(b_skk.diffie_hellman(&a.1 .0), b, a);
+        assert_eq!(a_keys, b_keys.reversed());
+        assert_ne!(a_keys.send, a_keys.recv);
+        // a message from a, reflected back to it
+        let m = Macced::new(KeepAliveInner(SystemTime::now()), &a_keys.send);
+        assert!(m.check(&b_keys.recv));
+        assert!(!m.check(&a_keys.recv));
+    }
+    #[test]
+    fn mac_verification() {
+        let key = MacKey::from([42u8; 32]);
+        let mac = compute_mac(b"data", &key);
+        assert!(verify_mac(b"data", &key, &mac));
+        assert!(!verify_mac(b"datb", &key, &mac));
+        assert!(!verify_mac(b"data", &MacKey::from([7u8; 32]), &mac));
+    }
+    #[test]
+    fn streamed_mac() {
+        let key = MacKey::dummy();
+        let data = (7u32, vec![1u8, 2, 3], PROTOCOL_VERSION);
+        let mac = compute_mac_of(&data, &key).unwrap();
+        assert!(verify_mac(&data.write_to_vec().unwrap(), &key, &mac));
+        assert!(Macced::new(data, &key).check(&key));
+    }
+    #[test]
+    fn encrypted_bytes() {
+        let key = EncKey::dummy();
+        let data = vec![42u8; 100];
+        let enc = Encrypted::new(data.clone(), &key, &RngSource::Thread);
+        assert_eq!(enc.clone().inner_bytes(&key), Some(data));
+        assert_eq!(enc.clone().inner_bytes(&key), enc.inner(&key));
+        assert_eq!(
+            Encrypted::new(vec![], &key, &RngSource::Thread).inner_bytes(&key),
+            Some(vec![])
+        );
+    }
     fn get_dummy_mac() -> Mac {
         Mac([42; 32].into())
     }
@@ -992,11 +1690,11 @@ mod test {
         let mac_key = MacKey::dummy();
 
         let hash = get_dummy_mac();
-        let offset = 0u32;
-        let data = SizedEncrypted::<_, FILE_CHUNK_SIZE>::new(file, &enc_key);
+        let piece = 0u32;
+        let data = SizedEncrypted::<_, FILE_CHUNK_SIZE>::new(file, &enc_key, &RngSource::Thread);
 
-        let file_message = FileMessage { hash, offset, data };
-        let macced = Macced::new_from_mac_key_test(file_message, &mac_key);
+        let file_message = FileMessage { hash, piece, data };
+        let macced = Macced::new(file_message, &mac_key);
         let message = Message::File(macced);
 
         let ser = message.write_to_vec().unwrap();
@@ -1005,7 +1703,7 @@ mod test {
         let unser = Message::read_from_buffer(&ser).unwrap();
         assert_eq!(message, unser);
 
-        let unmacced = macced.inner_from_mac_key_test(&mac_key).unwrap();
+        let unmacced = macced.inner(&mac_key).unwrap();
 
         assert_eq!(file_message, unmacced);
 
@@ -1014,21 +1712,197 @@ mod test {
         assert_eq!(file, unenced);
     }
     #[test]
+    fn chunk_hashes_message() {
+        let m = (
+            get_dummy_mac(),
+            0u32,
+            vec![get_dummy_mac(); MAX_CHUNK_HASHES],
+        );
+        let message = Message::ChunkHashes(Macced::try_new(m, &MacKey::dummy()).unwrap());
+        assert!(message.write_to_vec().unwrap().len() <= MAX_MESSAGE_SIZE);
+    }
+    #[test]
+    fn cipher_in_version() {
+        assert_eq!(PROTOCOL_VERSION & 0x7f, WIRE_VERSION);
+        assert_eq!(PROTOCOL_VERSION & 0x80 != 0, CIPHER_ROUNDS == 20);
+    }
+    #[test]
+    fn sub_score_range() {
+        assert!(SubScore::try_from(0.0).is_ok());
+        assert!(SubScore::try_from(1.0).is_ok());
+        assert!(SubScore::try_from(42.0).is_err());
+        assert!(SubScore::try_from(-0.5).is_err());
+        assert!(SubScore::try_from(f64::NAN).is_err());
+        let out_of_range = 42.0f64.write_to_vec().unwrap();
+        assert!(SubScore::read_from_buffer(&out_of_range).is_err());
+    }
+    #[test]
+    fn evaluation_message() {
+        let evp = QEvaluationProof {
+            evaluation_id: EvaluationId {
+                submission_id: SubmissionId {
+                    submitter: PubSigKey::dummy(),
+                    problem_id: 0,
+                    file_id: get_dummy_mac(),
+                },
+                evaluator: PubSigKey::dummy(),
+            },
+            detailhs: get_dummy_mac(),
+            test_hashes: vec![[42; 8]; MAX_TEST_HASHES],
+        };
+        let m = EvaluationMessage::Proof(evp.clone());
+        let message = Message::Evaluation(Macced::try_new(m, &MacKey::dummy()).unwrap());
+        assert!(message.write_to_vec().unwrap().len() <= MAX_MESSAGE_SIZE);
+        let score = SubScore::try_from(0.5).unwrap();
+        let ev = QEvaluation::new(evp, score, vec![TestVerdict::MFO; MAX_VERDICTS]);
+        let m = EvaluationMessage::Evaluation(ev);
+        let message = Message::Evaluation(Macced::try_new(m, &MacKey::dummy()).unwrap());
+        assert_eq!(message.write_to_vec().unwrap().len(), MAX_MESSAGE_SIZE);
+    }
+    #[test]
+    fn enc_key_id_satisfaction() {
+        let ctx = AccessContext {
+            entity: Entity::Participant,
+            psk: PubSigKey::dummy(),
+            published: HashSet::from([3]),
+            solved: HashSet::from([1]),
+        };
+        assert!(EncKeyId::ProblemSolved(1).is_satisfied_by(&ctx));
+        assert!(!EncKeyId::ProblemSolved(2).is_satisfied_by(&ctx));
+        assert!(EncKeyId::Or(vec![
+            EncKeyId::IsEntity(Entity::Worker),
+            EncKeyId::CustomPublic(3)
+        ])
+        .is_satisfied_by(&ctx));
+        assert!(!EncKeyId::And(vec![
+            EncKeyId::IsClient(PubSigKey::dummy()),
+            EncKeyId::IsEntity(Entity::Worker)
+        ])
+        .is_satisfied_by(&ctx));
+        assert!(EncKeyId::And(vec![]).is_satisfied_by(&ctx));
+        assert!(!EncKeyId::Or(vec![]).is_satisfied_by(&ctx));
+    }
+    #[test]
     fn obfuscated_ipv6() {
-        let socket: Obfuscated<PeerAddr> = Obfuscated(PeerAddr::from(
-            "[::1]:8080".parse::<std::net::SocketAddr>().unwrap(),
-        ));
-        let ser = socket.write_to_vec().unwrap();
+        let addr = PeerAddr::from("[::1]:8080".parse::<std::net::SocketAddr>().unwrap());
+        let key = ObfuscationKey::default();
+        let ser = Obfuscated::new(addr, &key).write_to_vec().unwrap();
         let unser = Obfuscated::<PeerAddr>::read_from_buffer(&ser).unwrap();
-        assert_eq!(socket, unser);
+        assert_eq!(unser.inner(&key), Some(addr));
+    }
+    impl SignatureDomain for u32 {
+        const DOMAIN: &'static str = "decipi test u32";
+    }
+    impl SignatureDomain for QuestionMessage {
+        const DOMAIN: &'static str = "decipi test question";
+    }
+    #[test]
+    fn signature_domain() {
+        let ssk = SecSigKey::from_bytes(&[42u8; 32]);
+        let signed = Signed::new((7u32, ()), &ssk);
+        // the same bytes signed as another kind of message
+        let forged = Signed::<u32, ()> {
+            data: (7u32, ()),
+            signature: ssk.sign(&(7u32, ()).write_to_vec().unwrap()),
+        };
+        assert!(signed.check(&ssk.psk()));
+        assert!(!forged.check(&ssk.psk()));
+    }
+    #[test]
+    fn custom_signer() {
+        // only exposes signing, like a key kept in an hsm
+        #[derive(Debug)]
+        struct Remote(SecSigKey);
+        impl Signer for Remote {
+            fn sign(&self, msg: &[u8]) -> Signature {
+                self.0.sign(msg)
+            }
+            fn psk(&self) -> PubSigKey {
+                self.0.psk()
+            }
+        }
+        let signer: Box<dyn Signer> = Box::new(Remote(SecSigKey::from_bytes(&[42u8; 32])));
+        let signed = Signed::new((7u32, ()), signer.as_ref());
+        assert!(signed.check(&signer.psk()));
+        assert!(!signed.check(&PubSigKey::from(&SecSigKey::from_bytes(&[1u8; 32]))));
+    }
+    #[test]
+    fn try_new_too_long() {
+        let q = QuestionMessage {
+            text: "a".repeat(MAX_TEXT_LENGTH + 1),
+            context: None,
+        };
+        assert!(Macced::try_new(q.clone(), &MacKey::dummy()).is_err());
+        let ssk = SecSigKey::from_bytes(&[42u8; 32]);
+        assert!(Signed::try_new((q, ()), &ssk).is_err());
+    }
+    #[test]
+    fn text_length() {
+        let ok = "a".repeat(MAX_TEXT_LENGTH);
+        let long = "a".repeat(MAX_TEXT_LENGTH + 1);
+        let q = QuestionMessage::new(ok.clone(), None).unwrap();
+        assert!(q.write_to_vec().is_ok());
+        let a = QAnnouncement::new(ok, Some(1)).unwrap();
+        assert!(a.write_to_vec().is_ok());
+        assert!(QuestionMessage::new(long.clone(), None).is_err());
+        assert!(QAnnouncement::new(long, None).is_err());
+    }
+    #[test]
+    fn queue_message_packing() {
+        let small = vec![1u8, 2, 3];
+        assert_eq!(pack_queue_message(&small)[0], QUEUE_RAW);
+        assert_eq!(
+            unpack_queue_message(&pack_queue_message(&small)),
+            Some(small)
+        );
+        let big = vec![b'a'; QUEUE_PART_SIZE * 4];
+        let packed = pack_queue_message(&big);
+        #[cfg(feature = "compression")]
+        assert!(packed.len() < QUEUE_PART_SIZE);
+        assert_eq!(unpack_queue_message(&packed), Some(big.clone()));
+        // from a sender with compression, even if this side does not compress
+        let deflated = [
+            &[QUEUE_DEFLATED],
+            &miniz_oxide::deflate::compress_to_vec(&big, 6)[..],
+        ]
+        .concat();
+        assert_eq!(unpack_queue_message(&deflated), Some(big));
+        assert_eq!(unpack_queue_message(&[42]), None);
+    }
+    #[test]
+    fn peer_addr_scope_id() {
+        let sa: std::net::SocketAddr = "[fe80::1%3]:8080".parse().unwrap();
+        let addr = PeerAddr::from(sa);
+        assert_eq!(addr.scope_id(), 3);
+        assert_eq!(std::net::SocketAddr::from(addr), sa);
+        let ser = addr.write_to_vec().unwrap();
+        assert_eq!(PeerAddr::read_from_buffer(&ser).unwrap(), addr);
+        // global addresses are serialized as before
+        for sa in ["[2001:db8::1]:8080", "1.2.3.4:80"] {
+            let sa: std::net::SocketAddr = sa.parse().unwrap();
+            let ser = PeerAddr::from(sa).write_to_vec().unwrap();
+            assert_eq!(ser, (sa.ip(), sa.port()).write_to_vec().unwrap());
+            assert_eq!(
+                PeerAddr::read_from_buffer(&ser).unwrap(),
+                PeerAddr::from(sa)
+            );
+        }
+    }
+    #[test]
+    fn peer_addr_plausible() {
+        let sa: std::net::SocketAddr = "[fe80::1%3]:8080".parse().unwrap();
+        assert!(PeerAddr::from(sa).is_plausible());
+        assert!(!PeerAddr::new(sa.ip(), 0).is_plausible());
+        assert!(!PeerAddr::from_str("0.0.0.0:80").unwrap().is_plausible());
+        assert!(!PeerAddr::from_str("[ff02::1]:80").unwrap().is_plausible());
     }
     #[test]
     fn obfuscated_ipv4() {
-        let socket: Obfuscated<PeerAddr> = Obfuscated(PeerAddr::from(
-            "127.0.0.1:8080".parse::<std::net::SocketAddr>().unwrap(),
-        ));
-        let ser = socket.write_to_vec().unwrap();
+        let addr = PeerAddr::from("127.0.0.1:8080".parse::<std::net::SocketAddr>().unwrap());
+        let key = ObfuscationKey::from_contest_id(42);
+        let ser = Obfuscated::new(addr, &key).write_to_vec().unwrap();
         let unser = Obfuscated::<PeerAddr>::read_from_buffer(&ser).unwrap();
-        assert_eq!(socket, unser);
+        assert_ne!(unser.clone().inner(&ObfuscationKey::default()), Some(addr));
+        assert_eq!(unser.inner(&key), Some(addr));
     }
 }
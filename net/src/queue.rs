@@ -0,0 +1,209 @@
+use crate::message::*;
+
+use scc::hash_map::Entry;
+use scc::HashMap;
+use std::time::{Duration, Instant};
+
+// parts are stored before the message signature can be checked,
+// so incomplete messages are limited and expire
+const MAX_INCOMPLETE_PER_PEER: usize = 64;
+const MAX_INCOMPLETE: usize = 1024;
+pub const QUEUE_PARTS_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct QueueParts {
+    parts: Vec<Option<Vec<u8>>>,
+    received: usize,
+    // when the first part arrived
+    start: Instant,
+}
+impl QueueParts {
+    fn new(nparts: u16) -> Self {
+        Self {
+            parts: vec![None; nparts as usize],
+            received: 0,
+            start: Instant::now(),
+        }
+    }
+    fn is_full(&self) -> bool {
+        self.received == self.parts.len()
+    }
+    fn add_part(&mut self, part: u16, data: Vec<u8>) {
+        if let Some(x @ None) = self.parts.get_mut(part as usize) {
+            *x = Some(data);
+            self.received += 1;
+        }
+    }
+    fn get_all(self) -> Vec<u8> {
+        self.parts.into_iter().flatten().flatten().collect()
+    }
+}
+
+/// reassembles queue messages that were split in multiple packets
+#[derive(Default)]
+pub struct QueueStore {
+    queue_parts: HashMap<(PubSigKey, QueueMessageId), QueueParts>,
+    // how many incomplete messages of each peer are in queue_parts
+    incomplete: HashMap<PubSigKey, usize>,
+}
+impl QueueStore {
+    pub fn new() -> Self {
+        Self {
+            queue_parts: HashMap::new(),
+            incomplete: HashMap::new(),
+        }
+    }
+    /// counts a new incomplete message of psk, returns false if there are too many
+    async fn reserve(&self, psk: PubSigKey) -> bool {
+        if self.queue_parts.len() >= MAX_INCOMPLETE {
+            return false;
+        }
+        let mut n = self.incomplete.entry_async(psk).await.or_insert(0);
+        if *n.get() >= MAX_INCOMPLETE_PER_PEER {
+            return false;
+        }
+        *n.get_mut() += 1;
+        true
+    }
+    async fn release(&self, psk: PubSigKey) {
+        if let Entry::Occupied(mut n) = self.incomplete.entry_async(psk).await {
+            *n.get_mut() -= 1;
+            if *n.get() == 0 {
+                let _ = n.remove();
+            }
+        }
+    }
+    /// drops the incomplete messages whose first part arrived more than timeout ago,
+    /// should be called periodically
+    pub async fn remove_expired(&self, timeout: Duration) {
+        let mut expired = vec![];
+        self.queue_parts
+            .retain_async(|(psk, _id), qp| {
+                let keep = qp.start.elapsed() < timeout;
+                if !keep {
+                    expired.push(*psk);
+                }
+                keep
+            })
+            .await;
+        for psk in expired {
+            self.release(psk).await;
+        }
+    }
+    /// adds a part received from psk,
+    /// returns the serialized message once all of its parts have been received
+    pub async fn add_part(&self, psk: PubSigKey, part: QueuePart) -> Option<Vec<u8>> {
+        if part.nparts == 0 || part.nparts > MAX_QUEUE_PARTS || part.part >= part.nparts {
+            return None;
+        }
+        let mut qp = match self.queue_parts.entry_async((psk, part.id)).await {
+            Entry::Occupied(o) => o,
+            Entry::Vacant(v) => {
+                if !self.reserve(psk).await {
+                    return None;
+                }
+                v.insert_entry(QueueParts::new(part.nparts))
+            }
+        };
+        if qp.get().parts.len() != part.nparts as usize {
+            return None;
+        }
+        qp.get_mut().add_part(part.part, part.data);
+        if qp.get().is_full() {
+            let data = qp.remove().get_all();
+            self.release(psk).await;
+            Some(data)
+        } else {
+            None
+        }
+    }
+}
+
+/// queue messages sent so far, so that they can be sent again to whoever missed them
+#[derive(Default)]
+pub struct QueueLog {
+    messages: HashMap<QueueMessageId, QueueMessage>,
+}
+impl QueueLog {
+    pub fn new() -> Self {
+        Self {
+            messages: HashMap::new(),
+        }
+    }
+    pub async fn add(&self, m: QueueMessage) {
+        let _ = self.messages.insert_async(m.id, m).await;
+    }
+    /// the messages with id in the ranges [l,r],
+    /// at most max ids are looked at, so that a request can't ask for too much
+    pub async fn get_ranges(&self, ranges: &[(u32, u32)], max: usize) -> Vec<QueueMessage> {
+        let ids = ranges.iter().flat_map(|&(l, r)| l..=r).take(max);
+        let mut messages = vec![];
+        for id in ids {
+            if let Some(m) = self.messages.get_async(&id).await {
+                messages.push(m.get().clone());
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[tokio::test]
+    async fn reassemble_out_of_order() {
+        let data: Vec<u8> = (0..QUEUE_PART_SIZE * 2 + 42).map(|x| x as u8).collect();
+        let mut parts = QueuePart::split(7, &data);
+        assert_eq!(parts.len(), 3);
+        parts.swap(0, 2);
+        let store = QueueStore::new();
+        let psk = PubSigKey::dummy();
+        assert_eq!(store.add_part(psk, parts[0].clone()).await, None);
+        assert_eq!(store.add_part(psk, parts[0].clone()).await, None);
+        assert_eq!(store.add_part(psk, parts[1].clone()).await, None);
+        assert_eq!(store.add_part(psk, parts[2].clone()).await, Some(data));
+    }
+    #[tokio::test]
+    async fn incomplete_limited() {
+        let data = vec![42u8; QUEUE_PART_SIZE * 2];
+        let store = QueueStore::new();
+        let psk = PubSigKey::dummy();
+        for id in 0..MAX_INCOMPLETE_PER_PEER as u32 + 1 {
+            let part = QueuePart::split(id, &data).swap_remove(0);
+            assert_eq!(store.add_part(psk, part).await, None);
+        }
+        assert_eq!(store.queue_parts.len(), MAX_INCOMPLETE_PER_PEER);
+        // other peers are not affected
+        let parts = QueuePart::split(0, &data);
+        let other = PubSigKey::from(&SecSigKey::from_bytes(&[7u8; 32]));
+        assert_eq!(store.add_part(other, parts[0].clone()).await, None);
+        assert_eq!(
+            store.add_part(other, parts[1].clone()).await,
+            Some(data.clone())
+        );
+        store.remove_expired(Duration::ZERO).await;
+        assert_eq!(store.queue_parts.len(), 0);
+        assert_eq!(store.incomplete.len(), 0);
+        let part = QueuePart::split(0, &data).swap_remove(0);
+        assert_eq!(store.add_part(psk, part).await, None);
+        assert_eq!(store.queue_parts.len(), 1);
+    }
+    #[tokio::test]
+    async fn queue_log_ranges() {
+        let log = QueueLog::new();
+        for id in [0, 1, 2, 4] {
+            let message =
+                QueueMessageInner::Announcement(QAnnouncement::new(id.to_string(), None).unwrap());
+            log.add(QueueMessage {
+                id,
+                timestamp: std::time::SystemTime::now(),
+                message,
+            })
+            .await;
+        }
+        let ids = |v: Vec<QueueMessage>| v.into_iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids(log.get_ranges(&[(1, 5)], 64).await), vec![1, 2, 4]);
+        assert_eq!(ids(log.get_ranges(&[(4, 4), (0, 0)], 64).await), vec![4, 0]);
+        assert_eq!(ids(log.get_ranges(&[(0, u32::MAX)], 2).await), vec![0, 1]);
+        assert_eq!(ids(log.get_ranges(&[(3, 1)], 64).await), vec![]);
+    }
+}
@@ -1,36 +1,251 @@
 use crate::message::*;
+use crate::stats::*;
+use crate::SendError;
 use anyhow::Result;
-use speedy::{Readable, Writable};
-use std::sync::Arc;
+use rand::{thread_rng, CryptoRng, RngCore};
+use speedy::{LittleEndian, Readable, Writable};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::Interest;
 use tokio::net::{ToSocketAddrs, UdpSocket};
+use tracing::*;
+
+fn is_transient(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::WouldBlock
+    )
+}
+
+// writes the version byte followed by the message, returns the datagram length,
+// messages that don't fit in buf (or in a datagram) are rejected rather than truncated
+pub(crate) fn encode(
+    message: &Message,
+    version: u8,
+    buf: &mut [u8],
+) -> std::result::Result<usize, SendError> {
+    let length = datagram_len(message)?;
+    if length > buf.len() {
+        return Err(SendError::MessageTooLarge);
+    }
+    buf[0] = version;
+    message.write_to_buffer(&mut buf[1..length])?;
+    Ok(length)
+}
+/// the length of the datagram carrying message, which must fit in MAX_DATAGRAM_SIZE
+pub(crate) fn datagram_len(message: &Message) -> std::result::Result<usize, SendError> {
+    let length = 1 + Writable::<LittleEndian>::bytes_needed(message)?;
+    if length > MAX_DATAGRAM_SIZE {
+        return Err(SendError::MessageTooLarge);
+    }
+    Ok(length)
+}
+pub(crate) fn decode(datagram: &[u8], version: u8) -> std::result::Result<Message, DropReason> {
+    match datagram.split_first() {
+        Some((&v, data)) if v == version => {
+            Message::read_from_buffer(data).map_err(|_| DropReason::Malformed)
+        }
+        Some(_) => Err(DropReason::WrongVersion),
+        None => Err(DropReason::Malformed),
+    }
+}
+
+// receives up to bufs.len() / MAX_DATAGRAM_SIZE datagrams with a single recvmmsg call,
+// the i-th into the i-th MAX_DATAGRAM_SIZE bytes of bufs, adding where it is and who sent it to received
+#[cfg(target_os = "linux")]
+fn recv_many(
+    socket: &UdpSocket,
+    bufs: &mut [u8],
+    received: &mut Vec<(Range<usize>, SocketAddr)>,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let mut iovs: Vec<libc::iovec> = bufs
+        .chunks_exact_mut(MAX_DATAGRAM_SIZE)
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        })
+        .collect();
+    // SAFETY: all zeroes is a valid sockaddr_storage and mmsghdr
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { std::mem::zeroed() }; iovs.len()];
+    let mut msgs: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .map(|(iov, addr)| {
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+            msg.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as _;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+    // SAFETY: the headers point to the buffers and addresses above, which outlive the call
+    let n = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as _,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    for (i, (msg, addr)) in msgs.iter().zip(addrs).take(n as usize).enumerate() {
+        // SAFETY: the kernel wrote an address of msg_namelen bytes
+        let addr = unsafe { socket2::SockAddr::new(addr, msg.msg_hdr.msg_namelen) };
+        if let Some(addr) = addr.as_socket() {
+            let start = i * MAX_DATAGRAM_SIZE;
+            received.push((start..start + msg.msg_len as usize, addr));
+        }
+    }
+    Ok(())
+}
+// elsewhere one recvfrom per datagram, but still one wakeup per batch
+#[cfg(not(target_os = "linux"))]
+fn recv_many(
+    socket: &UdpSocket,
+    bufs: &mut [u8],
+    received: &mut Vec<(Range<usize>, SocketAddr)>,
+) -> std::io::Result<()> {
+    for (i, buf) in bufs.chunks_exact_mut(MAX_DATAGRAM_SIZE).enumerate() {
+        match socket.try_recv_from(buf) {
+            Ok((length, addr)) => {
+                let start = i * MAX_DATAGRAM_SIZE;
+                received.push((start..start + length, addr));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && !received.is_empty() => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// waits between the sends of a one-shot control message, see Transmitter::repeat
+pub const RETRANSMIT_DELAYS: [Duration; 5] = [
+    Duration::from_millis(125),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(8),
+];
+
+/// randomness for key exchanges, nonces and jitter
+pub trait NetRng: RngCore + CryptoRng {}
+impl<T: RngCore + CryptoRng> NetRng for T {}
+
+/// thread_rng unless an rng is given, eg a seeded one to make tests reproducible
+#[derive(Clone, Default)]
+pub enum RngSource {
+    #[default]
+    Thread,
+    Shared(Arc<Mutex<dyn NetRng + Send>>),
+}
+impl RngSource {
+    pub fn new(rng: impl NetRng + Send + 'static) -> Self {
+        Self::Shared(Arc::new(Mutex::new(rng)))
+    }
+    pub fn with<R>(&self, f: impl FnOnce(&mut dyn NetRng) -> R) -> R {
+        match self {
+            Self::Thread => f(&mut thread_rng()),
+            Self::Shared(rng) => f(&mut *rng.lock().unwrap()),
+        }
+    }
+}
+impl std::fmt::Debug for RngSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Thread => write!(f, "Thread"),
+            Self::Shared(_) => write!(f, "Shared"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct SocketReader {
     socket: Arc<UdpSocket>,
     entity: Entity,
-    ssk: SecSigKey,
+    signer: Arc<dyn Signer>,
     contest_id: ContestId,
+    stats: Arc<Stats>,
 }
 impl SocketReader {
-    pub async fn recv_from(&self, buf: &mut [u8]) -> (Message, PeerAddr) {
+    /// receives the next message that can be parsed,
+    /// buf must be at least MAX_DATAGRAM_SIZE long
+    /// errors only if the socket itself fails
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(Message, PeerAddr)> {
         loop {
-            let Ok((length, addr)) = self.socket.recv_from(buf).await else {
-                continue;
-            };
-            let Ok(message) = Message::read_from_buffer(&buf[0..length]) else {
-                continue;
+            let (length, addr) = match self.socket.recv_from(buf).await {
+                Ok(x) => x,
+                // eg an icmp port unreachable from a previous send
+                Err(e) if is_transient(&e) => continue,
+                Err(e) => return Err(e.into()),
             };
-            return (message, addr.into());
+            if let Some(message) = self.parse(&buf[0..length], addr) {
+                return Ok((message, addr.into()));
+            }
         }
     }
+    /// waits for a datagram and receives it along with the ones already waiting,
+    /// at most bufs.len() / MAX_DATAGRAM_SIZE of them, adding the ones that can be parsed to out,
+    /// on linux they are all received with a single recvmmsg call instead of a syscall each
+    pub async fn recv_batch(
+        &self,
+        bufs: &mut [u8],
+        out: &mut Vec<(Message, PeerAddr)>,
+    ) -> Result<()> {
+        let mut received = vec![];
+        loop {
+            let result = self
+                .socket
+                .async_io(Interest::READABLE, || {
+                    recv_many(&self.socket, bufs, &mut received)
+                })
+                .await;
+            match result {
+                Ok(()) => break,
+                Err(e) if is_transient(&e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for (range, addr) in received {
+            if let Some(message) = self.parse(&bufs[range], addr) {
+                out.push((message, addr.into()));
+            }
+        }
+        Ok(())
+    }
+    fn parse(&self, datagram: &[u8], addr: SocketAddr) -> Option<Message> {
+        match decode(datagram, PROTOCOL_VERSION) {
+            Ok(message) => {
+                self.stats.received(&message);
+                Some(message)
+            }
+            Err(reason) => {
+                self.stats.dropped(reason);
+                debug!("dropping message from {:?}: {:?}", addr, reason);
+                None
+            }
+        }
+    }
+    pub(crate) fn stats(&self) -> &Stats {
+        &self.stats
+    }
     pub fn entity(&self) -> Entity {
         self.entity
     }
-    pub fn ssk(&self) -> SecSigKey {
-        self.ssk.clone()
+    pub fn signer(&self) -> Arc<dyn Signer> {
+        self.signer.clone()
     }
     pub fn psk(&self) -> PubSigKey {
-        (&self.ssk).into()
+        self.signer.psk()
     }
     pub fn own_addr(&self) -> Result<PeerAddr> {
         Ok(PeerAddr::from(self.socket.local_addr()?))
@@ -44,25 +259,39 @@ impl SocketReader {
 pub struct SocketWriter {
     socket: Arc<UdpSocket>,
     entity: Entity,
-    ssk: SecSigKey,
+    signer: Arc<dyn Signer>,
     contest_id: ContestId,
+    // ipv6 socket that also accepts ipv4, ipv4 destinations need to be mapped to ipv6
+    dual_stack: bool,
+    stats: Arc<Stats>,
+    rng: RngSource,
 }
 impl SocketWriter {
-    pub async fn send_to(&self, message: Message, addr: PeerAddr, buf: &mut [u8]) -> Result<()> {
-        message.write_to_buffer(buf).unwrap();
-        self.socket
-            .send_to(buf, std::net::SocketAddr::from(addr))
-            .await?;
+    /// encodes message on the stack, so sending doesn't allocate
+    /// and clones of the writer can send concurrently
+    pub async fn send_to(
+        &self,
+        message: Message,
+        addr: PeerAddr,
+    ) -> std::result::Result<(), SendError> {
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let length = encode(&message, PROTOCOL_VERSION, &mut buf)?;
+        let mut dest = SocketAddr::from(addr);
+        if let (true, IpAddr::V4(ip)) = (self.dual_stack, dest.ip()) {
+            dest = SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), dest.port());
+        }
+        self.socket.send_to(&buf[..length], dest).await?;
+        self.stats.sent();
         Ok(())
     }
     pub fn entity(&self) -> Entity {
         self.entity
     }
-    pub fn ssk(&self) -> SecSigKey {
-        self.ssk.clone()
+    pub fn signer(&self) -> Arc<dyn Signer> {
+        self.signer.clone()
     }
     pub fn psk(&self) -> PubSigKey {
-        (&self.ssk).into()
+        self.signer.psk()
     }
     pub fn own_addr(&self) -> Result<PeerAddr> {
         Ok(PeerAddr::from(self.socket.local_addr()?))
@@ -70,26 +299,205 @@ impl SocketWriter {
     pub fn contest_id(&self) -> ContestId {
         self.contest_id
     }
+    pub fn rng(&self) -> &RngSource {
+        &self.rng
+    }
+    pub fn with_rng(mut self, rng: RngSource) -> Self {
+        self.rng = rng;
+        self
+    }
 }
 
 pub async fn new_socket<T: ToSocketAddrs>(
     addr: T,
     entity: Entity,
-    ssk: SecSigKey,
+    signer: Arc<dyn Signer>,
+    contest_id: ContestId,
+) -> Result<(SocketReader, SocketWriter)> {
+    let socket = UdpSocket::bind(addr).await?;
+    Ok(split_socket(socket, entity, signer, contest_id, false))
+}
+
+/// binds to [::]:port accepting both ipv4 and ipv6 peers
+pub async fn new_dual_stack_socket(
+    port: u16,
+    entity: Entity,
+    signer: Arc<dyn Signer>,
     contest_id: ContestId,
 ) -> Result<(SocketReader, SocketWriter)> {
-    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV6,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    socket.set_only_v6(false)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    Ok(split_socket(socket, entity, signer, contest_id, true))
+}
+
+fn split_socket(
+    socket: UdpSocket,
+    entity: Entity,
+    signer: Arc<dyn Signer>,
+    contest_id: ContestId,
+    dual_stack: bool,
+) -> (SocketReader, SocketWriter) {
+    let socket = Arc::new(socket);
+    let stats = Arc::new(Stats::default());
     let sr = SocketReader {
         socket: socket.clone(),
         entity,
-        ssk: ssk.clone(),
+        signer: signer.clone(),
         contest_id,
+        stats: stats.clone(),
     };
     let sw = SocketWriter {
         socket: socket.clone(),
         entity,
-        ssk,
+        signer,
         contest_id,
+        dual_stack,
+        stats,
+        rng: RngSource::default(),
     };
-    Ok((sr, sw))
+    (sr, sw)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn wrong_version_rejected() {
+        let message = Message::Net(NetMessage::KeepAlive(
+            PubSigKey::dummy(),
+            Macced::new(
+                KeepAliveInner(std::time::SystemTime::now()),
+                &MacKey::dummy(),
+            ),
+        ));
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let length = encode(&message, 1, &mut buf).unwrap();
+        assert_eq!(decode(&buf[..length], 1).ok(), Some(message));
+        assert!(matches!(
+            decode(&buf[..length], 2),
+            Err(DropReason::WrongVersion)
+        ));
+        assert!(matches!(decode(&[], 2), Err(DropReason::Malformed)));
+    }
+    #[test]
+    fn too_large_rejected() {
+        let data = Encrypted::new(
+            vec![0u8; MAX_DATAGRAM_SIZE],
+            &EncKey::random(&RngSource::Thread),
+            &RngSource::Thread,
+        );
+        let message = Message::Stream(Macced::new(
+            StreamMessage::Data(0, 0, false, data),
+            &MacKey::dummy(),
+        ));
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        assert!(matches!(
+            encode(&message, 1, &mut buf),
+            Err(SendError::MessageTooLarge)
+        ));
+        let message = Message::Stream(Macced::new(StreamMessage::Ack(0, 0), &MacKey::dummy()));
+        assert!(matches!(
+            encode(&message, 1, &mut buf[..4]),
+            Err(SendError::MessageTooLarge)
+        ));
+        assert!(encode(&message, 1, &mut buf).is_ok());
+    }
+    fn keepalive() -> Message {
+        Message::Net(NetMessage::KeepAlive(
+            PubSigKey::dummy(),
+            Macced::new(
+                KeepAliveInner(std::time::SystemTime::now()),
+                &MacKey::dummy(),
+            ),
+        ))
+    }
+    async fn socket_pair() -> (SocketReader, SocketWriter) {
+        let signer: Arc<dyn Signer> = Arc::new(SecSigKey::from_bytes(&[42u8; 32]));
+        let (_, sw) = new_socket("127.0.0.1:0", Entity::Server, signer.clone(), 0)
+            .await
+            .unwrap();
+        let (sr, _) = new_socket("127.0.0.1:0", Entity::Participant, signer, 0)
+            .await
+            .unwrap();
+        (sr, sw)
+    }
+    #[tokio::test]
+    async fn recv_batch() {
+        let (sr, sw) = socket_pair().await;
+        let message = keepalive();
+        for _ in 0..5 {
+            sw.send_to(message.clone(), sr.own_addr().unwrap())
+                .await
+                .unwrap();
+        }
+        // not a message, dropped without ending the batch
+        let raw = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        raw.send_to(&[0], SocketAddr::from(sr.own_addr().unwrap()))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut bufs = vec![0u8; 3 * MAX_DATAGRAM_SIZE];
+        let mut out = vec![];
+        sr.recv_batch(&mut bufs, &mut out).await.unwrap();
+        assert_eq!(out.len(), 3);
+        out.clear();
+        let mut bufs = vec![0u8; 8 * MAX_DATAGRAM_SIZE];
+        sr.recv_batch(&mut bufs, &mut out).await.unwrap();
+        assert_eq!(out.len(), 2);
+        assert!(out
+            .iter()
+            .all(|(m, addr)| *m == message && *addr == sw.own_addr().unwrap()));
+    }
+    // cargo test --release recv_throughput -- --ignored --nocapture
+    // times only the receiving of datagrams already waiting in the socket
+    #[tokio::test]
+    #[ignore]
+    async fn recv_throughput() {
+        // few enough to fit in the default receive buffer
+        const ROUND: usize = 64;
+        const ROUNDS: usize = 4000;
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = [0u8; MAX_DATAGRAM_SIZE];
+        // cheap to parse, unlike a keepalive whose key is decompressed, so the syscalls dominate
+        let message = Message::Stream(Macced::new(StreamMessage::Ack(0, 0), &MacKey::dummy()));
+        let length = encode(&message, PROTOCOL_VERSION, &mut datagram).unwrap();
+        for batched in [false, true] {
+            let (sr, _) = socket_pair().await;
+            let dest = SocketAddr::from(sr.own_addr().unwrap());
+            let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+            let mut bufs = vec![0u8; ROUND * MAX_DATAGRAM_SIZE];
+            let mut out = vec![];
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..ROUNDS {
+                for _ in 0..ROUND {
+                    sender.send_to(&datagram[..length], dest).await.unwrap();
+                }
+                let start = std::time::Instant::now();
+                let mut received = 0;
+                while received < ROUND {
+                    if batched {
+                        sr.recv_batch(&mut bufs, &mut out).await.unwrap();
+                        received += out.len();
+                        out.clear();
+                    } else {
+                        sr.recv_from(&mut buf).await.unwrap();
+                        received += 1;
+                    }
+                }
+                elapsed += start.elapsed();
+            }
+            println!(
+                "{}: {:.0} datagrams/s",
+                if batched { "recv_batch" } else { "recv_from" },
+                (ROUND * ROUNDS) as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
 }
@@ -0,0 +1,122 @@
+use crate::message::*;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// number of messages of each kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageCounts {
+    pub net: u64,
+    pub queue: u64,
+    pub file: u64,
+    pub enc_key: u64,
+    pub request: u64,
+    pub submission: u64,
+    pub question: u64,
+    pub evaluation: u64,
+    pub stream: u64,
+    pub chunk_hashes: u64,
+}
+
+/// snapshot of what a Net has sent and received,
+/// the drop counters say why received messages were ignored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetStats {
+    pub sent: u64,
+    pub received: u64,
+    pub received_per_kind: MessageCounts,
+    pub malformed: u64,      // packets that could not be parsed as a message
+    pub wrong_version: u64,  // packets from an incompatible protocol version
+    pub filtered: u64,       // key exchanges rejected by the inbound connection filter
+    pub rate_limited: u64,   // key exchanges over the rate limit
+    pub replayed: u64,       // messages that were already received
+    pub invalid: u64,        // wrong timestamp or contest id
+    pub bad_signature: u64,  // signature check failed
+    pub unknown_peer: u64,   // no connection with the sender
+    pub mac_failed: u64,     // mac check failed, eg the peer has a different mac key
+    pub unexpected: u64,     // messages that this entity does not handle
+    pub from_spectator: u64, // submissions and questions from spectators
+}
+
+/// why a received message was ignored
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DropReason {
+    Malformed,
+    WrongVersion,
+    Filtered,
+    RateLimited,
+    Replayed,
+    Invalid,
+    BadSignature,
+    UnknownPeer,
+    MacFailed,
+    Unexpected,
+    FromSpectator,
+}
+const NREASONS: usize = DropReason::FromSpectator as usize + 1;
+const NKINDS: usize = 10;
+
+fn message_kind(m: &Message) -> usize {
+    match m {
+        Message::Net(_) => 0,
+        Message::Queue(_) => 1,
+        Message::File(_) => 2,
+        Message::EncKey(_) => 3,
+        Message::Request(_) => 4,
+        Message::Submission(_) => 5,
+        Message::Question(_) => 6,
+        Message::Evaluation(_) => 7,
+        Message::Stream(_) => 8,
+        Message::ChunkHashes(_) => 9,
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    sent: AtomicU64,
+    received: AtomicU64,
+    received_per_kind: [AtomicU64; NKINDS],
+    dropped: [AtomicU64; NREASONS],
+}
+impl Stats {
+    pub fn sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn received(&self, m: &Message) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        self.received_per_kind[message_kind(m)].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn dropped(&self, reason: DropReason) {
+        self.dropped[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn snapshot(&self) -> NetStats {
+        let dropped = |reason: DropReason| self.dropped[reason as usize].load(Ordering::Relaxed);
+        let kind = |i: usize| self.received_per_kind[i].load(Ordering::Relaxed);
+        NetStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            received_per_kind: MessageCounts {
+                net: kind(0),
+                queue: kind(1),
+                file: kind(2),
+                enc_key: kind(3),
+                request: kind(4),
+                submission: kind(5),
+                question: kind(6),
+                evaluation: kind(7),
+                stream: kind(8),
+                chunk_hashes: kind(9),
+            },
+            malformed: dropped(DropReason::Malformed),
+            wrong_version: dropped(DropReason::WrongVersion),
+            filtered: dropped(DropReason::Filtered),
+            rate_limited: dropped(DropReason::RateLimited),
+            replayed: dropped(DropReason::Replayed),
+            invalid: dropped(DropReason::Invalid),
+            bad_signature: dropped(DropReason::BadSignature),
+            unknown_peer: dropped(DropReason::UnknownPeer),
+            mac_failed: dropped(DropReason::MacFailed),
+            unexpected: dropped(DropReason::Unexpected),
+            from_spectator: dropped(DropReason::FromSpectator),
+        }
+    }
+}
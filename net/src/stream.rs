@@ -0,0 +1,176 @@
+use crate::message::*;
+use crate::Net;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tracing::*;
+
+// chunks sent and not acked yet, and bytes received and not read yet, at most
+const WINDOW: usize = 64;
+pub(crate) const WINDOW_BYTES: usize = WINDOW * STREAM_CHUNK_SIZE;
+// chunks not acked after this are sent again
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(200);
+// the stream is closed if the peer acks nothing for this long while there are chunks to ack
+const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+// a finished stream keeps acking the peer's retransmissions for this long, in case its last ack was lost
+pub(crate) const STREAM_LINGER: Duration = Duration::from_secs(1);
+// received messages waiting to be handled by the stream, more are dropped (and retransmitted)
+pub(crate) const STREAM_QUEUE: usize = 2 * WINDOW;
+
+struct SentChunk {
+    offset: u64,
+    fin: bool,
+    data: Encrypted<Vec<u8>>,
+    // offset of the first byte after this chunk, the fin counts as a byte
+    end: u64,
+    last_sent: Instant,
+}
+
+/// moves bytes between the local end of the pipe and the peer until both sides finished,
+/// the stream fails (the pipe is closed) if the connection is lost or the peer stops acking
+pub(crate) async fn run_stream(
+    net: Arc<Net>,
+    psk: PubSigKey,
+    id: StreamId,
+    enc_key: EncKey,
+    pipe: DuplexStream,
+    mut rx: mpsc::Receiver<StreamMessage>,
+) {
+    let (mut pipe_r, mut pipe_w) = tokio::io::split(pipe);
+    let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+    // sending side
+    let mut in_flight = VecDeque::<SentChunk>::new();
+    let mut send_next = 0u64;
+    let mut local_fin = false;
+    let mut last_progress = Instant::now();
+    // receiving side
+    let mut recv_next = 0u64;
+    let mut out_of_order = BTreeMap::<u64, (bool, Vec<u8>)>::new();
+    let mut pending = Vec::<u8>::new(); // in order, not written to the pipe yet
+    let mut remote_fin = false;
+    let mut pipe_w_closed = false;
+    let mut tick = tokio::time::interval(RETRANSMIT_INTERVAL);
+    loop {
+        if remote_fin && pending.is_empty() && !pipe_w_closed {
+            let _ = pipe_w.shutdown().await;
+            pipe_w_closed = true;
+        }
+        if local_fin && in_flight.is_empty() && pipe_w_closed {
+            linger(&net, psk, id, recv_next + remote_fin as u64, &mut rx).await;
+            break;
+        }
+        tokio::select! {
+            m = rx.recv() => match m {
+                Some(StreamMessage::Data(_id, offset, fin, data)) => {
+                    let in_window = offset >= recv_next
+                        && offset < recv_next + WINDOW_BYTES as u64
+                        && pending.len() < WINDOW_BYTES;
+                    if in_window {
+                        if let Some(data) = data.inner_bytes(&enc_key) {
+                            out_of_order.insert(offset, (fin, data));
+                        }
+                    }
+                    while let Some((fin, data)) = out_of_order.remove(&recv_next) {
+                        recv_next += data.len() as u64;
+                        // otherwise nobody reads the local end anymore
+                        if !pipe_w_closed {
+                            pending.extend(data);
+                        }
+                        if fin {
+                            remote_fin = true;
+                            out_of_order.clear();
+                        }
+                    }
+                    // also answers retransmissions, whose ack may have been lost
+                    let acked = recv_next + remote_fin as u64;
+                    if let Err(e) = net.send_stream_message(psk, StreamMessage::Ack(id, acked)).await {
+                        debug!("could not ack stream {} of {}: {}", id, psk, e);
+                    }
+                }
+                Some(StreamMessage::Ack(_id, acked)) => {
+                    while in_flight.front().is_some_and(|c| c.end <= acked) {
+                        in_flight.pop_front();
+                        last_progress = Instant::now();
+                    }
+                }
+                None => break,
+            },
+            r = pipe_r.read(&mut buf), if !local_fin && in_flight.len() < WINDOW => {
+                // a read error is treated as the end of the stream
+                let n = r.unwrap_or(0);
+                let fin = n == 0;
+                local_fin = fin;
+                let chunk = SentChunk {
+                    offset: send_next,
+                    fin,
+                    data: Encrypted::new(buf[..n].to_vec(), &enc_key, net.sw.rng()),
+                    end: send_next + n as u64 + fin as u64,
+                    last_sent: Instant::now(),
+                };
+                send_next += n as u64;
+                if in_flight.is_empty() {
+                    last_progress = Instant::now();
+                }
+                let m = StreamMessage::Data(id, chunk.offset, fin, chunk.data.clone());
+                if let Err(e) = net.send_stream_message(psk, m).await {
+                    debug!("could not send on stream {} of {}: {}", id, psk, e);
+                }
+                in_flight.push_back(chunk);
+            },
+            r = pipe_w.write(&pending), if !pending.is_empty() => match r {
+                Ok(n) => {
+                    pending.drain(..n);
+                }
+                Err(_) => {
+                    pending.clear();
+                    pipe_w_closed = true;
+                }
+            },
+            _ = tick.tick() => {
+                if !net.is_connected(psk).await {
+                    debug!("connection lost, closing stream {} of {}", id, psk);
+                    break;
+                }
+                if !in_flight.is_empty() && last_progress.elapsed() > STREAM_TIMEOUT {
+                    debug!("stream {} of {} timed out", id, psk);
+                    break;
+                }
+                for chunk in in_flight.iter_mut() {
+                    if chunk.last_sent.elapsed() < RETRANSMIT_INTERVAL {
+                        continue;
+                    }
+                    chunk.last_sent = Instant::now();
+                    let m = StreamMessage::Data(id, chunk.offset, chunk.fin, chunk.data.clone());
+                    let _ = net.send_stream_message(psk, m).await;
+                }
+            },
+        }
+    }
+    net.close_stream(psk, id).await;
+}
+
+/// answers whatever the peer sends again with the final ack, until STREAM_LINGER passes
+async fn linger(
+    net: &Net,
+    psk: PubSigKey,
+    id: StreamId,
+    acked: u64,
+    rx: &mut mpsc::Receiver<StreamMessage>,
+) {
+    let deadline = tokio::time::sleep(STREAM_LINGER);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            m = rx.recv() => match m {
+                Some(StreamMessage::Data(..)) => {
+                    let _ = net.send_stream_message(psk, StreamMessage::Ack(id, acked)).await;
+                }
+                Some(StreamMessage::Ack(..)) => {}
+                None => return,
+            },
+            _ = &mut deadline => return,
+        }
+    }
+}